@@ -4,7 +4,7 @@
 //! containers `ProtBuf` and `ProtKey`.
 use alloc::heap;
 
-use malloc;
+use malloc::{self, MallocError, Prot};
 
 
 /// Base trait for memory allocators
@@ -13,6 +13,38 @@ pub trait Allocator {
     /// of `align`.
     unsafe fn allocate(size: usize, align: usize) -> *mut u8;
 
+    /// Same as `allocate` but report failures as errors instead of
+    /// returning `NULL` or `panic!`ing.
+    unsafe fn try_allocate(size: usize,
+                           align: usize) -> Result<*mut u8, MallocError> {
+        let ptr = <Self as Allocator>::allocate(size, align);
+        if ptr.is_null() {
+            Err(MallocError::OutOfMemory)
+        } else {
+            Ok(ptr)
+        }
+    }
+
+    /// Allocate `count` areas of `size` bytes each, see `try_allocate`.
+    /// Each area is deallocated on its own, none is left allocated on
+    /// error.
+    unsafe fn try_allocate_batch(size: usize, count: usize, align: usize)
+                                 -> Result<Vec<*mut u8>, MallocError> {
+        let mut ptrs = Vec::with_capacity(count);
+        for _ in 0_usize..count {
+            match <Self as Allocator>::try_allocate(size, align) {
+                Ok(ptr) => ptrs.push(ptr),
+                Err(err) => {
+                    for &ptr in ptrs.iter() {
+                        <Self as Allocator>::deallocate(ptr, size, align);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(ptrs)
+    }
+
     /// Deallocate `size` bytes memory at `ptr`. `size` and `align` must
     /// be the same values used when `allocate` was called.
     unsafe fn deallocate(ptr: *mut u8, size: usize, align: usize);
@@ -31,6 +63,14 @@ pub trait KeyAllocator : Allocator {
     /// Set memory protection of pages allocated at `ptr` to prevent
     /// any access.
     unsafe fn protect_none(ptr: *mut u8, size: usize);
+
+    /// Same as `protect_none` but report failures as errors instead of
+    /// `panic!`ing.
+    unsafe fn try_protect_none(ptr: *mut u8,
+                               size: usize) -> Result<(), MallocError> {
+        <Self as KeyAllocator>::protect_none(ptr, size);
+        Ok(())
+    }
 }
 
 
@@ -91,6 +131,16 @@ impl Allocator for ProtectedBufferAllocator {
         malloc::malloc(size, align)
     }
 
+    unsafe fn try_allocate(size: usize,
+                           align: usize) -> Result<*mut u8, MallocError> {
+        malloc::try_malloc(size, align)
+    }
+
+    unsafe fn try_allocate_batch(size: usize, count: usize, align: usize)
+                                 -> Result<Vec<*mut u8>, MallocError> {
+        malloc::try_malloc_batch(size, count, align)
+    }
+
     unsafe fn deallocate(ptr: *mut u8, _size: usize, _align: usize) {
         malloc::free(ptr);
     }
@@ -110,6 +160,11 @@ impl Allocator for ProtectedKeyAllocator {
         malloc::malloc_key(size, align)
     }
 
+    unsafe fn try_allocate(size: usize,
+                           align: usize) -> Result<*mut u8, MallocError> {
+        malloc::try_malloc_key(size, align)
+    }
+
     unsafe fn deallocate(ptr: *mut u8, _size: usize, _align: usize) {
         malloc::free(ptr);
     }
@@ -127,4 +182,9 @@ impl KeyAllocator for ProtectedKeyAllocator {
     unsafe fn protect_none(ptr: *mut u8, _size: usize) {
         malloc::protect_none(ptr);
     }
+
+    unsafe fn try_protect_none(ptr: *mut u8,
+                               _size: usize) -> Result<(), MallocError> {
+        malloc::try_protect(ptr, Prot::None)
+    }
 }
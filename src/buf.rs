@@ -1,6 +1,7 @@
 //! Protected buffer
 //!
 use alloc::heap;
+use std::cmp;
 use std::convert::AsRef;
 use std::fmt::{self, Debug, Formatter, LowerHex, UpperHex};
 use std::intrinsics;
@@ -16,6 +17,7 @@ use rand::Rng;
 
 use allocator::{Allocator, KeyAllocator, DefaultBufferAllocator};
 use key::ProtKey;
+use malloc::MallocError;
 use utils;
 
 
@@ -23,14 +25,31 @@ use utils;
 pub type ProtBuf8<A = DefaultBufferAllocator> = ProtBuf<u8, A>;
 
 
-unsafe fn alloc<A: Allocator, T>(count: usize) -> *mut T {
-    let size = count.checked_mul(mem::size_of::<T>()).unwrap();
+unsafe fn try_alloc<A: Allocator, T>(count: usize,
+                                      align: usize) -> Result<*mut T,
+                                                              MallocError> {
+    let size = try!(count.checked_mul(mem::size_of::<T>())
+                    .ok_or(MallocError::Overflow));
 
     // allocate
-    let ptr = <A as Allocator>::allocate(size,
-                                         mem::align_of::<T>()) as *mut T;
+    let ptr = try!(<A as Allocator>::try_allocate(
+        size, cmp::max(align, mem::align_of::<T>())));
     assert!(!ptr.is_null());
-    ptr
+    Ok(ptr as *mut T)
+}
+
+unsafe fn try_alloc_batch<A: Allocator, T>(count: usize, length: usize)
+                                           -> Result<Vec<*mut T>,
+                                                     MallocError> {
+    let size = try!(length.checked_mul(mem::size_of::<T>())
+                    .ok_or(MallocError::Overflow));
+
+    let ptrs = try!(<A as Allocator>::try_allocate_batch(
+        size, count, mem::align_of::<T>()));
+    Ok(ptrs.into_iter().map(|ptr| {
+        assert!(!ptr.is_null());
+        ptr as *mut T
+    }).collect())
 }
 
 unsafe fn dealloc<A: Allocator, T>(ptr: *mut T, count: usize) {
@@ -104,26 +123,90 @@ impl<T: Copy, A: Allocator> ProtBuf<T, A> {
         self.len.checked_mul(mem::size_of::<T>()).unwrap()
     }
 
-    fn with_length(length: usize) -> ProtBuf<T, A> {
+    fn try_with_length(length: usize) -> Result<ProtBuf<T, A>, MallocError> {
+        ProtBuf::try_with_alignment(length, 0)
+    }
+
+    fn try_with_alignment(length: usize,
+                          align: usize) -> Result<ProtBuf<T, A>, MallocError> {
         if mem::size_of::<T>() == 0 || length == 0 {
             unsafe {
-                ProtBuf::new_with_parts(heap::EMPTY as *mut T, 0)
+                Ok(ProtBuf::new_with_parts(heap::EMPTY as *mut T, 0))
             }
         } else {
-            let ptr = unsafe {
-                alloc::<A, T>(length)
-            };
+            let ptr = try!(unsafe {
+                try_alloc::<A, T>(length, align)
+            });
             unsafe {
-                ProtBuf::new_with_parts(ptr, length)
+                Ok(ProtBuf::new_with_parts(ptr, length))
             }
         }
     }
 
+    fn with_length(length: usize) -> ProtBuf<T, A> {
+        match ProtBuf::try_with_length(length) {
+            Ok(n) => n,
+            Err(err) => panic!("{}", err)
+        }
+    }
+
     /// New allocated buffer with unitilialized memory.
     pub fn new(length: usize) -> ProtBuf<T, A> {
         ProtBuf::with_length(length)
     }
 
+    /// Same as `new` but return an error if memory cannot be allocated.
+    pub fn try_new(length: usize) -> Result<ProtBuf<T, A>, MallocError> {
+        ProtBuf::try_with_length(length)
+    }
+
+    /// `count` new allocated buffers of `length` elements with
+    /// uninitialized memory. Their pages are reserved and locked at once
+    /// when possible, each buffer is still deallocated on its own.
+    pub fn new_batch(count: usize, length: usize) -> Vec<ProtBuf<T, A>> {
+        match ProtBuf::try_new_batch(count, length) {
+            Ok(bufs) => bufs,
+            Err(err) => panic!("{}", err)
+        }
+    }
+
+    /// Same as `new_batch` but return an error if memory cannot be
+    /// allocated, no buffer is then left allocated.
+    pub fn try_new_batch(count: usize,
+                         length: usize) -> Result<Vec<ProtBuf<T, A>>,
+                                                  MallocError> {
+        if mem::size_of::<T>() == 0 || length == 0 {
+            return Ok((0_usize..count).map(|_| unsafe {
+                ProtBuf::new_with_parts(heap::EMPTY as *mut T, 0)
+            }).collect());
+        }
+
+        let ptrs = try!(unsafe {
+            try_alloc_batch::<A, T>(count, length)
+        });
+        Ok(ptrs.into_iter().map(|ptr| unsafe {
+            ProtBuf::new_with_parts(ptr, length)
+        }).collect())
+    }
+
+    /// New allocated buffer with uninitialized memory whose base address
+    /// is a multiple of `align`, a power of two that may be larger than
+    /// the page size. Empty buffers are not aligned, clones neither.
+    pub fn new_aligned(length: usize, align: usize) -> ProtBuf<T, A> {
+        match ProtBuf::try_with_alignment(length, align) {
+            Ok(n) => n,
+            Err(err) => panic!("{}", err)
+        }
+    }
+
+    /// Same as `new_aligned` but return an error if memory cannot be
+    /// allocated or if `align` is invalid.
+    pub fn try_new_aligned(length: usize,
+                           align: usize) -> Result<ProtBuf<T, A>,
+                                                   MallocError> {
+        ProtBuf::try_with_alignment(length, align)
+    }
+
     /// New allocated buffer with its memory initialized with bytes of
     /// value zero.
     pub fn new_zero(length: usize) -> ProtBuf<T, A> {
@@ -134,6 +217,16 @@ impl<T: Copy, A: Allocator> ProtBuf<T, A> {
         n
     }
 
+    /// Same as `new_zero` but return an error if memory cannot be
+    /// allocated.
+    pub fn try_new_zero(length: usize) -> Result<ProtBuf<T, A>, MallocError> {
+        let n = try!(ProtBuf::try_with_length(length));
+        unsafe {
+            ptr::write_bytes(*n.ptr, 0, length);
+        }
+        Ok(n)
+    }
+
     /// Allocate a new buffer of size `length` and fill it with randomly
     /// generated bytes. Use `rng` as random number generator.
     pub fn new_rand<R: Rng>(length: usize, rng: &mut R) -> ProtBuf<T, A> {
@@ -152,6 +245,26 @@ impl<T: Copy, A: Allocator> ProtBuf<T, A> {
         ProtBuf::new_rand(length, &mut utils::os_rng())
     }
 
+    /// Same as `new_rand` but return an error if memory cannot be
+    /// allocated.
+    pub fn try_new_rand<R: Rng>(length: usize,
+                                rng: &mut R) -> Result<ProtBuf<T, A>,
+                                                       MallocError> {
+        let mut n = try!(ProtBuf::try_with_length(length));
+        rng.fill_bytes(unsafe {
+            slice::from_raw_parts_mut(n.as_mut_ptr() as *mut u8,
+                                      n.len_bytes())
+        });
+        Ok(n)
+    }
+
+    /// Same as `new_rand_os` but return an error if memory cannot be
+    /// allocated.
+    pub fn try_new_rand_os(length: usize) -> Result<ProtBuf<T, A>,
+                                                    MallocError> {
+        ProtBuf::try_new_rand(length, &mut utils::os_rng())
+    }
+
     /// New buffer with elements copied from slice `values`.
     pub fn from_slice(values: &[T]) -> ProtBuf<T, A> {
         unsafe {
@@ -401,8 +514,14 @@ hex_fmt!(LowerHex, u64);
 
 #[cfg(test)]
 mod test {
-    use allocator::{NullHeapAllocator, ProtectedBufferAllocator};
+    use std::thread;
+    use std::usize;
+
+    use allocator::{NullHeapAllocator, ProtectedBufferAllocator,
+                    ProtectedKeyAllocator};
     use buf::{ProtBuf, ProtBuf8};
+    use malloc::MallocError;
+    use utils;
 
 
     #[test]
@@ -466,4 +585,80 @@ mod test {
         let _: ProtBuf8 = ProtBuf::new_zero(42);
         let _: ProtBuf<u8> = ProtBuf::new_zero(42);
     }
+
+    #[test]
+    fn test_send() {
+        let a: ProtBuf8 = ProtBuf::new_rand_os(42);
+        let b: ProtBuf8 = ProtBuf::from_slice(&a);
+
+        // Dropped in another thread than the allocating one.
+        let c = thread::spawn(move|| {
+            assert_eq!(a, b);
+            ProtBuf::<u8>::new_zero(42)
+        }).join().unwrap();
+        assert_eq!(c.len(), 42);
+    }
+
+    #[test]
+    fn test_try_new() {
+        let a: ProtBuf8 = ProtBuf::try_new_zero(42).unwrap();
+        assert_eq!(a.len(), 42);
+        assert!(a.iter().all(|x| *x == 0));
+
+        let b: ProtBuf<u64> = ProtBuf::try_new_rand_os(42).unwrap();
+        assert_eq!(b.len(), 42);
+
+        let c: Result<ProtBuf<u64>, _> = ProtBuf::try_new(usize::MAX);
+        assert_eq!(c.err(), Some(MallocError::Overflow));
+    }
+
+    #[test]
+    fn test_new_aligned() {
+        for &align in [utils::page_size(), 2 << 20].iter() {
+            let mut a: ProtBuf8 = ProtBuf::new_aligned(42, align);
+            assert_eq!(a.len(), 42);
+            assert_eq!(a.as_ptr() as usize % align, 0);
+            a[41] = 42;
+
+            let k = ProtBuf::<u64, ProtectedKeyAllocator>::new_aligned(
+                42, align).into_key();
+            assert_eq!(k.read().as_ptr() as usize % align, 0);
+        }
+
+        let b: Result<ProtBuf8, _> = ProtBuf::try_new_aligned(42, 3);
+        assert_eq!(b.err(), Some(MallocError::InvalidAlignment));
+    }
+
+    #[test]
+    fn test_new_batch() {
+        let large = utils::page_size() * 5;
+        for &length in [42, large].iter() {
+            let mut bufs: Vec<ProtBuf8> = ProtBuf::new_batch(4, length);
+            assert_eq!(bufs.len(), 4);
+            for (i, buf) in bufs.iter_mut().enumerate() {
+                assert_eq!(buf.len(), length);
+                for x in buf.iter_mut() {
+                    *x = i as u8;
+                }
+            }
+            for (i, buf) in bufs.iter().enumerate() {
+                assert!(buf.iter().all(|x| *x == i as u8));
+            }
+
+            // Buffers are dropped independently, some of them in another
+            // thread.
+            let others = bufs.split_off(2);
+            thread::spawn(move|| {
+                drop(others);
+            }).join().unwrap();
+            bufs.pop();
+        }
+
+        let e: Vec<ProtBuf<u64>> = ProtBuf::new_batch(3, 0);
+        assert!(e.iter().all(|buf| buf.len() == 0));
+
+        let c: Result<Vec<ProtBuf<u64>>, _> = ProtBuf::try_new_batch(
+            2, usize::MAX);
+        assert_eq!(c.err(), Some(MallocError::Overflow));
+    }
 }
@@ -7,6 +7,7 @@ use std::rc::Rc;
 
 use allocator::{Allocator, KeyAllocator, DefaultKeyAllocator};
 use buf::ProtBuf;
+use malloc::MallocError;
 
 
 /// Key of bytes
@@ -80,6 +81,21 @@ impl<T: Copy, A: KeyAllocator> ProtKey<T, A> {
         }
     }
 
+    /// Same as `new` but return an error if `prot_buf`'s memory
+    /// protections cannot be changed, `prot_buf` is then deallocated.
+    pub fn try_new(prot_buf: ProtBuf<T, A>) -> Result<ProtKey<T, A>,
+                                                      MallocError> {
+        try!(unsafe {
+            <A as KeyAllocator>::try_protect_none(
+                prot_buf.as_ptr() as *mut u8, prot_buf.len_bytes())
+        });
+
+        Ok(ProtKey {
+            key: RefCell::new(prot_buf),
+            read_ctr: Rc::new(Cell::new(NOREAD))
+        })
+    }
+
     /// Consume and copy `prot_buf` to force using `ProtKey`'s allocator.
     /// If `prot_buf` already uses a `KeyAllocator` there is no need to make
     /// a copy so directly call the default cstor `new` instead.
@@ -360,4 +376,12 @@ mod test {
         let b = ProtBuf::new_zero(42);
         let _: ProtKey<u8> = ProtKey::new(b);
     }
+
+    #[test]
+    fn test_try_new() {
+        let b = ProtBuf::try_new_rand_os(42).unwrap();
+        let c = b.clone();
+        let key: ProtKey8 = ProtKey::try_new(b).unwrap();
+        assert_eq!(*key.read(), c);
+    }
 }
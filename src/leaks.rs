@@ -0,0 +1,178 @@
+//! Leak tracking
+//!
+//! Record the size, kind and allocation backtrace of every live object
+//! while leak tracking is enabled, in order to report the objects still
+//! allocated when a thread exits or on demand. Objects are tracked by
+//! address for the whole process as they may be freed from any thread.
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::{Mutex, MutexGuard, Once, ONCE_INIT};
+
+use malloc::{AllocKind, LeakReport, LeakSite};
+
+
+// Maximum number of frames captured per allocation.
+const MAX_FRAMES: usize = 32;
+
+
+// Live object.
+struct Record {
+    // Identifier of the directory it was allocated from.
+    dir: usize,
+    size: usize,
+    kind: AllocKind,
+    // Return addresses of the allocation's backtrace.
+    frames: Vec<usize>
+}
+
+fn records() -> MutexGuard<'static, HashMap<usize, Record>> {
+    static ONCE: Once = ONCE_INIT;
+    static mut instance: *mut Mutex<HashMap<usize, Record>> =
+        0 as *mut Mutex<HashMap<usize, Record>>;
+
+    unsafe {
+        ONCE.call_once(|| {
+            instance = Box::into_raw(Box::new(Mutex::new(HashMap::new())));
+        });
+
+        // Records are never left in an inconsistent state by a panicking
+        // thread, ignore poisoning.
+        match (*instance).lock() {
+            Ok(guard) => guard,
+            Err(err) => err.into_inner()
+        }
+    }
+}
+
+
+/// Record `ptr` as a live object of `size` bytes and of kind `kind`
+/// allocated from directory `dir` by the current call stack.
+pub fn record(ptr: usize, dir: usize, size: usize, kind: AllocKind) {
+    let frames = bt_imp::capture(MAX_FRAMES);
+    records().insert(ptr, Record {
+        dir: dir,
+        size: size,
+        kind: kind,
+        frames: frames
+    });
+}
+
+/// Forget `ptr`, it was deallocated.
+pub fn forget(ptr: usize) {
+    records().remove(&ptr);
+}
+
+/// Attribute the objects of directory `orphan` to directory `dir`, it
+/// adopted them.
+pub fn adopt(orphan: usize, dir: usize) {
+    for record in records().values_mut() {
+        if record.dir == orphan {
+            record.dir = dir;
+        }
+    }
+}
+
+/// Report the live objects allocated from directory `dir`, or all of them
+/// if `dir` is `None`, grouped by allocation backtrace.
+pub fn report(dir: Option<usize>) -> LeakReport {
+    let mut sites: HashMap<Vec<usize>, Vec<(usize, usize, AllocKind)>> =
+        HashMap::new();
+
+    for (&ptr, record) in records().iter() {
+        if dir.map_or(false, |id| id != record.dir) {
+            continue;
+        }
+        sites.entry(record.frames.clone()).or_insert(Vec::new())
+            .push((ptr, record.size, record.kind));
+    }
+
+    // Symbolize out of the lock.
+    let mut sites: Vec<LeakSite> = sites.into_iter()
+        .map(|(frames, mut allocations)| {
+            allocations.sort_by(|a, b| a.0.cmp(&b.0));
+            LeakSite {
+                backtrace: bt_imp::symbolize(&frames),
+                allocations: allocations
+            }
+        })
+        .collect();
+    sites.sort_by(|a, b| b.bytes().cmp(&a.bytes()));
+
+    LeakReport {
+        sites: sites
+    }
+}
+
+/// Write to the standard error the report of the objects of directory
+/// `dir` still allocated when its thread exits.
+pub fn report_exit(dir: usize) {
+    let report = report(Some(dir));
+    if !report.is_empty() {
+        let _ = write!(io::stderr(), "tars: objects still allocated at \
+                                      thread exit, {}\n", report);
+    }
+}
+
+
+#[cfg(any(all(target_os = "linux", target_env = "gnu"),
+          target_os = "macos"))]
+mod bt_imp {
+    use libc::funcs::c95::stdlib::free;
+    use libc::types::common::c95::c_void;
+    use libc::types::os::arch::c95::{c_char, c_int};
+    use std::ffi::CStr;
+    use std::ptr;
+
+    extern {
+        fn backtrace(buffer: *mut *mut c_void, size: c_int) -> c_int;
+        fn backtrace_symbols(buffer: *const *mut c_void,
+                             size: c_int) -> *mut *mut c_char;
+    }
+
+    pub fn capture(max_frames: usize) -> Vec<usize> {
+        let mut frames: Vec<*mut c_void> = vec![ptr::null_mut(); max_frames];
+        let count = unsafe {
+            backtrace(frames.as_mut_ptr(), max_frames as c_int)
+        };
+        frames.iter().take(count as usize).map(|&frame| frame as usize)
+            .collect()
+    }
+
+    pub fn symbolize(frames: &[usize]) -> Vec<String> {
+        let addrs: Vec<*mut c_void> = frames.iter()
+            .map(|&frame| frame as *mut c_void).collect();
+
+        unsafe {
+            let symbols = backtrace_symbols(addrs.as_ptr(),
+                                            addrs.len() as c_int);
+            if symbols.is_null() {
+                return super::addresses(frames);
+            }
+
+            let names = (0..addrs.len()).map(|i| {
+                let name = *symbols.offset(i as isize);
+                String::from_utf8_lossy(CStr::from_ptr(name).to_bytes())
+                    .into_owned()
+            }).collect();
+            free(symbols as *mut c_void);
+            names
+        }
+    }
+}
+
+#[cfg(not(any(all(target_os = "linux", target_env = "gnu"),
+              target_os = "macos")))]
+mod bt_imp {
+    pub fn capture(_: usize) -> Vec<usize> {
+        Vec::new()
+    }
+
+    pub fn symbolize(frames: &[usize]) -> Vec<String> {
+        super::addresses(frames)
+    }
+}
+
+// Unsymbolized frames.
+fn addresses(frames: &[usize]) -> Vec<String> {
+    frames.iter().map(|&frame| format!("{:#x}", frame)).collect()
+}
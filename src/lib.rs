@@ -24,7 +24,7 @@
 #![cfg_attr(test, feature(step_by))]
 
 #[cfg(test)] extern crate test;
-#[cfg(test)] #[macro_use] extern crate log;
+#[cfg(any(test, feature = "malloc_log"))] #[macro_use] extern crate log;
 
 extern crate alloc;
 
@@ -36,9 +36,15 @@ pub use allocator::DefaultBufferAllocator;
 pub use allocator::DefaultKeyAllocator;
 pub use buf::{ProtBuf, ProtBuf8};
 pub use key::{ProtKey, ProtKey8, ProtKeyRead, ProtKeyWrite};
+pub use malloc::MallocError;
 
 mod utils;
+mod rng;
 mod mmap;
+mod registry;
+mod leaks;
+mod svg;
+mod trace;
 pub mod malloc;
 pub mod allocator;
 mod buf;
@@ -14,8 +14,17 @@
 //! All regions allocated through these functions must call `free` to
 //! deallocate their memory.
 //!
-//! `realloc` and `realloc_key` currently always work by explictly
-//! making a new copy after having allocated a new object.
+//! Objects larger than half a page and up to four pages are medium
+//! objects, packed in the slots of a span with a guard page between each
+//! slot. Free slots are inaccessible and unlocked, the pages of a medium
+//! object are destroyed as soon as it is freed.
+//!
+//! `realloc` and `realloc_key` return the same pointer when the new size
+//! stays in the same chunk or medium size-class. Large objects and keys
+//! are grown or shrunk in place when possible (with `mremap` on Linux),
+//! keys may then be moved inside their mapping to stay next to their
+//! trailing guard page. Otherwise a new object is allocated, the data
+//! copied and the previous object wiped and freed.
 //!
 //! Mixing different functions together may lead to undetermined
 //! behaviors. For instance if an attempt is made to reallocate memory
@@ -29,6 +38,26 @@
 //! unwinding as each allocator is instantiated and dedicated to a single
 //! thread.
 //!
+//! Memory may however be freed from another thread than the one that
+//! allocated it, its object is then wiped right away except for keys, and
+//! its pointer queued and deallocated by its owning thread on its next
+//! call to the allocator. Errors met then, such as double frees, are
+//! returned to the freeing thread by `take_deferred_errors`. When a thread
+//! exits while some of its objects are still alive, they are adopted by
+//! the next thread calling the allocator instead of being unmapped, unless
+//! its metadata are corrupted, see `corrupted_orphans`. `realloc` and
+//! `protect_*` functions must still be called from the owning thread.
+//!
+//! `malloc_batch` allocates several objects of the same size at once,
+//! large objects then share a single mapping while keeping their own
+//! guard pages so that each of them is still freed independently.
+//!
+//! `try_malloc`, `try_calloc`, `try_malloc_key`, `try_malloc_batch`,
+//! `try_realloc`, `try_realloc_key`, `try_free` and `try_protect` are
+//! fallible variants of these functions, they report the same errors as
+//! a `MallocError` instead of `panic!`ing, for instance when the process
+//! runs out of lockable memory.
+//!
 //! Allocations of size zero are handled by returning a pointer to a
 //! static page that can't be read nor written, emitting a termination
 //! signal on any attempt.
@@ -37,26 +66,88 @@
 //! used to change memory protections on regions allocated through
 //! `malloc_key` and `realloc_key` exclusively.
 //!
+//! The allocator's behavior can be tuned at runtime with `Options`, either
+//! programmatically before its first use or through the
+//! `TARS_MALLOC_OPTIONS` environment variable.
+//!
+//! The memory used by each thread and by the whole process can be limited
+//! with `set_thread_quota` and `set_process_quota`, allocations going over
+//! a quota fail cleanly. Current usage is returned by `thread_usage` and
+//! `process_usage`.
+//!
+//! `trim` releases the empty chunks and spare mappings cached by the
+//! current thread and shrinks its regions table, `trim_all` requests the
+//! same from every thread. Other threads only trim on their next call to
+//! the allocator, idle threads keep their caches. When mapping or locking
+//! memory fails, the allocator trims the caches of the current thread
+//! only and retries once before reporting the error.
+//!
+//! `check_heap` cross-checks all the metadata of the current thread's
+//! allocator, it is meant to be called from tests to detect heap
+//! corruptions close to where they happen. `snapshot` returns the kind,
+//! size, pages, slots occupancy and protection of each of its regions and
+//! can be rendered in JSON with `HeapSnapshot::to_json` or drawn as an
+//! SVG heap map with `render_svg`.
+//!
+//! When leak tracking is enabled, the backtrace of each allocation is
+//! recorded and the objects still allocated are reported by
+//! `report_leaks`, they are also reported on the standard error when
+//! their thread exits.
+//!
+//! When tracing is enabled, the last events of each thread are kept in a
+//! ring buffer returned by `trace_events`, passed to the hook set with
+//! `set_trace_hook`, logged with the `log` crate when built with the
+//! `malloc_log` feature and dumped on the standard error before any
+//! `panic!` on error, including failed assertions.
+//!
+//! When enabled, statistics of the current thread are returned by `stats`
+//! and aggregated across all threads by `stats_all`. They can be rendered
+//! in the Prometheus text exposition format with `Stats::to_prometheus`.
+//!
+//! When built with the `malloc_seed` feature, `set_seed` or the
+//! `TARS_MALLOC_SEED` environment variable enable a deterministic mode for
+//! debugging where the placement of objects, the choice of chunks and
+//! slots, the evictions and the canaries of every thread are derived from
+//! a single seed, in the order threads first use the allocator. Mapping
+//! addresses are still chosen by the kernel. A warning is printed on the
+//! standard error once enabled, this mode defeats the randomization of
+//! the heap and must never be used in production. Release builds refuse
+//! it with a warning.
+//!
 //! This malloc implementation is heavily inspired by [OpenBSD's malloc](
 //! http://www.openbsd.org/cgi-bin/man.cgi?query=malloc&arch=default&
 //! manpath=OpenBSD-current).
 //!
-use std::cell::RefCell;
+use std::cell::{RefCell, RefMut};
 use std::cmp;
-use std::fmt::{self, Debug, Formatter};
+use std::env;
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter, Write};
 use std::hash::{Hash, SipHasher, Hasher};
-use std::iter;
 use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::ptr;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::thread;
 
 use num::ToPrimitive;
 use rand::Rng;
 
-use mmap::{self, RangePos, Prot};
+use mmap::{self, MapError, RangePos};
+use leaks;
+use registry::{self, Counters, Layout, Shared};
+use rng::PoolRng;
+#[cfg(feature = "malloc_seed")]
+use rng;
+use svg;
+use trace::{self, Ring};
 use utils;
 
+pub use mmap::Prot;
+#[cfg(feature = "malloc_seed")]
+pub use rng::SEED_ENV;
+
 
 // Chunks
 // Minimal size of a slot in a chunk.
@@ -65,31 +156,111 @@ const MIN_CHUNK_SIZE: usize = 16;
 // is provided only for use in the Dir struct. It is expected to
 // be larger than the real value.
 const MAX_CHUNK_SHIFT: usize = 16;
+// Number of size-classes between two powers of two, must be a power of
+// two. Their sizes remain multiples of mmap::MIN_ALIGN.
+const CHUNK_SUBCLASSES: usize = 4;
+// Number of chunks size-classes indexes, see `chunk_index`.
+const CHUNK_CLASSES: usize = MAX_CHUNK_SHIFT * CHUNK_SUBCLASSES;
 // It will only be possible to map up to MAX_CHUNK_MAPPING * 8
 // slots in a chunk.
 const MAX_CHUNK_MAPPING: usize = 16;
+// Bits in a word of the slots bitmap of a chunk.
+#[cfg(target_pointer_width = "32")]
+const WORD_BITS: usize = 32;
+#[cfg(target_pointer_width = "64")]
+const WORD_BITS: usize = 64;
+// Number of words of the slots bitmap of a chunk.
+const MAPPING_WORDS: usize = MAX_CHUNK_MAPPING * 8 / WORD_BITS;
+
+// Medium objects
+// Maximal number of pages of a medium object, must be a power of two.
+const MAX_MEDIUM_PAGES: usize = 4;
+// Number of medium size-classes, i.e. log2(MAX_MEDIUM_PAGES) + 1.
+const MEDIUM_CLASSES: usize = 3;
+// Number of pages spanned by the slots of medium objects of a span, guard
+// pages between them included. Spans are aligned on this size.
+const SPAN_PAGES: usize = 16;
+
+// Bytes used at the end of a chunk slot to store the length of its
+// canary, see `write_canary`.
+const CANARY_LEN_BYTES: usize = 2;
 
 // Initial number of regions, must be a power of two.
 const INITIAL_REGIONS: usize = 128;
+// Index of no region, ends lists of regions.
+const NO_REGION: usize = !0;
 
 // Junk bytes used to fill buffers after memory allocation and
 // before deallocation. Even if disabled, memory will be zeroed-out
 // on deallocation but will use byte of value zero.
-const USE_JUNK: bool = true;
 const ALLOC_JUNK: u8 = 0xd0;
 const FREE_JUNK: u8 = 0xdf;
 
-// Cache empty chunks.
-const USE_CACHE: bool = true;
-const MAX_CACHE_SIZE: usize = 64;
+// Default and maximal numbers of cached empty chunks.
+const DEFAULT_CACHE_SIZE: usize = 64;
+const MAX_CACHE_SIZE: usize = 256;
+
+// Default and maximal numbers of pages of freed large mappings kept for
+// reuse, and maximal number of such mappings.
+const DEFAULT_SPARE_PAGES: usize = 64;
+const MAX_SPARE_PAGES: usize = 1024;
+const MAX_SPARES: usize = 32;
 
-// Set `true` to this constant or pass `--cfg feature="malloc_stats"`
-// to `rustc` or use `cargo build --features malloc_stats` with cargo
-// to enable assembling statistics.
+// Depth of the quarantine once enabled and its maximal depth.
+const DEFAULT_QUARANTINE: usize = 16;
+const MAX_QUARANTINE: usize = 256;
+
+// Pass `--cfg feature="malloc_stats"` to `rustc` or use `cargo build
+// --features malloc_stats` with cargo to enable assembling statistics
+// by default.
 #[cfg(feature = "malloc_stats")]
-const USE_STATS: bool = true;
+const DEFAULT_STATS: bool = true;
 #[cfg(not(feature = "malloc_stats"))]
-const USE_STATS: bool = false;
+const DEFAULT_STATS: bool = false;
+
+// Pass `--cfg feature="no_mlock"` to `rustc` or use `cargo build
+// --features no_mlock` with cargo to disable `mlock` calls by default
+// (it might be needed in virtual environments).
+#[cfg(feature = "no_mlock")]
+const DEFAULT_MLOCK: bool = false;
+#[cfg(not(feature = "no_mlock"))]
+const DEFAULT_MLOCK: bool = true;
+
+/// Name of the environment variable read for options flags.
+pub const OPTIONS_ENV: &'static str = "TARS_MALLOC_OPTIONS";
+
+// Options states.
+const OPTIONS_UNSET: usize = 0;
+const OPTIONS_BUSY: usize = 1;
+const OPTIONS_SET: usize = 2;
+
+const DEFAULT_OPTIONS: Options = Options {
+    canaries: false,
+    junk: true,
+    cache_size: DEFAULT_CACHE_SIZE,
+    quarantine: 0,
+    spare_pages: DEFAULT_SPARE_PAGES,
+    protect_metadata: false,
+    track_leaks: false,
+    stats: DEFAULT_STATS,
+    validate_junk: false,
+    mlock: DEFAULT_MLOCK,
+    trace: false
+};
+
+static OPTIONS_STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+static mut OPTIONS: Options = DEFAULT_OPTIONS;
+
+// Memory used by all the directories and process-wide quotas, stored as
+// their limit plus one or 0 if unlimited.
+static PROCESS_BYTES: AtomicUsize = ATOMIC_USIZE_INIT;
+static PROCESS_PAGES: AtomicUsize = ATOMIC_USIZE_INIT;
+static QUOTA_BYTES: AtomicUsize = ATOMIC_USIZE_INIT;
+static QUOTA_PAGES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Incremented by `trim_all`, each directory trims its caches on its next
+// use once its own epoch differs.
+static TRIM_EPOCH: AtomicUsize = ATOMIC_USIZE_INIT;
 
 
 #[inline]
@@ -117,30 +288,64 @@ fn max_slot_index(chunk_size: usize) -> usize {
     mmap::page_size() / chunk_size
 }
 
+// Largest power of two lower than or equal to `size`.
+#[inline]
+fn floor_power_of_two(size: usize) -> usize {
+    if size.is_power_of_two() {
+        size
+    } else {
+        size.next_power_of_two() >> 1
+    }
+}
+
+// Gap between the size-classes following the power of two below `size`.
+#[inline]
+fn chunk_step(size: usize) -> usize {
+    cmp::max(floor_power_of_two(size) / CHUNK_SUBCLASSES, mmap::MIN_ALIGN)
+}
+
 #[inline]
 fn chunk_size(size: usize) -> usize {
     match size {
         0 => 0,
         sz if sz <= min_chunk_size() => min_chunk_size(),
-        sz if sz <= max_chunk_size() => sz.next_power_of_two(),
+        sz if sz <= max_chunk_size() => {
+            let step = chunk_step(sz);
+            (sz + step - 1) & !(step - 1)
+        },
         _ => unreachable!()
     }
 }
 
+// Index of size-class `chunk_size`, size-classes from 2^i up to 2^(i+1)
+// are indexed from i * CHUNK_SUBCLASSES.
 #[inline]
 fn chunk_index(chunk_size: usize) -> usize {
     match chunk_size {
         0 => 0,
         cs => {
-            assert!(cs.is_power_of_two());
-            cs.trailing_zeros().to_usize().unwrap()
+            debug_assert_eq!(chunk_size(cs), cs);
+            let base = floor_power_of_two(cs);
+            base.trailing_zeros().to_usize().unwrap() * CHUNK_SUBCLASSES +
+                (cs - base) / (base / CHUNK_SUBCLASSES)
         }
     }
 }
 
+// Bytes needed in a chunk slot to hold `size` bytes and its canary if
+// canaries are enabled.
+#[inline]
+fn slot_size(size: usize) -> usize {
+    if size != 0 && options().canaries {
+        size.saturating_add(CANARY_LEN_BYTES)
+    } else {
+        size
+    }
+}
+
 #[inline]
 fn fill_byte_alloc(zero_fill: bool) -> Option<u8> {
-    match (zero_fill, USE_JUNK) {
+    match (zero_fill, options().junk) {
         (true, _) => Some(0),
         (false, true) => Some(ALLOC_JUNK),
         (_, _) => None
@@ -149,1781 +354,6057 @@ fn fill_byte_alloc(zero_fill: bool) -> Option<u8> {
 
 #[inline]
 fn fill_byte_dealloc() -> Option<u8> {
-    if USE_JUNK {
+    if options().junk {
         Some(FREE_JUNK)
     } else {
         Some(0)
     }
 }
 
+// Chunks size-classes as pairs of (index, size).
+fn size_classes() -> Vec<(usize, usize)> {
+    (chunk_index(min_chunk_size())..chunk_index(max_chunk_size()) + 1)
+        .filter_map(|i| {
+            let base = 1_usize << (i / CHUNK_SUBCLASSES);
+            let size = base + i % CHUNK_SUBCLASSES * base / CHUNK_SUBCLASSES;
+            if chunk_size(size) == size {
+                Some((i, size))
+            } else {
+                None
+            }
+        }).collect()
+}
+
+#[inline]
+fn max_medium_size() -> usize {
+    MAX_MEDIUM_PAGES * mmap::page_size()
+}
+
+// Return `true` if an object of `size` bytes aligned on `align` is a
+// medium object, allocated in a slot of a span.
+#[inline]
+fn is_medium(size: usize, align: usize, force_large: bool) -> bool {
+    !force_large && align <= mmap::page_size() &&
+        slot_size(size) > max_chunk_size() && size <= max_medium_size()
+}
 
-unsafe fn dir_alloc() -> *mut u8 {
-    mmap::allocate(mem::size_of::<Dir>(),
-                   mem::align_of::<Dir>(),
-                   None,
-                   Prot::ReadWrite,
-                   RangePos::Rand)
+// Size-class of a medium object of `size` bytes, a power of two number of
+// pages.
+#[inline]
+fn medium_size(size: usize) -> usize {
+    let pages = mmap::page_round(size).unwrap() / mmap::page_size();
+    pages.next_power_of_two() * mmap::page_size()
 }
 
-unsafe fn dir_dealloc(ptr: *mut u8) {
-    mmap::deallocate(ptr, mem::size_of::<Dir>(), Some(0));
+#[inline]
+fn medium_index(medium_size: usize) -> usize {
+    (medium_size / mmap::page_size()).trailing_zeros().to_usize().unwrap()
 }
 
-unsafe fn regions_alloc(count: usize) -> *mut u8 {
-    let size = count.checked_mul(mem::size_of::<Region>()).unwrap();
-    mmap::allocate(size,
-                   mem::align_of::<Region>(),
-                   None,
-                   Prot::ReadWrite,
-                   RangePos::Start)
+// Number of slots of a span of size-class `medium_size`. Each slot is
+// followed by a guard page, the trailing guard page of the span's mapping
+// for the last one.
+#[inline]
+fn span_slots(medium_size: usize) -> usize {
+    (SPAN_PAGES + 1) / (medium_size / mmap::page_size() + 1)
 }
 
-unsafe fn regions_dealloc(ptr: *mut u8, count: usize) {
-    let size = count.checked_mul(mem::size_of::<Region>()).unwrap();
-    mmap::deallocate(ptr, size, Some(0));
+// Size of the mapping of a span of size-class `medium_size`, without its
+// leading and trailing guard pages.
+#[inline]
+fn span_size(medium_size: usize) -> usize {
+    span_slots(medium_size) * (medium_size + mmap::page_size()) -
+        mmap::page_size()
 }
 
+// First page of the span that would hold `ptr`.
+#[inline]
+fn span_base(ptr: *mut u8) -> *mut u8 {
+    (ptr as usize & !(SPAN_PAGES * mmap::page_size() - 1)) as *mut u8
+}
 
-// Provide an interface to the pages directory.
-#[doc(hidden)]
-pub struct ThreadDir {
-    dir: Rc<RefCell<LocalDir>>
+// Medium size-classes as pairs of (index, size).
+fn medium_classes() -> Vec<(usize, usize)> {
+    (0..MEDIUM_CLASSES).map(|i| (i, mmap::page_size() << i)).collect()
 }
 
-impl ThreadDir {
-    pub unsafe fn alloc(&mut self, size: usize, zero_fill: bool,
-                        force_large: bool) -> *mut u8 {
-        self.dir.borrow_mut().alloc(size, zero_fill, force_large)
+// Number of pages of a mapping of `size` bytes, guard pages included.
+#[inline]
+fn mapping_pages(size: usize) -> usize {
+    mmap::page_round(size).unwrap_or(0) / mmap::page_size() + 2
+}
+
+// Return `true` if adding `more` to `used` goes over `limit`.
+#[inline]
+fn exceeds(used: usize, more: usize, limit: Option<usize>) -> bool {
+    more > 0 && limit.map_or(false, |limit| {
+        used.checked_add(more).map_or(true, |total| total > limit)
+    })
+}
+
+// Number of bytes locked in memory by a mapping of `size` bytes.
+#[inline]
+fn locked_size(size: usize) -> usize {
+    if options().mlock {
+        mmap::page_round(size).unwrap_or(0)
+    } else {
+        0
+    }
+}
+
+// Word `block` of the canary of `slot` keyed by `keys`, the masks of the
+// bytes `8 * block` to `8 * block + 7` of the slot. Knowing some canary
+// bytes tells nothing about the others nor about the keys.
+#[inline]
+fn canary_word(keys: (u64, u64), slot: *const u8, block: usize) -> u64 {
+    let mut s = SipHasher::new_with_keys(keys.0, keys.1);
+    (slot as usize).hash(&mut s);
+    block.hash(&mut s);
+    s.finish()
+}
+
+// Canary byte at offset `i` of `slot` given the canary word of its block.
+#[inline]
+fn canary_byte(word: u64, i: usize) -> u8 {
+    (word >> (i % 8 * 8)) as u8
+}
+
+// Write the canary of `slot` of size-class `chunk_size` holding `size`
+// bytes. Canary bytes fill the unused tail of the slot except for its
+// last bytes which store the masked length of this tail.
+unsafe fn write_canary(slot: *mut u8, chunk_size: usize, size: usize,
+                       keys: (u64, u64)) {
+    let tail = chunk_size - size;
+    assert!(tail >= CANARY_LEN_BYTES);
+
+    let mut word = 0;
+    for i in size..chunk_size - CANARY_LEN_BYTES {
+        if i == size || i % 8 == 0 {
+            word = canary_word(keys, slot, i / 8);
+        }
+        *slot.offset(i as isize) = canary_byte(word, i);
+    }
+
+    let len = tail as u16 ^ canary_word(keys, slot, !0) as u16;
+    *slot.offset((chunk_size - 2) as isize) = len as u8;
+    *slot.offset((chunk_size - 1) as isize) = (len >> 8) as u8;
+}
+
+// Return the length of the tail of `slot` if its canary is intact.
+unsafe fn canary_tail(slot: *const u8, chunk_size: usize,
+                      keys: (u64, u64)) -> Option<usize> {
+    let len = *slot.offset((chunk_size - 2) as isize) as u16 |
+        (*slot.offset((chunk_size - 1) as isize) as u16) << 8;
+    let tail = (len ^ canary_word(keys, slot, !0) as u16) as usize;
+    if tail < CANARY_LEN_BYTES || tail > chunk_size {
+        return None;
     }
 
-    pub unsafe fn realloc(&mut self, ptr: *mut u8, size: usize, zero_fill: bool,
-                          force_large: bool) -> *mut u8 {
-        self.dir.borrow_mut().realloc(ptr, size, zero_fill, force_large)
+    let size = chunk_size - tail;
+    let mut word = 0;
+    for i in size..chunk_size - CANARY_LEN_BYTES {
+        if i == size || i % 8 == 0 {
+            word = canary_word(keys, slot, i / 8);
+        }
+        if *slot.offset(i as isize) != canary_byte(word, i) {
+            return None;
+        }
     }
+    Some(tail)
+}
 
-    pub unsafe fn dealloc(&mut self, ptr: *mut u8) {
-        self.dir.borrow_mut().dealloc(ptr)
+// Check that the `size` bytes at `offset` in `chunk`, a chunk of size-class
+// `chunk_size`, still hold the junk written when they were freed.
+unsafe fn check_junk(chunk: *const u8, offset: usize, size: usize,
+                     chunk_size: usize) -> Result<(), MallocError> {
+    if !options().validate_junk {
+        return Ok(());
     }
 
-    pub unsafe fn protect(&mut self, ptr: *mut u8, prot: Prot) {
-        self.dir.borrow_mut().protect(ptr, prot)
+    let junk = fill_byte_dealloc().unwrap();
+    match (offset..offset + size).find(|&i| *chunk.offset(i as isize) != junk) {
+        None => Ok(()),
+        Some(i) => Err(MallocError::WriteAfterFree {
+            chunk: chunk as usize,
+            offset: if chunk_size == 0 { i } else { i - i % chunk_size },
+            size_class: chunk_size
+        })
     }
 }
 
-impl Debug for ThreadDir {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        self.dir.borrow().fmt(f)
+// Add `value` to `counter` if `add` is true, subtract it otherwise.
+#[inline]
+fn stat_update(counter: &AtomicUsize, value: usize, add: bool) {
+    if add {
+        counter.fetch_add(value, Ordering::Relaxed);
+    } else {
+        counter.fetch_sub(value, Ordering::Relaxed);
     }
 }
 
 
-// Structure used to manage a Dir and owned by a thread.
-struct LocalDir {
-    dir: *mut Dir
+/// Allocator options
+///
+/// Options are shared by all threads and can't be changed once the
+/// allocator is used for the first time. Until then they can be set with
+/// `set_options`, otherwise they are parsed from the `TARS_MALLOC_OPTIONS`
+/// environment variable, in the style of OpenBSD's `malloc.conf`. Each
+/// character of this variable is a flag, uppercase enables an option and
+/// lowercase disables it:
+///
+/// * `C`/`c`: write a canary after the bytes allocated in chunk slots,
+///   checked on deallocation to detect overflows (default: disabled).
+/// * `D`/`d`: collect statistics returned by `stats` and `stats_all`
+///   (default: disabled unless built with the `malloc_stats` feature).
+/// * `E`/`e`: record the last allocations, reallocations, deallocations
+///   and protection changes of each thread, see `trace_events` (default:
+///   disabled).
+/// * `J`/`j`: fill memory with junk bytes on allocation and deallocation,
+///   when disabled memory is still zeroed-out on deallocation (default:
+///   enabled).
+/// * `L`/`l`: lock pages in memory with `mlock` (default: enabled unless
+///   built with the `no_mlock` feature).
+/// * `P`/`p`: keep the allocator's metadata of each thread inaccessible
+///   while not in use, they are only opened for writing during the
+///   allocator's operations at the cost of extra `mprotect` calls
+///   (default: disabled).
+/// * `T`/`t`: track the allocation backtrace of every object to report
+///   the objects still allocated with `report_leaks` and at thread exit,
+///   this is slow (default: disabled).
+/// * `V`/`v`: check that the junk written in freed chunk slots is intact
+///   before they are reused, to detect writes after free (default:
+///   disabled).
+/// * `Q`/`q`: enable or double, or disable the quarantine of freed objects
+///   per thread. Quarantined chunk slots are wiped and can't be allocated
+///   until evicted at random, large objects and keys are replaced by an
+///   inaccessible mapping (default: disabled, depth once enabled: 16,
+///   maximum: 256).
+/// * `S`/`s`: enable or double, or disable the cache of freed large
+///   objects and keys mappings per thread. They are wiped, made
+///   inaccessible and kept locked to be reused by allocations of the same
+///   number of pages, at most 32 of them are kept and evicted at random
+///   (default: 64 pages, maximum: 1024 pages).
+/// * `<`/`>`: halve or double the number of cached empty chunks per
+///   thread (default: 64, maximum: 256, 0 disables caching).
+///
+/// ```rust
+/// # use tars::malloc::{self, Options};
+/// // Run cheaply, for instance in a CI environment.
+/// let opts = Options::parse("jl<<<<<<<").unwrap();
+/// assert!(!opts.junk && !opts.mlock && opts.cache_size == 0);
+/// malloc::set_options(opts);
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Write canaries after chunk slots.
+    pub canaries: bool,
+    /// Fill memory with junk bytes.
+    pub junk: bool,
+    /// Maximum number of cached empty chunks per thread.
+    pub cache_size: usize,
+    /// Number of freed objects kept in quarantine per thread, at most 256.
+    pub quarantine: usize,
+    /// Maximum number of pages of freed large mappings cached per thread.
+    pub spare_pages: usize,
+    /// Protect the allocator's metadata while not in use.
+    pub protect_metadata: bool,
+    /// Track allocations to report leaks.
+    pub track_leaks: bool,
+    /// Collect statistics.
+    pub stats: bool,
+    /// Check the junk of freed chunk slots before their reuse.
+    pub validate_junk: bool,
+    /// Lock pages in memory.
+    pub mlock: bool,
+    /// Record the allocator's events.
+    pub trace: bool
 }
 
-impl Drop for LocalDir {
-    fn drop(&mut self) {
-        unsafe {
-            // Do not do anything if structure's integrity is broken.
-            if self.dir.is_null() || !(*self.dir).check_integrity() {
-                return;
-            }
+impl Options {
+    /// Default options.
+    pub fn new() -> Options {
+        DEFAULT_OPTIONS
+    }
 
-            // FIXME: temp debug
-            // Dump statistics.
-            // info!("{}", *self.dir);
+    /// Default options modified by `flags`. Return the first unknown flag
+    /// as error.
+    pub fn parse(flags: &str) -> Result<Options, char> {
+        let mut opts = Options::new();
+        try!(opts.apply(flags));
+        Ok(opts)
+    }
 
-            // Force dealloc regions metadata and objects to clean-up the heap.
-            (*self.dir).scavenge();
-            // Dealloc Dir.
-            dir_dealloc(self.dir as *mut u8);
-            self.dir = ptr::null_mut();
+    /// Default options modified by the flags of `TARS_MALLOC_OPTIONS`,
+    /// unknown flags are ignored with a warning on the standard error.
+    pub fn from_env() -> Options {
+        use std::io::{self, Write};
+
+        let mut opts = Options::new();
+        if let Ok(flags) = env::var(OPTIONS_ENV) {
+            for c in flags.chars() {
+                if let Err(flag) = opts.apply_flag(c) {
+                    let _ = writeln!(io::stderr(),
+                                     "tars malloc: WARNING: unknown flag \
+                                      '{}' in {} ignored", flag,
+                                     OPTIONS_ENV);
+                }
+            }
         }
+        opts
     }
-}
 
-impl Deref for LocalDir {
-    type Target = Dir;
+    fn apply(&mut self, flags: &str) -> Result<(), char> {
+        for c in flags.chars() {
+            try!(self.apply_flag(c));
+        }
+        Ok(())
+    }
 
-    fn deref(&self) -> &Dir {
-        unsafe {
-            &*self.dir
+    fn apply_flag(&mut self, flag: char) -> Result<(), char> {
+        match flag {
+            'C' => self.canaries = true,
+            'c' => self.canaries = false,
+            'D' => self.stats = true,
+            'd' => self.stats = false,
+            'E' => self.trace = true,
+            'e' => self.trace = false,
+            'J' => self.junk = true,
+            'j' => self.junk = false,
+            'V' => self.validate_junk = true,
+            'v' => self.validate_junk = false,
+            'L' => self.mlock = true,
+            'l' => self.mlock = false,
+            'P' => self.protect_metadata = true,
+            'p' => self.protect_metadata = false,
+            'T' => self.track_leaks = true,
+            't' => self.track_leaks = false,
+            'Q' => self.quarantine = match self.quarantine {
+                0 => DEFAULT_QUARANTINE,
+                depth => cmp::min(depth << 1, MAX_QUARANTINE)
+            },
+            'q' => self.quarantine = 0,
+            'S' => self.spare_pages = match self.spare_pages {
+                0 => DEFAULT_SPARE_PAGES,
+                pages => cmp::min(pages << 1, MAX_SPARE_PAGES)
+            },
+            's' => self.spare_pages = 0,
+            '<' => self.cache_size >>= 1,
+            '>' => self.cache_size = cmp::min(self.cache_size << 1,
+                                              MAX_CACHE_SIZE),
+            _ => return Err(flag)
         }
+        Ok(())
     }
 }
 
-impl DerefMut for LocalDir {
-    fn deref_mut(&mut self) -> &mut Dir {
-        unsafe {
-            &mut *self.dir
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+// Freeze options to `opts` or to the environment's options. Return `false`
+// if options were already frozen.
+fn options_init(opts: Option<Options>) -> bool {
+    match OPTIONS_STATE.compare_and_swap(OPTIONS_UNSET, OPTIONS_BUSY,
+                                         Ordering::SeqCst) {
+        OPTIONS_UNSET => {
+            unsafe {
+                OPTIONS = opts.unwrap_or_else(Options::from_env);
+            }
+            OPTIONS_STATE.store(OPTIONS_SET, Ordering::SeqCst);
+            true
+        },
+        _ => {
+            while OPTIONS_STATE.load(Ordering::SeqCst) != OPTIONS_SET {
+                thread::yield_now();
+            }
+            false
         }
     }
 }
 
+/// Set allocator options
+///
+/// Must be called before the allocator is used for the first time.
+/// Return `false` if options are already in use, they are then left
+/// unchanged.
+pub fn set_options(opts: Options) -> bool {
+    options_init(Some(opts))
+}
 
-#[doc(hidden)]
-pub fn thread_dir() -> ThreadDir {
-    thread_local!(static THREAD_DIR_KEY: Rc<RefCell<LocalDir>> = {
-        let dir = LocalDir {
-            dir: unsafe { Dir::init() }
-        };
-        Rc::new(RefCell::new(dir))
-    });
+/// Enable the deterministic mode with `seed`
+///
+/// Only available when built with the `malloc_seed` feature, see the
+/// module's documentation. Must be called before the allocator is used
+/// for the first time, otherwise the seed is read from `SEED_ENV`.
+/// Return `false` if the seed is already in use, it is then left
+/// unchanged, or if this is a release build which never enables this
+/// mode.
+#[cfg(feature = "malloc_seed")]
+pub fn set_seed(seed: u64) -> bool {
+    rng::set_seed(seed)
+}
 
-    ThreadDir {
-        dir: THREAD_DIR_KEY.with(|dir| dir.clone())
+/// Return the options in use
+///
+/// Options are frozen by this call if they were not already.
+pub fn options() -> Options {
+    if OPTIONS_STATE.load(Ordering::SeqCst) != OPTIONS_SET {
+        options_init(None);
+    }
+    unsafe {
+        OPTIONS
     }
 }
 
 
-// Central pages directory.
-struct Dir {
-    // Canary used for integrity checks.
-    canary1: usize,
-    // Pointer to the current pool of allocated regions.
-    regions: *mut Region,
-    // Total number of regions allocated in the pool.
-    total: usize,
-    // Number of unused regions.
-    free: usize,
-    // Cache list.
-    cache1: *mut u8,
-    // Cache list's back pointer.
-    cache2: *mut u8,
-    // Current number of cached free chunks.
-    cache_len: usize,
-    // Pointers to chunks with free slots where index i represents chunks
-    // with slots of size-class 2^i (except for i=0 used to handle allocations
-    // of size 0 and also for the first indexes which might remain unused
-    // depending on the pagesize and the value of MAX_CHUNK_MAPPING).
-    chunks1: [*mut u8; MAX_CHUNK_SHIFT],
-    // Pointers to the last chunks of their respective chunk lists inserted
-    // in chunks1.
-    chunks2: [*mut u8; MAX_CHUNK_SHIFT],
-    // Canary.
-    canary2: usize,
-    // Statistics.
-    stats: Option<Stats>
-}
-
-
-// Aggregated Dir statistics.
-struct Stats {
-    // Number of allocated Large objects.
-    larges: usize,
-    // Cumulated size of allocated Large objects.
-    larges_bytes: usize,
-    // Number of allocated Chunks.
-    chunks: usize,
-    // Distribution of chunks in their respective size-classes.
-    chunks_classes: [usize; MAX_CHUNK_SHIFT],
-    // Number of deallocated chunks.
-    chunks_dealloc: usize,
-    // Number of cached chunks.
-    cached: usize,
-    // Number of chunks reused from cache.
-    reused: usize,
-    // Number of allocated keys.
-    keys: usize,
-    // Number of modifications of memory protections for
-    // read, write, none.
-    prot_reads: usize,
-    prot_writes: usize,
-    prot_nones: usize
+/// Errors returned by fallible allocation functions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MallocError {
+    /// New pages could not be mapped.
+    OutOfMemory,
+    /// New pages could not be locked in memory, `RLIMIT_MEMLOCK` is
+    /// likely exhausted.
+    MlockLimit,
+    /// Requested alignment is not a power of two.
+    InvalidAlignment,
+    /// Pointer was already freed.
+    DoubleFree,
+    /// Pointer was not returned by this allocator.
+    UnknownPointer,
+    /// Allocator's metadata failed its integrity checks.
+    IntegrityViolation,
+    /// Requested size overflows.
+    Overflow,
+    /// Bytes were written past the end of the chunk slot at address `slot`
+    /// of size-class `size_class`, its canary is corrupted.
+    SlotOverflow {
+        slot: usize,
+        size_class: usize
+    },
+    /// The freed slot at `offset` in the chunk at address `chunk` of
+    /// size-class `size_class` was written after being freed.
+    WriteAfterFree {
+        chunk: usize,
+        offset: usize,
+        size_class: usize
+    },
+    /// Allocation would go over the thread's or the process' quota.
+    QuotaExceeded,
+    /// Changing the protections of pages or unmapping them failed with
+    /// the OS error `errno`.
+    SysError {
+        errno: i32
+    }
 }
 
-#[derive(Copy, Clone)]
-enum RegionType {
-    // Region is free, no object attached.
-    Free = 0,
-    // Region holds a chunk as object.
-    Chunk,
-    // Region holds a "large" (> max_chunk_size()) object.
-    Large,
-    // Region holds an empty chunk that is currently cached.
-    Cache
+impl Error for MallocError {
+    fn description(&self) -> &str {
+        match *self {
+            MallocError::OutOfMemory => "out of memory",
+            MallocError::MlockLimit => "mlock limit reached",
+            MallocError::InvalidAlignment => "invalid alignment",
+            MallocError::DoubleFree => "double free",
+            MallocError::UnknownPointer => "invalid pointer",
+            MallocError::IntegrityViolation => "integrity check failed",
+            MallocError::Overflow => "integer overflow",
+            MallocError::SlotOverflow { .. } => "chunk slot overflow",
+            MallocError::WriteAfterFree { .. } => "write after free",
+            MallocError::QuotaExceeded => "quota exceeded",
+            MallocError::SysError { .. } => "system call failed"
+        }
+    }
 }
 
+impl Display for MallocError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            MallocError::SlotOverflow { slot, size_class } =>
+                write!(f, "{} at {:#x} of size-class {}", self.description(),
+                       slot, size_class),
+            MallocError::WriteAfterFree { chunk, offset, size_class } =>
+                write!(f, "{} at {:#x}+{} of size-class {}",
+                       self.description(), chunk, offset, size_class),
+            MallocError::SysError { errno } =>
+                write!(f, "{} with errno {}", self.description(), errno),
+            _ => f.write_str(self.description())
+        }
+    }
+}
 
-// Region hold metadata informations and point to the allocated object
-// eventually returned by the allocator.
-struct Region {
-    // Pointer to the allocated object, i.e. either a chunk for small sizes
-    // under pagesize / 2, or a standalone mmap'ed area for larger ojects
-    // and keys.
-    object: *mut u8,
-    // Canary used for integrity checks.
-    canary: usize,
-    // Size-class of the chunk or full size of the mapped memory object for
-    // large objects.
-    size: usize,
-    // Region type.
-    kind: RegionType,
+impl From<MapError> for MallocError {
+    fn from(err: MapError) -> MallocError {
+        match err {
+            MapError::Lock(_) => MallocError::MlockLimit,
+            MapError::Overflow => MallocError::Overflow,
+            MapError::Map(_) => MallocError::OutOfMemory,
+            MapError::Sys(err) => MallocError::SysError {
+                errno: err.raw_os_error().unwrap_or(0)
+            }
+        }
+    }
+}
 
-    // Next fields are only relevant for chunks.
 
-    // Bits mapping for tracking free slots in chunks of a given size-class.
-    mapping: [u8; MAX_CHUNK_MAPPING],
-    // Next referenced chunk of the same size-class also with free slots; or
-    // next cached chunk when inserted in cache.
-    next: *mut u8,
-    // Previous referenced chunk of the same size-class also with free slots;
-    // or previous cached chunk when inserted in cache.
-    prev: *mut u8,
+/// Limits on the memory used by the allocator
+///
+/// Set for the current thread with `set_thread_quota` or for the whole
+/// process with `set_process_quota`. Live objects count for their size,
+/// or their size-class for chunk slots and medium objects, and mappings
+/// for their pages, guard pages included, empty chunks and freed mappings
+/// kept for reuse included. The allocator's metadata aren't counted.
+/// Allocations going over a quota fail with `MallocError::QuotaExceeded`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Quota {
+    /// Maximum cumulated size in bytes of live objects.
+    pub bytes: Option<usize>,
+    /// Maximum number of mapped pages.
+    pub pages: Option<usize>
 }
 
-// A bit countertuitive but it happens that regions are shallowly copied.
-impl Copy for Region {}
+impl Quota {
+    /// No limits.
+    pub fn unlimited() -> Quota {
+        Quota {
+            bytes: None,
+            pages: None
+        }
+    }
+}
 
-impl Clone for Region {
-    fn clone(&self) -> Region {
-        *self
+impl Default for Quota {
+    fn default() -> Quota {
+        Quota::unlimited()
     }
 }
 
+/// Memory used by the allocator
+///
+/// Returned by `thread_usage` for the current thread and by
+/// `process_usage` for all threads, along with the corresponding quota.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Usage {
+    /// Cumulated size in bytes of live objects.
+    pub bytes: usize,
+    /// Number of mapped pages.
+    pub pages: usize,
+    /// Current quota.
+    pub quota: Quota
+}
 
-impl Dir {
-    // Singleton initialization.
-    pub unsafe fn init() -> *mut Dir {
-        // Various checks at runtime.
-        assert!(mmap::page_size() > 1 && mmap::page_size().is_power_of_two());
-        assert!(MAX_CHUNK_MAPPING * 8 < mmap::page_size() &&
-                mmap::page_size() % (MAX_CHUNK_MAPPING * 8) == 0);
-        assert!(INITIAL_REGIONS.is_power_of_two());
+fn process_quota() -> Quota {
+    Quota {
+        bytes: QUOTA_BYTES.load(Ordering::SeqCst).checked_sub(1),
+        pages: QUOTA_PAGES.load(Ordering::SeqCst).checked_sub(1)
+    }
+}
 
-        // Need this check because MAX_CHUNK_SHIFT's value must be known at
-        // compile-time as it is used statically in Dir's struct.
-        assert!(max_chunk_shift() <= MAX_CHUNK_SHIFT);
 
-        let dir = dir_alloc() as *mut Dir;
-        (*dir).canary1 = utils::os_rng().gen();
-        (*dir).canary2 = (*dir).canary1 ^ dir as usize;
-        (*dir).total = INITIAL_REGIONS;
-        (*dir).free = INITIAL_REGIONS;
-        (*dir).regions = regions_alloc((*dir).total) as *mut Region;
-        if USE_STATS {
-            (*dir).stats = Some(Stats::new());
-        } else {
-            (*dir).stats = None;
-        }
+/// Allocator statistics
+///
+/// Snapshot returned by `stats` for the current thread or by `stats_all`
+/// for all threads. Counters of live objects go up and down, the others
+/// only increase.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of live large objects, keys included.
+    pub larges: usize,
+    /// Cumulated size in bytes of live large objects.
+    pub larges_bytes: usize,
+    /// Number of live keys.
+    pub keys: usize,
+    /// Number of live medium objects.
+    pub mediums: usize,
+    /// Bytes of live medium objects in each size-class, as pairs of
+    /// (size-class, bytes) ordered by size.
+    pub mediums_bytes: Vec<(usize, usize)>,
+    /// Number of mapped spans of medium objects.
+    pub spans: usize,
+    /// Number of live chunks slots.
+    pub chunks: usize,
+    /// Bytes of live chunks slots in each size-class, as pairs of
+    /// (size-class, bytes) ordered by size.
+    pub chunks_bytes: Vec<(usize, usize)>,
+    /// Number of currently cached empty chunks.
+    pub cached: usize,
+    /// Number of chunks reused from cache.
+    pub cache_hits: usize,
+    /// Number of chunks mapped while the cache was empty.
+    pub cache_misses: usize,
+    /// Number of currently cached mappings of freed large objects.
+    pub spares: usize,
+    /// Number of large objects reusing a cached mapping.
+    pub spare_hits: usize,
+    /// Number of changes of memory protections to read-only.
+    pub prot_reads: usize,
+    /// Number of changes of memory protections to write-only.
+    pub prot_writes: usize,
+    /// Number of changes of memory protections to no access.
+    pub prot_nones: usize,
+    /// Number of changes of the protections of the allocator's metadata.
+    pub metadata_prots: usize,
+    /// Number of bytes locked in memory, allocator's metadata included.
+    pub locked_bytes: usize
+}
 
-        dir
+impl Stats {
+    fn new() -> Stats {
+        Stats {
+            larges: 0,
+            larges_bytes: 0,
+            keys: 0,
+            mediums: 0,
+            mediums_bytes: medium_classes().iter()
+                .map(|&(_, size)| (size, 0)).collect(),
+            spans: 0,
+            chunks: 0,
+            chunks_bytes: size_classes().iter()
+                .map(|&(_, size)| (size, 0)).collect(),
+            cached: 0,
+            cache_hits: 0,
+            cache_misses: 0,
+            spares: 0,
+            spare_hits: 0,
+            prot_reads: 0,
+            prot_writes: 0,
+            prot_nones: 0,
+            metadata_prots: 0,
+            locked_bytes: 0
+        }
     }
 
-    // This method try to force deallocate all allocated structures
-    // belonging to this Dir. After calling this method, its corresponding
-    // instance is left in an undetermined state and must not be used
-    // anymore.
-    pub unsafe fn scavenge(&mut self) {
-        // Only proceed with deallocations if current state is sound.
-        if self.regions.is_null() || !self.check_integrity() {
-            return;
+    fn accumulate(&mut self, counters: &Counters) {
+        let load = |counter: &AtomicUsize| counter.load(Ordering::Relaxed);
+
+        self.larges += load(&counters.larges);
+        self.larges_bytes += load(&counters.larges_bytes);
+        self.keys += load(&counters.keys);
+        self.mediums += load(&counters.mediums);
+        self.spans += load(&counters.spans);
+        self.chunks += load(&counters.chunks);
+        for (pos, &(index, size)) in size_classes().iter().enumerate() {
+            if let Some(slots) = counters.classes.get(index) {
+                self.chunks_bytes[pos].1 += load(slots) * size;
+            }
         }
-
-        if self.total != self.free {
-            let canary_dir = self.canary2;
-
-            for i in 0_usize..self.total {
-                let region = self.region_at_index_mut(i);
-
-                // Do not do anything if region's integrity is broken.
-                if region.is_free() || !region.check_integrity(canary_dir) {
-                    continue;
-                }
-
-                region.dealloc_data(true);
+        for (pos, &(index, size)) in medium_classes().iter().enumerate() {
+            if let Some(slots) = counters.classes.get(CHUNK_CLASSES + index) {
+                self.mediums_bytes[pos].1 += load(slots) * size;
             }
         }
+        self.cached += load(&counters.cached);
+        self.cache_hits += load(&counters.cache_hits);
+        self.cache_misses += load(&counters.cache_misses);
+        self.spares += load(&counters.spares);
+        self.spare_hits += load(&counters.spare_hits);
+        self.prot_reads += load(&counters.prot_reads);
+        self.prot_writes += load(&counters.prot_writes);
+        self.prot_nones += load(&counters.prot_nones);
+        self.metadata_prots += load(&counters.metadata_prots);
+        self.locked_bytes += load(&counters.locked_bytes);
+    }
 
-        regions_dealloc(self.regions as *mut u8, self.total);
-        self.regions = ptr::null_mut();
+    /// Render statistics in the Prometheus text exposition format, metrics
+    /// names are prefixed with `tars_malloc_`.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let single = |value: usize| vec![(String::new(), value)];
+
+        write_metric(&mut out, "larges", "gauge",
+                     "Number of live large objects, keys included.",
+                     &single(self.larges));
+        write_metric(&mut out, "larges_bytes", "gauge",
+                     "Cumulated size in bytes of live large objects.",
+                     &single(self.larges_bytes));
+        write_metric(&mut out, "keys", "gauge",
+                     "Number of live keys.", &single(self.keys));
+        write_metric(&mut out, "mediums", "gauge",
+                     "Number of live medium objects.", &single(self.mediums));
+        let classes: Vec<(String, usize)> = self.mediums_bytes.iter()
+            .map(|&(size, bytes)| (format!("{{size_class=\"{}\"}}", size),
+                                   bytes))
+            .collect();
+        write_metric(&mut out, "mediums_bytes", "gauge",
+                     "Bytes of live medium objects per size-class.",
+                     &classes);
+        write_metric(&mut out, "spans", "gauge",
+                     "Number of mapped spans of medium objects.",
+                     &single(self.spans));
+        write_metric(&mut out, "chunks", "gauge",
+                     "Number of live chunks slots.", &single(self.chunks));
+        let classes: Vec<(String, usize)> = self.chunks_bytes.iter()
+            .map(|&(size, bytes)| (format!("{{size_class=\"{}\"}}", size),
+                                   bytes))
+            .collect();
+        write_metric(&mut out, "chunks_bytes", "gauge",
+                     "Bytes of live chunks slots per size-class.", &classes);
+        write_metric(&mut out, "cached_chunks", "gauge",
+                     "Number of cached empty chunks.", &single(self.cached));
+        write_metric(&mut out, "cache_hits_total", "counter",
+                     "Number of chunks reused from cache.",
+                     &single(self.cache_hits));
+        write_metric(&mut out, "cache_misses_total", "counter",
+                     "Number of chunks mapped while the cache was empty.",
+                     &single(self.cache_misses));
+        write_metric(&mut out, "spare_mappings", "gauge",
+                     "Number of cached mappings of freed large objects.",
+                     &single(self.spares));
+        write_metric(&mut out, "spare_hits_total", "counter",
+                     "Number of large objects reusing a cached mapping.",
+                     &single(self.spare_hits));
+        write_metric(&mut out, "protections_total", "counter",
+                     "Number of changes of memory protections.",
+                     &[("{prot=\"read\"}".to_string(), self.prot_reads),
+                       ("{prot=\"write\"}".to_string(), self.prot_writes),
+                       ("{prot=\"none\"}".to_string(), self.prot_nones)]);
+        write_metric(&mut out, "metadata_protections_total", "counter",
+                     "Number of changes of the protections of metadata.",
+                     &single(self.metadata_prots));
+        write_metric(&mut out, "locked_bytes", "gauge",
+                     "Number of bytes locked in memory.",
+                     &single(self.locked_bytes));
+        out
     }
+}
 
-    #[inline]
-    pub fn check_integrity(&self) -> bool {
-        self.canary2 == self.canary1 ^ (self as *const Dir as usize)
+/// Report of a heap consistency check
+///
+/// Returned by `check_heap`, the heap is sound if no error was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeapReport {
+    /// Number of used regions checked.
+    pub regions: usize,
+    /// Inconsistencies found.
+    pub errors: Vec<String>
+}
+
+impl HeapReport {
+    /// Return `true` if no inconsistency was found.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
     }
+}
 
-    #[inline]
-    fn regions_used(&self) -> usize {
-        self.total.checked_sub(self.free).unwrap()
+impl Display for HeapReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(write!(f, "heap check: {} regions, {} errors", self.regions,
+                    self.errors.len()));
+        for error in self.errors.iter() {
+            try!(write!(f, "\n- {}", error));
+        }
+        Ok(())
     }
+}
 
-    unsafe fn regions_realloc(&mut self, count: usize) {
-        let prev_total = self.total;
-        let prev_alloc = self.regions;
-        let mut prev_regions = self.regions;
-
-        self.total = count;
-        self.free = count;
-        self.regions = regions_alloc(count) as *mut Region;
+/// Kind of an allocated object
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AllocKind {
+    /// Slot of a chunk shared with other small objects.
+    Chunk,
+    /// Slot of a span shared with other medium objects, between guard
+    /// pages.
+    Medium,
+    /// Object in its own mapping.
+    Large,
+    /// Object in its own mapping whose protections may change.
+    Key
+}
 
-        // Move regions.
-        for _ in 0_usize..prev_total {
-            if !(*prev_regions).is_free() {
-                let new_index = self.region_pick((*prev_regions).object);
-                let new_region = self.region_at_index_mut(new_index) as
-                    *mut Region;
-                self.free = self.free.checked_sub(1).unwrap();
-                *new_region = *prev_regions;
-            }
-            prev_regions = prev_regions.offset(1);
-        }
+/// Live objects allocated from the same call site
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeakSite {
+    /// Symbolized backtrace of the allocation, innermost frame first. It
+    /// is empty on platforms where backtraces can't be captured.
+    pub backtrace: Vec<String>,
+    /// Address, size and kind of each live object ordered by address.
+    pub allocations: Vec<(usize, usize, AllocKind)>
+}
 
-        // Finally deallocate old regions.
-        regions_dealloc(prev_alloc as *mut u8, prev_total);
+impl LeakSite {
+    /// Cumulated size in bytes of its live objects.
+    pub fn bytes(&self) -> usize {
+        self.allocations.iter().map(|&(_, size, _)| size).sum()
     }
+}
 
-    fn regions_grow(&mut self) {
-        // Keep a sparse list of regions to minimize collisions on
-        // insertions.
-        if self.free.checked_mul(4).unwrap() >= self.total {
-            return;
-        }
+/// Report of the objects still allocated
+///
+/// Returned by `report_leaks`, sites are ordered by decreasing cumulated
+/// size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeakReport {
+    /// Allocation sites of the live objects.
+    pub sites: Vec<LeakSite>
+}
 
-        let count = self.total.checked_mul(2).unwrap();
-        unsafe {
-            self.regions_realloc(count)
-        }
+impl LeakReport {
+    /// Return `true` if no object is still allocated.
+    pub fn is_empty(&self) -> bool {
+        self.sites.is_empty()
     }
 
-    fn regions_shrink(&mut self) {
-        if self.total == INITIAL_REGIONS ||
-           self.regions_used().checked_mul(16).unwrap() >= self.total {
-            return;
-        }
+    /// Number of objects still allocated.
+    pub fn count(&self) -> usize {
+        self.sites.iter().map(|site| site.allocations.len()).sum()
+    }
 
-        let count = cmp::max(self.total.checked_div(2).unwrap(),
-                             INITIAL_REGIONS);
-        unsafe {
-            self.regions_realloc(count)
-        }
+    /// Cumulated size in bytes of the objects still allocated.
+    pub fn bytes(&self) -> usize {
+        self.sites.iter().map(|site| site.bytes()).sum()
     }
+}
 
-    #[inline]
-    fn region_at_index(&self, index: usize) -> &Region {
-        assert!(index < self.total);
-        unsafe {
-            &*self.regions.offset(index.to_isize().unwrap())
+impl Display for LeakReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        try!(write!(f, "{} bytes in {} objects from {} sites", self.bytes(),
+                    self.count(), self.sites.len()));
+        for site in self.sites.iter() {
+            try!(write!(f, "\n{} bytes in {} objects allocated at:",
+                        site.bytes(), site.allocations.len()));
+            for frame in site.backtrace.iter() {
+                try!(write!(f, "\n    {}", frame));
+            }
         }
+        Ok(())
     }
+}
 
-    #[inline]
-    fn region_at_index_mut(&mut self, index: usize) -> &mut Region {
-        assert!(index < self.total);
-        unsafe {
-            &mut *self.regions.offset(index.to_isize().unwrap())
+/// Kind of a mapped region
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Page of slots of small objects, or the static page of objects of
+    /// size zero.
+    Chunk,
+    /// Object in its own mapping.
+    Large,
+    /// Object in its own mapping whose protections may change.
+    Key,
+    /// Empty chunk kept for reuse.
+    Cache,
+    /// Freed large object or key in quarantine.
+    Tomb,
+    /// Wiped mapping of a freed large object or key kept for reuse.
+    Spare,
+    /// Slots of medium objects separated by guard pages.
+    Span
+}
+
+impl RegionKind {
+    /// Lowercase name of this kind, as rendered in JSON and SVG.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RegionKind::Chunk => "chunk",
+            RegionKind::Large => "large",
+            RegionKind::Key => "key",
+            RegionKind::Cache => "cache",
+            RegionKind::Tomb => "tomb",
+            RegionKind::Spare => "spare",
+            RegionKind::Span => "span"
         }
     }
+}
 
-    #[inline]
-    fn region_mask(&self) -> usize {
-        self.total - 1
-    }
+/// State of a mapped region
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionSnapshot {
+    /// Index of its entry in the regions table.
+    pub index: usize,
+    /// Kind of region.
+    pub kind: RegionKind,
+    /// Address of its first page, following its leading guard page.
+    pub address: usize,
+    /// Size-class of the slots of chunks and spans, size of large objects
+    /// and keys, 0 for cached chunks and the static chunk.
+    pub size: usize,
+    /// Number of mapped pages, guard pages included.
+    pub pages: usize,
+    /// Number of slots of chunks and spans, 0 for other regions.
+    pub slots: usize,
+    /// Number of slots in use.
+    pub used_slots: usize,
+    /// Occupancy of each slot in address order, `true` if it is in use.
+    pub occupancy: Vec<bool>,
+    /// Current protection of its object, or of the slots in use of chunks
+    /// and spans.
+    pub prot: Prot
+}
 
-    #[inline]
-    fn object_to_region_index(&self, object: *mut u8) -> usize {
-        let mut s = SipHasher::new_with_keys(0, 0);
-        (mmap::mask_pointer(object) as usize).hash(&mut s);
-        s.finish() as usize & self.region_mask()
+/// State of the heap of a thread
+///
+/// Returned by `snapshot`, regions are ordered by address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeapSnapshot {
+    /// Number of entries of the regions table, unused ones included.
+    pub total: usize,
+    /// Every region currently mapped.
+    pub regions: Vec<RegionSnapshot>
+}
 
-//        hash::hash::<_, SipHasher>(&(mmap::mask_pointer(object) as usize))
-  //          as usize & self.region_mask()
+impl HeapSnapshot {
+    /// Number of mapped pages, guard pages included.
+    pub fn pages(&self) -> usize {
+        self.regions.iter().map(|region| region.pages).sum()
     }
 
-    fn region_pick(&self, object: *mut u8) -> usize {
-        // Algorithm L Knuth volume 3, 6.4.
-        let mut index = self.object_to_region_index(object);
-        loop {
-            if self.region_at_index(index).is_free() {
-                break;
+    /// Render the snapshot as a JSON object with the fields of
+    /// `HeapSnapshot`, each region being an object with the fields of
+    /// `RegionSnapshot`. Kinds and protections are lowercase strings,
+    /// occupancy is a string of `0` (free) and `1` (used) per slot.
+    pub fn to_json(&self) -> String {
+        let mut out = format!("{{\"total\":{},\"regions\":[", self.total);
+        for (i, region) in self.regions.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
             }
-            index = index.wrapping_sub(1) & self.region_mask();
+            let prot = match region.prot {
+                Prot::None => "none",
+                Prot::Read => "read",
+                Prot::Write => "write",
+                Prot::ReadWrite => "read_write"
+            };
+            let occupancy: String = region.occupancy.iter()
+                .map(|&used| if used { '1' } else { '0' }).collect();
+            let _ = write!(out, "{{\"index\":{},\"kind\":\"{}\",\
+                                 \"address\":{},\"size\":{},\"pages\":{},\
+                                 \"slots\":{},\"used_slots\":{},\
+                                 \"occupancy\":\"{}\",\"prot\":\"{}\"}}",
+                           region.index, region.kind.name(), region.address,
+                           region.size, region.pages, region.slots,
+                           region.used_slots, occupancy, prot);
         }
-        index
+        out.push_str("]}");
+        out
     }
 
-    fn region_insert(&mut self, object: *mut u8, size: usize,
-                     chunk: bool) -> usize {
-        // Grow regions if needed.
-        self.regions_grow();
+    /// Render the snapshot as an SVG image drawing the regions table, then
+    /// each mapping with its guard pages and the occupancy of its slots.
+    pub fn to_svg(&self) -> String {
+        svg::render(self, mmap::page_size())
+    }
+}
 
-        let canary_dir = self.canary2;
-        let region_index = self.region_pick(object);
+/// Operation of a traced event
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceOp {
+    /// Object allocated.
+    Alloc,
+    /// Object resized, possibly moved.
+    Realloc,
+    /// Object about to be deallocated.
+    Free,
+    /// Protections of an object about to be changed.
+    Protect
+}
 
-        {
-            let region = self.region_at_index_mut(region_index);
-            region.init(object, size, chunk, canary_dir);
-        }
+/// Event recorded by tracing
+///
+/// Allocations and reallocations are recorded once they succeed,
+/// deallocations and protection changes as soon as they are requested.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// Operation.
+    pub op: TraceOp,
+    /// Address of the object, the resulting one for reallocations.
+    pub address: usize,
+    /// Size-class of the region holding the object, or the requested size
+    /// if it has no region.
+    pub size: usize,
+    /// Index of the region of the object in the regions table, `None` if
+    /// it is unknown to this thread.
+    pub region: Option<usize>,
+    /// Requested protection of protection changes.
+    pub prot: Option<Prot>
+}
 
-        self.free = self.free.checked_sub(1).unwrap();
-        region_index
+impl Display for TraceEvent {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let op = match self.op {
+            TraceOp::Alloc => "alloc",
+            TraceOp::Realloc => "realloc",
+            TraceOp::Free => "free",
+            TraceOp::Protect => "protect"
+        };
+        try!(write!(f, "{} {:#x} size {}", op, self.address, self.size));
+        match self.region {
+            Some(index) => try!(write!(f, " region {}", index)),
+            None => try!(write!(f, " no region"))
+        }
+        if let Some(prot) = self.prot {
+            try!(write!(f, " prot {:?}", prot));
+        }
+        Ok(())
     }
+}
 
-    fn region_find(&self, object: *mut u8) -> Option<usize> {
-        assert!(!object.is_null());
+// Record the allocation of `ptr` from directory `dir` if leaks are
+// tracked. Objects of size 0 all share the static chunk and aren't
+// tracked.
+fn track_alloc(ptr: *mut u8, dir: usize, size: usize, align: usize,
+               force_large: bool) {
+    if !options().track_leaks || size == 0 {
+        return;
+    }
 
-        let start = mmap::mask_pointer(object);
-        let mut index = self.object_to_region_index(object);
+    let kind = if force_large {
+        AllocKind::Key
+    } else if is_medium(size, align, force_large) {
+        AllocKind::Medium
+    } else if slot_size(size) > max_chunk_size() {
+        AllocKind::Large
+    } else {
+        AllocKind::Chunk
+    };
+    leaks::record(ptr as usize, dir, size, kind);
+}
 
-        loop {
-            let region = self.region_at_index(index);
-            if region.is_free() {
-                return None;
-            }
-            if mmap::mask_pointer(region.object) == start {
-                if !region.is_chunk() && region.object != object {
-                    return None;
-                } else {
-                    return Some(index);
-                }
-            }
-            index = index.wrapping_sub(1) & self.region_mask();
-        }
+// Append metric `name` of type `kind` to `out`, each value is preceded by
+// its labels.
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str,
+                values: &[(String, usize)]) {
+    let _ = write!(out, "# HELP tars_malloc_{} {}\n", name, help);
+    let _ = write!(out, "# TYPE tars_malloc_{} {}\n", name, kind);
+    for &(ref labels, value) in values.iter() {
+        let _ = write!(out, "tars_malloc_{}{} {}\n", name, labels, value);
     }
+}
 
-    fn region_delete(&mut self, index: usize) {
-        // Algorithm R Knuth volume 3, 6.4.
-        self.free += 1;
-        let mut i = index;
 
-        'a: loop {
-            self.region_at_index_mut(i).set_as_free();
-            let j = i;
+unsafe fn dir_alloc(random: usize) -> Result<*mut u8, MallocError> {
+    Ok(try!(mmap::allocate(mem::size_of::<Dir>(),
+                           mem::align_of::<Dir>(),
+                           None,
+                           Prot::ReadWrite,
+                           RangePos::Rand(random))))
+}
 
-            loop {
-                i = i.wrapping_sub(1) & self.region_mask();
-                let region = self.region_at_index(i);
-                if region.is_free() {
-                    break 'a;
-                }
-                let ri = self.object_to_region_index(region.object);
-	        if (i <= ri && ri < j) || (ri < j && j < i) ||
-                   (j < i && i <= ri) {
-		    continue;
-                }
-                unsafe {
-                    *self.regions.offset(j.to_isize().unwrap()) =
-                        *(region as *const Region);
-                }
-                break;
-            }
-        }
+unsafe fn dir_dealloc(ptr: *mut u8) -> Result<(), MallocError> {
+    Ok(try!(mmap::deallocate(ptr, mem::size_of::<Dir>(), Some(0))))
+}
 
-        // Shrink regions if needed.
-        self.regions_shrink();
-    }
+unsafe fn regions_alloc(count: usize) -> Result<*mut u8, MallocError> {
+    let size = try!(count.checked_mul(mem::size_of::<Region>())
+                    .ok_or(MallocError::Overflow));
+    Ok(try!(mmap::allocate(size,
+                           mem::align_of::<Region>(),
+                           None,
+                           Prot::ReadWrite,
+                           RangePos::Start)))
+}
 
-    unsafe fn list_insert(&mut self, start: &mut *mut u8, end: &mut *mut u8,
-                          region: &mut Region) {
-        if start.is_null() {
-            assert!(end.is_null());
-            region.next = ptr::null_mut();
-            *end = region.object;
-        } else {
-            assert!(!end.is_null());
-            region.next = *start;
-            let first_region = self.regions.offset(self.region_find(
-                *start).unwrap().to_isize().unwrap());
-            (*first_region).prev = region.object;
-        }
+unsafe fn regions_dealloc(ptr: *mut u8,
+                          count: usize) -> Result<(), MallocError> {
+    let size = try!(count.checked_mul(mem::size_of::<Region>())
+                    .ok_or(MallocError::Overflow));
+    Ok(try!(mmap::deallocate(ptr, size, Some(0))))
+}
 
-        region.prev = ptr::null_mut();
-        *start = region.object;
+// Open the metadata of `dir` for writing or close them when metadata are
+// protected.
+#[inline]
+unsafe fn metadata_protect(dir: *mut Dir,
+                           open: bool) -> Result<(), MallocError> {
+    if !options().protect_metadata {
+        return Ok(());
     }
+    metadata_prot(dir, open)
+}
 
-    unsafe fn list_remove(&mut self, start: &mut *mut u8, end: &mut *mut u8,
-                          region: &mut Region) {
-        let prev_region = if !region.prev.is_null() {
-            self.regions.offset(self.region_find(region.prev).unwrap()
-                                .to_isize().unwrap())
-        } else {
-            ptr::null_mut()
-        };
+// Open the metadata of `dir` for writing or close them. The Dir is opened
+// first and closed last as it locates the regions.
+unsafe fn metadata_prot(dir: *mut Dir, open: bool) -> Result<(), MallocError> {
+    if open {
+        try!(mmap::protect(dir as *mut u8, mem::size_of::<Dir>(),
+                           Prot::ReadWrite));
+    }
+    let prot = if open {
+        Prot::ReadWrite
+    } else {
+        Prot::None
+    };
+    try!(mmap::protect((*dir).regions as *mut u8,
+                       (*dir).total * mem::size_of::<Region>(), prot));
+    if let Some(counters) = (*dir).counters() {
+        stat_update(&counters.metadata_prots, 2, true);
+    }
+    if !open {
+        try!(mmap::protect(dir as *mut u8, mem::size_of::<Dir>(),
+                           Prot::None));
+    }
+    Ok(())
+}
 
-        let next_region = if !region.next.is_null() {
-            self.regions.offset(self.region_find(region.next).unwrap()
-                                .to_isize().unwrap())
-        } else {
-            ptr::null_mut()
-        };
 
-        if prev_region.is_null() {
-            if next_region.is_null() {
-                *start = ptr::null_mut();
-                *end = ptr::null_mut();
-            } else {
-                *start = (*next_region).object;
-                (*next_region).prev = ptr::null_mut();
-            }
-        } else {
-            if next_region.is_null() {
-                (*prev_region).next = ptr::null_mut();
-                *end = (*prev_region).object;
-            } else {
-                (*prev_region).next = (*next_region).object;
-                (*next_region).prev = (*prev_region).object;
-            }
+// Provide an interface to the pages directory.
+#[doc(hidden)]
+pub struct ThreadDir {
+    dir: Rc<RefCell<LocalDir>>
+}
+
+impl ThreadDir {
+    // Borrow the local directory with its metadata opened, a new Dir is
+    // initialized if it could not be allocated until now.
+    fn open(&self) -> Result<OpenDir, MallocError> {
+        let mut local = self.dir.borrow_mut();
+        if local.dir.is_null() {
+            local.dir = try!(unsafe { Dir::init() });
         }
+        try!(unsafe { metadata_protect(local.dir, true) });
+        Ok(OpenDir {
+            local: local
+        })
     }
 
-    unsafe fn free_chunk_insert(&mut self, region_index: usize) {
-        let dir: *mut Dir = mem::transmute(self);
+    // Return the opened local directory. Pending remote frees and orphaned
+    // directories are handled first.
+    fn local(&self) -> Result<OpenDir, MallocError> {
+        let mut local = try!(self.open());
+        unsafe {
+            local.sync_remote();
+        }
+        Ok(local)
+    }
 
-        let region = (*dir).regions.offset(region_index.to_isize().unwrap());
-        assert!((*region).is_chunk());
+    pub unsafe fn alloc(&mut self, size: usize, align: usize, zero_fill: bool,
+                        force_large: bool) -> Result<*mut u8, MallocError> {
+        let (ptr, id) = {
+            let mut local = try!(self.local());
+            (try!(local.alloc(size, align, zero_fill, force_large)), local.id)
+        };
+        track_alloc(ptr, id, size, align, force_large);
+        Ok(ptr)
+    }
 
-        let index = chunk_index((*region).size);
+    pub unsafe fn alloc_batch(&mut self, size: usize, count: usize,
+                              align: usize, zero_fill: bool)
+                              -> Result<Vec<*mut u8>, MallocError> {
+        let (ptrs, id) = {
+            let mut local = try!(self.local());
+            (try!(local.alloc_batch(size, count, align, zero_fill)), local.id)
+        };
+        for &ptr in ptrs.iter() {
+            track_alloc(ptr, id, size, align, false);
+        }
+        Ok(ptrs)
+    }
 
-        if (*region).size == 0 {
-            assert!((*dir).chunks1[index].is_null());
+    pub unsafe fn realloc(&mut self, ptr: *mut u8, size: usize, align: usize,
+                          zero_fill: bool, force_large: bool)
+                          -> Result<*mut u8, MallocError> {
+        let (nptr, id) = {
+            let mut local = try!(self.local());
+            (try!(local.realloc(ptr, size, align, zero_fill, force_large)),
+             local.id)
+        };
+        if options().track_leaks && !ptr.is_null() {
+            leaks::forget(ptr as usize);
         }
+        track_alloc(nptr, id, size, align, force_large);
+        Ok(nptr)
+    }
 
-        (*dir).list_insert(&mut (*dir).chunks1[index],
-                           &mut (*dir).chunks2[index], &mut *region);
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8) -> Result<(), MallocError> {
+        try!(try!(self.local()).dealloc(ptr));
+        if options().track_leaks && !ptr.is_null() {
+            leaks::forget(ptr as usize);
+        }
+        Ok(())
     }
 
-    unsafe fn free_chunk_remove(&mut self, region_index: usize) {
-        let dir: *mut Dir = mem::transmute(self);
+    pub unsafe fn protect(&mut self, ptr: *mut u8,
+                          prot: Prot) -> Result<(), MallocError> {
+        try!(self.local()).protect(ptr, prot)
+    }
 
-        let region = (*dir).regions.offset(region_index.to_isize().unwrap());
-        assert!((*region).is_chunk() && (*region).size != 0);
+    pub fn stats(&self) -> Option<Stats> {
+        self.local().ok().and_then(|local| local.stats_snapshot())
+    }
 
-        let index = chunk_index((*region).size);
-        (*dir).list_remove(&mut (*dir).chunks1[index],
-                           &mut (*dir).chunks2[index], &mut *region);
+    pub fn snapshot(&self) -> Option<HeapSnapshot> {
+        self.local().ok().map(|local| local.heap_snapshot())
     }
 
-    #[inline]
-    fn can_cache_chunk(&self) -> bool {
-        USE_CACHE && self.cache_len < MAX_CACHE_SIZE
+    pub fn trace_events(&self) -> Option<Vec<TraceEvent>> {
+        self.open().ok().map(|local| local.trace.events())
     }
 
-    #[inline]
-    fn has_cached_chunk(&self) -> bool {
-        USE_CACHE && self.cache_len > 0
+    pub fn usage(&self) -> Option<Usage> {
+        self.local().ok().map(|local| Usage {
+            bytes: local.used_bytes,
+            pages: local.used_pages,
+            quota: local.quota
+        })
     }
 
-    unsafe fn cache_chunk_insert(&mut self, region_index: usize) {
-        let dir: *mut Dir = mem::transmute(self);
+    pub fn set_quota(&self, quota: Quota) -> Result<(), MallocError> {
+        try!(self.local()).quota = quota;
+        Ok(())
+    }
 
-        let region = (*dir).regions.offset(region_index.to_isize().unwrap());
-        assert!((*region).is_chunk() && (*region).size != 0);
+    pub fn take_deferred_errors(&self) -> Vec<MallocError> {
+        match self.open() {
+            Ok(local) => registry::take_errors(local.id),
+            Err(_) => Vec::new()
+        }
+    }
 
-        (*dir).list_insert(&mut (*dir).cache1, &mut (*dir).cache2,
-                           &mut *region);
+    pub fn trim(&self) -> Result<usize, MallocError> {
+        let mut local = try!(self.open());
+        let used_pages = local.used_pages;
+        unsafe {
+            local.sync_remote();
+            try!(local.trim());
+        }
+        Ok(used_pages.saturating_sub(local.used_pages))
+    }
 
-        (*dir).cache_len += 1;
-        (*region).set_as_cache();
+    pub fn check_heap(&self) -> HeapReport {
+        match self.local() {
+            Ok(local) => local.check_heap(),
+            Err(err) => HeapReport {
+                regions: 0,
+                errors: vec![format!("directory unavailable: {}", err)]
+            }
+        }
     }
+}
 
-    unsafe fn cache_chunk_take(&mut self,
-                               chunk_size: usize) -> (usize, *mut u8) {
-        let dir: *mut Dir = mem::transmute(self);
+impl Debug for ThreadDir {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.dir.borrow().dir.is_null() {
+            return write!(f, "Uninitialized directory\n");
+        }
+        match self.open() {
+            Ok(local) => local.fmt(f),
+            Err(err) => write!(f, "Unavailable directory: {}\n", err)
+        }
+    }
+}
 
-        let chunk = if utils::rng().gen_range(0_usize, 2_usize) == 1 {
-            (*dir).cache1
-        } else {
-            (*dir).cache2
-        };
-        assert!(!chunk.is_null());
 
-        let region_index = (*dir).region_find(chunk).unwrap();
-        let region = (*dir).regions.offset(region_index.to_isize().unwrap());
-        assert!((*region).check_integrity((*dir).canary2));
+// Local directory borrowed with its metadata opened, they are closed again
+// when it is released.
+struct OpenDir<'a> {
+    local: RefMut<'a, LocalDir>
+}
 
-        (*dir).list_remove(&mut (*dir).cache1, &mut (*dir).cache2,
-                           &mut *region);
+impl<'a> Drop for OpenDir<'a> {
+    fn drop(&mut self) {
+        if !self.local.dir.is_null() {
+            // Panics raised while the Dir is opened bypass `fail`, its
+            // traced events are dumped here as it can't be opened again.
+            if options().trace && thread::panicking() {
+                trace::dump(&self.local.trace.events());
+            }
+            let _ = unsafe { metadata_protect(self.local.dir, false) };
+        }
+    }
+}
 
-        (*dir).cache_len -= 1;
-        (*region).set_as_chunk(chunk_size);
+impl<'a> Deref for OpenDir<'a> {
+    type Target = LocalDir;
 
-        (region_index, chunk)
+    fn deref(&self) -> &LocalDir {
+        &self.local
     }
+}
 
-    #[inline]
-    fn has_free_chunk(&self, chunk_size: usize) -> bool {
-        !self.chunks1[chunk_index(chunk_size)].is_null()
+impl<'a> DerefMut for OpenDir<'a> {
+    fn deref_mut(&mut self) -> &mut LocalDir {
+        &mut self.local
     }
+}
 
-    unsafe fn create_chunk(&mut self, chunk_size: usize) {
-        let (region_index, chunk) = if self.has_cached_chunk() {
-            if USE_STATS {
-                self.stats.as_mut().unwrap().reused += 1;
-            }
-            self.cache_chunk_take(chunk_size)
-        } else {
-            let chunk = mmap::allocate(mmap::page_size(),
-                                       0,
-                                       fill_byte_alloc(false),
-                                       Prot::ReadWrite,
-                                       RangePos::Start);
-            let region_index = self.region_insert(chunk, chunk_size, true);
-            (region_index, chunk)
-        };
-
-        self.free_chunk_insert(region_index);
 
-        // A static chunk is used for allocations of size 0 and is pointing
-        // to a non-readable-writable memory area.
-        if chunk_size == 0 {
-            mmap::protect(chunk, mmap::page_size(), Prot::None);
-        }
-    }
-
-    unsafe fn take_chunk_slot(&mut self, chunk_size: usize, real_size: usize,
-                              zero_fill: bool) -> *mut u8 {
-        debug_assert!(self.has_free_chunk(chunk_size) &&
-                      chunk_size >= real_size);
-
-        let index = chunk_index(chunk_size);
-        // Either take the first chunk or the last one.
-        let chunk = if utils::rng().gen_range(0_usize, 2_usize) == 1 {
-            self.chunks1[index]
-        } else {
-            self.chunks2[index]
-        };
-        assert!(!chunk.is_null());
-
-        if index == 0 {
-            return chunk;
-        }
+// Structure used to manage a Dir and owned by a thread.
+struct LocalDir {
+    dir: *mut Dir
+}
 
-        if USE_STATS {
-            self.stats.as_mut().unwrap().chunks_classes[index] += 1;
-        }
+impl Drop for LocalDir {
+    fn drop(&mut self) {
+        unsafe {
+            // Do not do anything if structure's integrity is broken.
+            if self.dir.is_null() ||
+               metadata_protect(self.dir, true).is_err() ||
+               !(*self.dir).check_integrity() {
+                return;
+            }
 
-        let region_index = self.region_find(chunk).unwrap();
+            (*self.dir).drain_remote();
+            let _ = (*self.dir).flush_quarantine();
 
-        let (slot_index, chunk_now_full) = {
-            let canary_dir = self.canary2;
-            let region = self.region_at_index_mut(region_index);
-            assert!(region.check_integrity(canary_dir));
+            if options().track_leaks {
+                leaks::report_exit((*self.dir).id);
+            }
 
-            let slot_index = region.take_chunk_slot();
-            (slot_index, region.is_full_chunk())
-        };
+            // Live objects may still be used by other threads, let another
+            // thread adopt them.
+            if (*self.dir).has_live_objects() {
+                let id = (*self.dir).id;
+                let _ = metadata_protect(self.dir, false);
+                registry::orphan(id, self.dir as usize);
+                self.dir = ptr::null_mut();
+                return;
+            }
 
-        if chunk_now_full {
-            self.free_chunk_remove(region_index);
+            // Force dealloc regions metadata and objects to clean-up the heap.
+            registry::detach((*self.dir).id);
+            PROCESS_BYTES.fetch_sub((*self.dir).used_bytes, Ordering::SeqCst);
+            PROCESS_PAGES.fetch_sub((*self.dir).used_pages, Ordering::SeqCst);
+            (*self.dir).scavenge();
+            // Dealloc Dir.
+            let _ = dir_dealloc(self.dir as *mut u8);
+            self.dir = ptr::null_mut();
         }
+    }
+}
 
-        let slot = chunk.offset((slot_index * chunk_size) as isize);
+impl Deref for LocalDir {
+    type Target = Dir;
 
-        if let Some(fill_byte) = fill_byte_alloc(zero_fill) {
-            ptr::write_bytes(slot, fill_byte, chunk_size);
+    fn deref(&self) -> &Dir {
+        unsafe {
+            &*self.dir
         }
-
-        slot
     }
+}
 
-    pub unsafe fn alloc(&mut self, size: usize, zero_fill: bool,
-                        force_large: bool) -> *mut u8 {
-        assert!(self.check_integrity());
-
-        if force_large || size > max_chunk_size() {
-            if USE_STATS {
-                self.stats.as_mut().unwrap().larges += 1;
-                self.stats.as_mut().unwrap().larges_bytes += size;
-            }
-
-            let (prot, pos) = if force_large {
-                if USE_STATS {
-                    self.stats.as_mut().unwrap().keys += 1;
-                }
-                (Prot::Write, RangePos::End)
-            } else {
-                (Prot::ReadWrite, RangePos::Start)
-            };
-
-            let object = mmap::allocate(size, 0, fill_byte_alloc(zero_fill),
-                                        prot, pos);
-            self.region_insert(object, size, false);
-            object as *mut u8
-        } else {
-            if USE_STATS {
-                self.stats.as_mut().unwrap().chunks += 1;
-            }
+impl DerefMut for LocalDir {
+    fn deref_mut(&mut self) -> &mut Dir {
+        unsafe {
+            &mut *self.dir
+        }
+    }
+}
 
-            let chunk_size = chunk_size(size);
 
-            if !self.has_free_chunk(chunk_size) {
-                self.create_chunk(chunk_size);
-            }
+#[doc(hidden)]
+pub fn thread_dir() -> ThreadDir {
+    thread_local!(static THREAD_DIR_KEY: Rc<RefCell<LocalDir>> = {
+        // On failure the Dir's initialization is retried on next use.
+        let dir = LocalDir {
+            dir: unsafe { Dir::init().unwrap_or(ptr::null_mut()) }
+        };
+        Rc::new(RefCell::new(dir))
+    });
 
-            self.take_chunk_slot(chunk_size, size, zero_fill)
-        }
+    ThreadDir {
+        dir: THREAD_DIR_KEY.with(|dir| dir.clone())
     }
+}
 
-    pub unsafe fn realloc(&mut self, ptr: *mut u8, size: usize, zero_fill: bool,
-                          force_large: bool) -> *mut u8 {
-        assert!(self.check_integrity());
-
-        let nptr = self.alloc(size, zero_fill, force_large);
-        assert!(!nptr.is_null());
 
-        if ptr.is_null() {
-            return nptr;
-        }
+// Central pages directory.
+struct Dir {
+    // Canary used for integrity checks.
+    canary1: usize,
+    // Pointer to the current pool of allocated regions.
+    regions: *mut Region,
+    // Total number of regions allocated in the pool.
+    total: usize,
+    // Number of unused regions.
+    free: usize,
+    // Keys of the hash locating regions from their object.
+    hash_keys: (u64, u64),
+    // Keys of the canaries of chunk slots.
+    canary_keys: (u64, u64),
+    // Random source of the placement of objects and of the choice of
+    // chunks, slots and evicted entries.
+    rng: PoolRng,
+    // Lists are doubly linked through the indexes of their regions, ended
+    // by NO_REGION, and fixed up whenever regions are moved.
+    // Index of the cache list's first region.
+    cache1: usize,
+    // Cache list's back index.
+    cache2: usize,
+    // Current number of cached free chunks.
+    cache_len: usize,
+    // Lists of chunks with free slots indexed by `chunk_index` of their
+    // size-class (except for i=0 used to handle allocations of size 0 and
+    // also for the indexes which might remain unused depending on the
+    // pagesize and the value of MAX_CHUNK_MAPPING).
+    chunks1: [usize; CHUNK_CLASSES],
+    // Last chunks of their respective chunk lists inserted in chunks1.
+    chunks2: [usize; CHUNK_CLASSES],
+    // Lists of spans with free slots where index i represents spans of
+    // medium objects of 2^i pages.
+    mediums1: [usize; MEDIUM_CLASSES],
+    // Last spans of their respective span lists inserted in mediums1.
+    mediums2: [usize; MEDIUM_CLASSES],
+    // Freed objects not yet deallocated, null entries are unused.
+    quarantine: [*mut u8; MAX_QUARANTINE],
+    // First pages of the spare mappings, null entries are unused.
+    spares: [*mut u8; MAX_SPARES],
+    // Number of pages of the spare mappings.
+    spare_pages: usize,
+    // Canary.
+    canary2: usize,
+    // Identifier in the registry of directories.
+    id: usize,
+    // Pending remote frees counter and statistics counters, owned by the
+    // registry.
+    shared: *const Shared,
+    // Collect statistics.
+    stats: bool,
+    // Memory used and quota, see `Quota`.
+    used_bytes: usize,
+    used_pages: usize,
+    quota: Quota,
+    // Value of TRIM_EPOCH when the caches were last trimmed.
+    trim_epoch: usize,
+    // Last events, only recorded if tracing is enabled.
+    trace: Ring
+}
 
-        let prev_size = {
-            let region_index = self.region_find(ptr).unwrap();
-            let region = self.region_at_index(region_index);
+#[derive(Copy, Clone)]
+enum RegionType {
+    // Region is free, no object attached.
+    Free = 0,
+    // Region holds a chunk as object.
+    Chunk,
+    // Region holds a "large" (> max_chunk_size()) object.
+    Large,
+    // Region holds a key, a large object whose protections may change.
+    Key,
+    // Region holds an empty chunk that is currently cached.
+    Cache,
+    // Region holds a freed large object or key in quarantine, mapped as an
+    // inaccessible tombstone.
+    Tomb,
+    // Region holds the wiped and inaccessible mapping of a freed large
+    // object or key, kept for reuse.
+    Spare,
+    // Region holds a span of medium objects slots separated by guard pages,
+    // free slots are inaccessible.
+    Span
+}
 
-            assert!(region.check_integrity(self.canary2));
 
-            region.size
-        };
+// Region hold metadata informations and point to the allocated object
+// eventually returned by the allocator.
+struct Region {
+    // Pointer to the allocated object, i.e. either a chunk for small sizes
+    // under pagesize / 2, a span for medium sizes up to a few pages, or a
+    // standalone mmap'ed area for larger ojects and keys.
+    object: *mut u8,
+    // Canary used for integrity checks.
+    canary: usize,
+    // Size-class of the chunk or of the span's slots, or full size of the
+    // mapped memory object for large objects.
+    size: usize,
+    // Region type.
+    kind: RegionType,
+    // Current memory protections of large objects and keys.
+    prot: Prot,
+
+    // Next fields are only relevant for chunks and spans.
+
+    // Bits mapping for tracking free slots in chunks and spans of a given
+    // size-class, bit i % WORD_BITS of word i / WORD_BITS is set if slot i
+    // is free.
+    mapping: [usize; MAPPING_WORDS],
+    // Number of free slots, i.e. of bits set in mapping.
+    free_slots: usize,
+    // Index of the next referenced chunk or span of the same size-class
+    // also with free slots; or of the next cached chunk when inserted in
+    // cache. NO_REGION if last or not listed.
+    next: usize,
+    // Index of the previous referenced chunk or span of the same
+    // size-class also with free slots; or of the previous cached chunk
+    // when inserted in cache. NO_REGION if first or not listed.
+    prev: usize,
+}
 
-        ptr::copy_nonoverlapping(ptr as *const u8, nptr,
-                                 cmp::min(size, prev_size));
+// A bit countertuitive but it happens that regions are shallowly copied.
+impl Copy for Region {}
 
-        self.dealloc(ptr);
-        nptr
+impl Clone for Region {
+    fn clone(&self) -> Region {
+        *self
     }
+}
 
-    unsafe fn free_chunk_slot(&mut self, region_index: usize, offset: usize) {
-        let (chunk_was_full, chunk_is_empty) = {
-            let region = self.region_at_index_mut(region_index);
-            assert!(region.is_chunk() && region.size != 0);
-
-            let was_full = region.is_full_chunk();
 
-            region.free_chunk_slot(offset);
+impl Dir {
+    // Singleton initialization.
+    pub unsafe fn init() -> Result<*mut Dir, MallocError> {
+        // Various checks at runtime.
+        assert!(mmap::page_size() > 1 && mmap::page_size().is_power_of_two());
+        assert!(MAX_CHUNK_MAPPING * 8 < mmap::page_size() &&
+                mmap::page_size() % (MAX_CHUNK_MAPPING * 8) == 0 &&
+                MAPPING_WORDS * WORD_BITS == MAX_CHUNK_MAPPING * 8);
+        assert!(INITIAL_REGIONS.is_power_of_two());
 
-            let is_empty = region.is_empty_chunk();
-            (was_full, is_empty)
+        // Need this check because MAX_CHUNK_SHIFT's value must be known at
+        // compile-time as it is used statically in Dir's struct.
+        assert!(chunk_index(max_chunk_size()) < CHUNK_CLASSES &&
+                CHUNK_SUBCLASSES.is_power_of_two());
+        assert!(MAX_MEDIUM_PAGES == 1 << (MEDIUM_CLASSES - 1) &&
+                SPAN_PAGES.is_power_of_two() &&
+                span_slots(max_medium_size()) > 1);
+
+        // Placed before its generator exists, which is built in place.
+        let dir = try!(dir_alloc(utils::os_rng().gen())) as *mut Dir;
+        PoolRng::init_for_dir(&mut (*dir).rng);
+        (*dir).canary1 = (*dir).rng.gen();
+        (*dir).canary2 = (*dir).canary1 ^ dir as usize;
+        (*dir).hash_keys = (*dir).rng.gen();
+        (*dir).canary_keys = (*dir).rng.gen();
+        (*dir).cache1 = NO_REGION;
+        (*dir).cache2 = NO_REGION;
+        (*dir).chunks1 = [NO_REGION; CHUNK_CLASSES];
+        (*dir).chunks2 = [NO_REGION; CHUNK_CLASSES];
+        (*dir).mediums1 = [NO_REGION; MEDIUM_CLASSES];
+        (*dir).mediums2 = [NO_REGION; MEDIUM_CLASSES];
+        (*dir).total = INITIAL_REGIONS;
+        (*dir).free = INITIAL_REGIONS;
+        (*dir).regions = match regions_alloc((*dir).total) {
+            Ok(regions) => regions as *mut Region,
+            Err(err) => {
+                let _ = dir_dealloc(dir as *mut u8);
+                return Err(err);
+            }
         };
-
-        if chunk_was_full {
-            self.free_chunk_insert(region_index);
-        }
-
-        if chunk_is_empty {
-            self.free_chunk_remove(region_index);
-        }
+        let (id, shared) = registry::attach(CHUNK_CLASSES + MEDIUM_CLASSES);
+        (*dir).id = id;
+        (*dir).shared = shared;
+        (*dir).stats = options().stats;
+        (*dir).quota = Quota::unlimited();
+        (*dir).trim_epoch = TRIM_EPOCH.load(Ordering::SeqCst);
+        (*dir).trace = Ring::new();
+        (*dir).stats_mapped(mem::size_of::<Dir>(), true);
+        (*dir).stats_mapped(INITIAL_REGIONS * mem::size_of::<Region>(), true);
+
+        // Metadata are only opened while in use, they are simply left
+        // writable on failure.
+        let _ = metadata_protect(dir, false);
+        Ok(dir)
     }
 
-    pub unsafe fn dealloc(&mut self, ptr: *mut u8) {
-        if ptr.is_null() {
+    // This method try to force deallocate all allocated structures
+    // belonging to this Dir. After calling this method, its corresponding
+    // instance is left in an undetermined state and must not be used
+    // anymore.
+    pub unsafe fn scavenge(&mut self) {
+        // Only proceed with deallocations if current state is sound.
+        if self.regions.is_null() || !self.check_integrity() {
             return;
         }
 
-        assert!(self.check_integrity());
-
-        // Potentially signals a double free in case a region is not found.
-        let region_index = self.region_find(ptr).unwrap();
+        if self.total != self.free {
+            let canary_dir = self.canary2;
 
-        let region = &mut *self.regions.offset(region_index.to_isize().unwrap());
-        assert!(region.check_integrity(self.canary2));
+            for i in 0_usize..self.total {
+                let region = self.region_at_index_mut(i);
 
-        match region.kind {
-            RegionType::Chunk => {
-                if USE_STATS {
-                    self.stats.as_mut().unwrap().chunks_dealloc += 1;
+                // Do not do anything if region's integrity is broken.
+                if region.is_free() || !region.check_integrity(canary_dir) {
+                    continue;
                 }
 
-                // Kind of static chunk, never deallocated.
-                if region.size == 0 {
-                    return;
-                }
+                let _ = region.dealloc_data(true);
+            }
+        }
 
-                let chunk_offset = (ptr as usize).checked_sub(
-                    region.object as usize).unwrap();
+        let _ = regions_dealloc(self.regions as *mut u8, self.total);
+        self.regions = ptr::null_mut();
+    }
 
-                // Free chunk slot.
-                self.free_chunk_slot(region_index, chunk_offset);
+    #[inline]
+    pub fn check_integrity(&self) -> bool {
+        self.canary2 == self.canary1 ^ (self as *const Dir as usize)
+    }
 
-                if region.is_empty_chunk() {
-                    if self.can_cache_chunk() {
-                        // Cache region and its chunk object.
-                        self.cache_chunk_insert(region_index);
-                        if USE_STATS {
-                            self.stats.as_mut().unwrap().cached += 1;
-                        }
-                    } else {
-                        // Delete object and regions's metadata.
-                        region.dealloc_data(false);
-                        self.region_delete(region_index);
-                    }
-                }
-            },
-            RegionType::Large => {
-                assert_eq!(region.object, ptr);
-                region.dealloc_data(false);
-                self.region_delete(region_index);
-            },
-            _ => unreachable!()
-        }
+    // Return `true` if some objects could still be used by their owner,
+    // empty cached chunks and the static chunk don't count.
+    fn has_live_objects(&self) -> bool {
+        (0_usize..self.total).any(|i| {
+            let region = self.region_at_index(i);
+            match region.kind {
+                RegionType::Chunk => region.size != 0,
+                // Empty spans are unmapped.
+                RegionType::Large | RegionType::Key | RegionType::Span => true,
+                _ => false
+            }
+        })
     }
 
-    pub unsafe fn protect(&mut self, ptr: *mut u8, prot: Prot) {
-        if ptr.is_null() {
+    // Deallocate pointers queued by other threads. Errors are reported to
+    // the threads which freed them.
+    unsafe fn drain_remote(&mut self) {
+        if (*self.shared).pending.load(Ordering::SeqCst) == 0 {
             return;
         }
 
-        assert!(self.check_integrity());
-
-        if USE_STATS {
-            match prot {
-                Prot::Read => self.stats.as_mut().unwrap().prot_reads += 1,
-                Prot::Write => self.stats.as_mut().unwrap().prot_writes +=1,
-                Prot::None => self.stats.as_mut().unwrap().prot_nones += 1,
-                _ => ()
+        for pending in registry::take_pending(self.id) {
+            if let Err(err) = self.free_object(pending.ptr as *mut u8,
+                                               pending.wiped) {
+                registry::report(pending.freer, err);
             }
         }
-
-
-        let canary_dir = self.canary2;
-        let region_index = self.region_find(ptr).unwrap();
-        let region = self.region_at_index_mut(region_index);
-
-        assert!(region.check_integrity(canary_dir));
-        assert_eq!(region.kind as usize, RegionType::Large as usize);
-
-        assert_eq!(region.object, ptr);
-        mmap::protect(region.object, region.size, prot);
     }
-}
 
-impl Debug for Dir {
-    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
-        try!(write!(fmt, "Current state:\n"));
+    // Adopt orphaned directories then deallocate pending remote frees. The
+    // caches are trimmed if requested by `trim_all` since then. Errors are
+    // not the current call's, they are deferred to `take_deferred_errors`
+    // and orphans which can't be adopted yet are left for a later call.
+    pub unsafe fn sync_remote(&mut self) {
+        while let Some((orphan_id, orphan)) = registry::take_orphan() {
+            if let Err(err) = self.adopt(orphan_id, orphan as *mut Dir) {
+                registry::report(self.id, err);
+                break;
+            }
+        }
+        self.drain_remote();
+        if self.trim_epoch != TRIM_EPOCH.load(Ordering::SeqCst) {
+            if let Err(err) = self.trim() {
+                registry::report(self.id, err);
+            }
+        }
+    }
 
-        assert!(!self.regions.is_null());
-        let mut num_free = 0_usize;
-        let mut num_chunk = 0_usize;
-        let mut num_large = 0_usize;
-        let mut num_cache = 0_usize;
-        for i in 0_usize..self.total {
-            let region = self.region_at_index(i);
+    // Take ownership of the live objects of `orphan` and release it. On
+    // error `orphan` is handed over again with its remaining objects, or
+    // closed and quarantined if it is corrupted.
+    unsafe fn adopt(&mut self, orphan_id: usize,
+                    orphan: *mut Dir) -> Result<(), MallocError> {
+        if let Err(err) = metadata_protect(orphan, true) {
+            registry::orphan(orphan_id, orphan as usize);
+            return Err(err);
+        }
 
-            match region.kind {
-                RegionType::Free => num_free += 1,
-                RegionType::Chunk => num_chunk += 1,
-                RegionType::Large => num_large += 1,
-                RegionType::Cache => num_cache += 1
-            }
+        // Its objects can't be trusted, leave them as they are.
+        if !(*orphan).check_integrity() {
+            let _ = metadata_protect(orphan, false);
+            registry::quarantine(orphan_id);
+            return Err(MallocError::IntegrityViolation);
         }
-        try!(write!(fmt, "regions:\n"));
-        try!(write!(fmt, "total:         {}\n", self.total));
-        try!(write!(fmt, "free:          {}\n",  num_free));
-        try!(write!(fmt, "chunks:        {}\n", num_chunk));
-        try!(write!(fmt, "large objects: {}\n", num_large));
-        try!(write!(fmt, "cached chunks: {}\n", num_cache));
 
-        try!(write!(fmt, "chunks:\n"));
-        for i in iter::range_inclusive(0_usize, max_chunk_shift()) {
-            let size = 1_usize << i;
+        let canary_orphan = (*orphan).canary2;
+        for i in 0_usize..(*orphan).total {
+            let region = (*orphan).region_at_index_mut(i) as *mut Region;
 
-            if i != 0 && size < min_chunk_size() {
+            if (*region).is_free() {
                 continue;
             }
+            if !(*region).check_integrity(canary_orphan) {
+                let _ = metadata_protect(orphan, false);
+                registry::quarantine(orphan_id);
+                return Err(MallocError::IntegrityViolation);
+            }
 
-            if self.chunks1[i].is_null() {
-                try!(write!(fmt, "chunk size: {:<5} -> empty\n", size));
-            } else {
-                let mut l: usize = 0;
-                let mut object: *mut u8 = self.chunks1[i];
-                loop {
-                    l += 1;
-                    let region = self.region_at_index(
-                        self.region_find(object).unwrap());
-                    if !region.next.is_null() {
-                        object = region.next;
-                    } else {
-                        break;
+            match (*region).kind {
+                RegionType::Cache | RegionType::Tomb | RegionType::Spare => {
+                    let pages = (*region).mapped_pages();
+                    if (*region).dealloc_data(false).is_ok() {
+                        PROCESS_PAGES.fetch_sub(pages, Ordering::SeqCst);
                     }
+                },
+                _ => {
+                    let keys = (*orphan).canary_keys;
+                    if let Err(err) = self.region_adopt(&*region, keys) {
+                        let _ = metadata_protect(orphan, false);
+                        registry::orphan(orphan_id, orphan as usize);
+                        return Err(err);
+                    }
+                    (*region).set_as_free();
                 }
-                try!(write!(fmt, "chunk size: {:<5} -> free chunks: {}\n",
-                            size, l));
             }
+            (*orphan).free += 1;
         }
 
-        if USE_STATS {
-            try!(write!(fmt, "\n{:?}", self.stats.as_ref().unwrap()));
+        registry::adopt(orphan_id, self.id);
+        if options().track_leaks {
+            leaks::adopt(orphan_id, self.id);
         }
-
+        let _ = regions_dealloc((*orphan).regions as *mut u8,
+                                (*orphan).total);
+        let _ = dir_dealloc(orphan as *mut u8);
         Ok(())
     }
-}
 
+    // Insert a copy of `orphan`, a region from another directory whose
+    // slots canaries are keyed by `orphan_keys`, its object is now owned by
+    // this directory.
+    fn region_adopt(&mut self, orphan: &Region,
+                    orphan_keys: (u64, u64)) -> Result<usize, MallocError> {
+        let chunk = orphan.is_chunk();
+        let index = try!(self.region_insert(orphan.object, orphan.size,
+                                            orphan.kind));
+        self.region_at_index_mut(index).prot = orphan.prot;
+
+        // Already counted in the process' usage.
+        self.used_pages += orphan.mapped_pages();
+        match orphan.kind {
+            RegionType::Chunk => {
+                self.stats_mapped(mmap::page_size(), true);
+                if orphan.size != 0 {
+                    self.stats_slots(orphan.size, orphan.used_slots(), true);
+                    self.used_bytes += orphan.size * orphan.used_slots();
+                }
+            },
+            RegionType::Large => self.stats_large(orphan.size, false, true),
+            RegionType::Key => self.stats_large(orphan.size, true, true),
+            RegionType::Span => {
+                self.stats_span(true);
+                self.stats_mediums(orphan.size, orphan.used_slots(), true);
+            },
+            _ => ()
+        }
+        if orphan.is_span() {
+            self.used_bytes += orphan.size * orphan.used_slots();
+        } else if !chunk {
+            self.used_bytes += orphan.size;
+        }
 
-impl Stats {
-    fn new() -> Stats {
-        Stats {
-            larges: 0,
-            larges_bytes: 0,
-            chunks: 0,
-            chunks_classes: [0; MAX_CHUNK_SHIFT],
-            chunks_dealloc: 0,
-            cached: 0,
-            reused: 0,
-            keys: 0,
-            prot_reads: 0,
-            prot_writes: 0,
-            prot_nones: 0
+        if chunk && orphan.size != 0 && options().canaries {
+            for i in 0_usize..max_slot_index(orphan.size) {
+                if !orphan.chunk_slot_is_free(i) {
+                    unsafe {
+                        let slot = orphan.object.offset(
+                            (i * orphan.size) as isize);
+                        self.rekey_canary(slot, orphan.size, orphan_keys);
+                    }
+                }
+            }
         }
-    }
-}
 
-impl Debug for Stats {
-    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
-        try!(write!(fmt, "Stats:\n"));
-        try!(write!(fmt, "larges:       {}\n", self.larges));
-        try!(write!(fmt, "larges_bytes: {}\n", self.larges_bytes));
-        try!(write!(fmt, "chunks:       {}\n", self.chunks));
-        try!(write!(fmt, "chunks sizes:\n"));
-        for i in iter::range_inclusive(0_usize, max_chunk_shift()) {
-            let size = 1_usize << i;
-            if i != 0 && size < min_chunk_size() {
-                continue;
+        if (chunk && orphan.size != 0) || orphan.is_span() {
+            // Keep its slots, the static chunk is never put in a list.
+            let has_free_slot = {
+                let region = self.region_at_index_mut(index);
+                region.mapping = orphan.mapping;
+                region.free_slots = orphan.free_slots;
+                !region.is_full_chunk()
+            };
+
+            if has_free_slot && chunk {
+                try!(unsafe { self.free_chunk_insert(index) });
+            } else if has_free_slot {
+                try!(unsafe { self.free_span_insert(index) });
             }
-            try!(write!(fmt, " {:5} bytes: {}\n", size,
-                        self.chunks_classes[i]));
         }
-        try!(write!(fmt, "chks_dealloc: {}\n", self.chunks_dealloc));
-        try!(write!(fmt, "cached:       {}\n", self.cached));
-        try!(write!(fmt, "reused:       {}\n", self.reused));
-        try!(write!(fmt, "keys:         {}\n", self.keys));
-        try!(write!(fmt, "prot_reads:   {}\n", self.prot_reads));
-        try!(write!(fmt, "prot_writes:  {}\n", self.prot_writes));
-        try!(write!(fmt, "prot_nones:   {}\n", self.prot_nones));
-        Ok(())
+        Ok(index)
     }
-}
 
+    #[inline]
+    fn regions_used(&self) -> usize {
+        self.total.checked_sub(self.free).unwrap()
+    }
 
-impl Region {
-    fn init(&mut self, object: *mut u8, size: usize, chunk: bool,
-            canary_dir: usize) {
-        assert!(!object.is_null());
-        self.object = object;
-        self.canary = canary_dir ^ object as usize;
-        self.kind = if chunk {
-            RegionType::Chunk
-        } else {
-            RegionType::Large
-        };
-        self.size = size;
+    unsafe fn regions_realloc(&mut self,
+                              count: usize) -> Result<(), MallocError> {
+        let regions = try!(regions_alloc(count)) as *mut Region;
+        let prev_total = self.total;
+        let prev_alloc = self.regions;
+        let mut prev_regions = self.regions;
+        // New index of each moved region.
+        let mut moved = vec![NO_REGION; prev_total];
 
-        if chunk {
-            self.init_chunk();
+        self.total = count;
+        self.free = count;
+        self.regions = regions;
+
+        // Move regions.
+        for i in 0_usize..prev_total {
+            if !(*prev_regions).is_free() {
+                let new_index = self.region_pick((*prev_regions).object);
+                let new_region = self.region_at_index_mut(new_index) as
+                    *mut Region;
+                self.free = self.free.checked_sub(1).unwrap();
+                *new_region = *prev_regions;
+                moved[i] = new_index;
+            }
+            prev_regions = prev_regions.offset(1);
         }
-    }
 
-    fn init_chunk(&mut self) {
-        if self.size == 0 {
-            return;
+        // Then relink lists.
+        let relink = |index: usize| {
+            if index == NO_REGION {
+                NO_REGION
+            } else {
+                moved[index]
+            }
+        };
+        for i in 0_usize..count {
+            let region = self.region_at_index_mut(i);
+            if !region.is_free() {
+                region.next = relink(region.next);
+                region.prev = relink(region.prev);
+            }
         }
+        self.map_list_ends(relink);
 
-        let max_index = max_slot_index(self.size);
+        // Finally deallocate old regions. Regions are already moved at
+        // this point, a failure would only leak the old pages.
+        let _ = regions_dealloc(prev_alloc as *mut u8, prev_total);
+        self.stats_mapped(count * mem::size_of::<Region>(), true);
+        self.stats_mapped(prev_total * mem::size_of::<Region>(), false);
+        Ok(())
+    }
 
-        for i in 0_usize..max_index >> 3 {
-            self.mapping[i] = 0xff;
+    fn regions_grow(&mut self) -> Result<(), MallocError> {
+        // Keep a sparse list of regions to minimize collisions on
+        // insertions.
+        if self.free.checked_mul(4).unwrap() >= self.total {
+            return Ok(());
         }
 
-        if max_index % 8 != 0 {
-            self.mapping[max_index >> 3] = 0;
+        let count = try!(self.total.checked_mul(2)
+                         .ok_or(MallocError::Overflow));
+        unsafe {
+            self.regions_realloc(count)
         }
+    }
 
-        for i in 0_usize..max_index % 8 {
-            self.mapping[max_index >> 3] |= 1 << i;
+    fn regions_shrink(&mut self) {
+        if self.total == INITIAL_REGIONS ||
+           self.regions_used().checked_mul(16).unwrap() >= self.total {
+            return;
         }
 
-        self.next = ptr::null_mut();
-        self.prev = ptr::null_mut();
+        let count = cmp::max(self.total.checked_div(2).unwrap(),
+                             INITIAL_REGIONS);
+        // Not fatal, current regions are kept on failure.
+        unsafe {
+            let _ = self.regions_realloc(count);
+        }
     }
 
     #[inline]
-    fn check_integrity(&self, canary_dir: usize) -> bool {
-        !self.is_free() && self.canary == canary_dir ^ self.object as usize
+    fn region_at_index(&self, index: usize) -> &Region {
+        assert!(index < self.total);
+        unsafe {
+            &*self.regions.offset(index.to_isize().unwrap())
+        }
     }
 
-    unsafe fn set_as_cache(&mut self) {
-        assert_eq!(self.kind as usize, RegionType::Chunk as usize);
+    #[inline]
+    fn region_at_index_mut(&mut self, index: usize) -> &mut Region {
+        assert!(index < self.total);
+        unsafe {
+            &mut *self.regions.offset(index.to_isize().unwrap())
+        }
+    }
 
-        self.kind = RegionType::Cache;
-        self.size = 0;
+    #[inline]
+    fn region_mask(&self) -> usize {
+        self.total - 1
+    }
 
-        assert!(!self.object.is_null());
-        mmap::protect(self.object, mmap::page_size(), Prot::None);
+    #[inline]
+    fn object_to_region_index(&self, object: *mut u8) -> usize {
+        let (key0, key1) = self.hash_keys;
+        let mut s = SipHasher::new_with_keys(key0, key1);
+        (mmap::mask_pointer(object) as usize).hash(&mut s);
+        s.finish() as usize & self.region_mask()
     }
 
-    unsafe fn set_as_chunk(&mut self, chunk_size: usize) {
-        assert_eq!(self.kind as usize, RegionType::Cache as usize);
+    fn region_pick(&self, object: *mut u8) -> usize {
+        // Algorithm L Knuth volume 3, 6.4.
+        let mut index = self.object_to_region_index(object);
+        loop {
+            if self.region_at_index(index).is_free() {
+                break;
+            }
+            index = index.wrapping_sub(1) & self.region_mask();
+        }
+        index
+    }
 
-        self.kind = RegionType::Chunk;
-        self.size = chunk_size;
-        self.init_chunk();
+    fn region_insert(&mut self, object: *mut u8, size: usize,
+                     kind: RegionType) -> Result<usize, MallocError> {
+        // Grow regions if needed.
+        try!(self.regions_grow());
 
-        assert!(!self.object.is_null());
-        mmap::protect(self.object, mmap::page_size(), Prot::ReadWrite);
-    }
+        let canary_dir = self.canary2;
+        let region_index = self.region_pick(object);
 
-    fn set_as_free(&mut self) {
-        self.object = ptr::null_mut();
-        self.kind = RegionType::Free;
-    }
+        let layout = {
+            let region = self.region_at_index_mut(region_index);
+            region.init(object, size, kind, canary_dir);
+            region.remote_layout()
+        };
 
-    #[inline]
-    fn is_free(&self) -> bool {
-        debug_assert_eq!(self.object.is_null(),
-                         (self.kind as usize == RegionType::Free as usize));
-        self.kind as usize == RegionType::Free as usize
+        self.region_register(object, layout);
+        self.free = self.free.checked_sub(1).unwrap();
+        Ok(region_index)
     }
 
-    #[inline]
-    fn is_chunk(&self) -> bool {
-        self.kind as usize == RegionType::Chunk as usize
+    // Record this directory as owner of `object` in the registry, other
+    // threads freeing its objects wipe them according to `layout`.
+    fn region_register(&self, object: *mut u8, layout: Layout) {
+        registry::register(mmap::mask_pointer(object) as usize, self.id,
+                           layout);
     }
 
-    fn chunk_state(&self, full: bool) -> bool {
-        // Check this region represents a valid chunk.
-        assert!(self.is_chunk() && self.size != 0);
+    fn region_find(&self, object: *mut u8) -> Option<usize> {
+        assert!(!object.is_null());
 
-        let byte_val = if full {
-            0
-        } else {
-            255
-        };
+        match self.region_find_page(object) {
+            // Medium objects are located through the first page of their
+            // span.
+            None if span_base(object) != mmap::mask_pointer(object) => {
+                self.region_find_page(span_base(object)).and_then(|index| {
+                    if self.region_at_index(index).is_span() {
+                        Some(index)
+                    } else {
+                        None
+                    }
+                })
+            },
+            found => found
+        }
+    }
 
-        let max_slot_index = max_slot_index(self.size);
+    fn region_find_page(&self, object: *mut u8) -> Option<usize> {
+        let start = mmap::mask_pointer(object);
+        let mut index = self.object_to_region_index(object);
 
-        for i in 0_usize..max_slot_index >> 3 {
-            if self.mapping[i] != byte_val {
-                return false;
+        loop {
+            let region = self.region_at_index(index);
+            if region.is_free() {
+                return None;
+            }
+            if mmap::mask_pointer(region.object) == start {
+                if !region.is_chunk() && !region.is_span() &&
+                   region.object != object {
+                    return None;
+                } else {
+                    return Some(index);
+                }
             }
+            index = index.wrapping_sub(1) & self.region_mask();
         }
+    }
 
-        for i in 0_usize..max_slot_index % 8 {
-            let expected_val = if full {
-                0
-            } else {
-                1 << i
-            };
-            if self.mapping[max_slot_index >> 3] & (1 << i) != expected_val {
-                return false;
+    fn region_delete(&mut self, index: usize) {
+        // Algorithm R Knuth volume 3, 6.4.
+        self.free += 1;
+        let mut i = index;
+
+        'a: loop {
+            self.region_at_index_mut(i).set_as_free();
+            let j = i;
+
+            loop {
+                i = i.wrapping_sub(1) & self.region_mask();
+                let ri = {
+                    let region = self.region_at_index(i);
+                    if region.is_free() {
+                        break 'a;
+                    }
+                    self.object_to_region_index(region.object)
+                };
+	        if (i <= ri && ri < j) || (ri < j && j < i) ||
+                   (j < i && i <= ri) {
+		    continue;
+                }
+                unsafe {
+                    *self.regions.offset(j.to_isize().unwrap()) =
+                        *self.regions.offset(i.to_isize().unwrap());
+                }
+                self.list_relink(i, j);
+                break;
             }
         }
 
-        true
+        // Shrink regions if needed.
+        self.regions_shrink();
     }
 
-    fn is_full_chunk(&self) -> bool {
-        self.chunk_state(true)
+    // Apply `f` to the first and last indexes of every list.
+    fn map_list_ends<F: Fn(usize) -> usize>(&mut self, f: F) {
+        self.cache1 = f(self.cache1);
+        self.cache2 = f(self.cache2);
+        for end in self.chunks1.iter_mut()
+            .chain(self.chunks2.iter_mut())
+            .chain(self.mediums1.iter_mut())
+            .chain(self.mediums2.iter_mut()) {
+            *end = f(*end);
+        }
     }
 
-    fn is_empty_chunk(&self) -> bool {
-        self.chunk_state(false)
+    // Fix the links to a region moved from index `from` to index `to`.
+    fn list_relink(&mut self, from: usize, to: usize) {
+        let (prev, next) = {
+            let region = self.region_at_index(to);
+            (region.prev, region.next)
+        };
+        if prev != NO_REGION {
+            self.region_at_index_mut(prev).next = to;
+        }
+        if next != NO_REGION {
+            self.region_at_index_mut(next).prev = to;
+        }
+        if prev == NO_REGION || next == NO_REGION {
+            self.map_list_ends(|end| if end == from { to } else { end });
+        }
     }
 
-    #[inline]
-    fn chunk_slot_is_free(&self, index: usize) -> bool {
-        debug_assert!(index < max_slot_index(self.size));
-        self.mapping[index >> 3] & (1 << (index % 8)) != 0
+    // Linked regions must always be valid, fail with an integrity error
+    // otherwise.
+    unsafe fn list_region(&self, index: usize)
+                          -> Result<*mut Region, MallocError> {
+        if index >= self.total ||
+           !self.region_at_index(index).check_integrity(self.canary2) {
+            return Err(MallocError::IntegrityViolation);
+        }
+        Ok(self.regions.offset(index.to_isize().unwrap()))
     }
 
-    fn take_chunk_slot(&mut self) -> usize {
-        debug_assert!(self.is_chunk() && self.size != 0 &&
-                      !self.is_full_chunk());
-
-        let max_slot_index = max_slot_index(self.size);
-        assert!(max_slot_index > 0);
-        let mut slot_index = utils::rng().gen_range(0_usize, max_slot_index);
-
-        let mut found = false;
-        for _ in 0_usize..max_slot_index {
-            if self.chunk_slot_is_free(slot_index) {
-                found = true;
-                break;
-            }
-            slot_index = slot_index.wrapping_sub(1) % max_slot_index;
+    unsafe fn list_insert(&mut self, start: &mut usize, end: &mut usize,
+                          index: usize) -> Result<(), MallocError> {
+        let region = try!(self.list_region(index));
+        if *start == NO_REGION {
+            assert!(*end == NO_REGION);
+            *end = index;
+        } else {
+            assert!(*end != NO_REGION);
+            let first_region = try!(self.list_region(*start));
+            (*first_region).prev = index;
         }
-        assert!(found);
 
-        // Mark slot as taken.
-        self.mapping[slot_index >> 3] ^= 1 << (slot_index % 8);
-
-        slot_index
+        (*region).next = *start;
+        (*region).prev = NO_REGION;
+        *start = index;
+        Ok(())
     }
 
-    unsafe fn free_chunk_slot(&mut self, offset: usize) {
-        debug_assert!(self.is_chunk() && self.size != 0);
+    unsafe fn list_remove(&mut self, start: &mut usize, end: &mut usize,
+                          index: usize) -> Result<(), MallocError> {
+        let region = try!(self.list_region(index));
+        let (prev, next) = ((*region).prev, (*region).next);
 
-        assert!(offset < mmap::page_size() && offset % self.size == 0);
-        let slot_index = offset.checked_div(self.size).unwrap();
-        assert!(slot_index < max_slot_index(self.size));
+        let prev_region = if prev != NO_REGION {
+            try!(self.list_region(prev))
+        } else if *start == index {
+            ptr::null_mut()
+        } else {
+            return Err(MallocError::IntegrityViolation);
+        };
 
-        // Potentially detected a double free.
-        assert!(!self.chunk_slot_is_free(slot_index));
+        let next_region = if next != NO_REGION {
+            try!(self.list_region(next))
+        } else if *end == index {
+            ptr::null_mut()
+        } else {
+            return Err(MallocError::IntegrityViolation);
+        };
 
-        // Mark slot as free.
-        self.mapping[slot_index >> 3] |= 1 << (slot_index % 8);
+        if prev_region.is_null() {
+            *start = next;
+        } else {
+            (*prev_region).next = next;
+        }
+        if next_region.is_null() {
+            *end = prev;
+        } else {
+            (*next_region).prev = prev;
+        }
 
-        utils::set_memory(self.object.offset(offset as isize),
-                          fill_byte_dealloc().unwrap(), self.size);
+        (*region).next = NO_REGION;
+        (*region).prev = NO_REGION;
+        Ok(())
     }
 
-    unsafe fn dealloc_data(&mut self, forced: bool) {
-        match self.kind {
-            RegionType::Chunk => {
-                let fill = if forced {
-                    fill_byte_dealloc()
-                } else {
-                    None
-                };
-                mmap::deallocate(self.object, mmap::page_size(), fill);
-            },
-            RegionType::Large => {
-                mmap::deallocate(self.object, self.size, fill_byte_dealloc());
-            },
-            RegionType::Cache => {
-                mmap::deallocate(self.object, mmap::page_size(), None);
-            },
-            _ => unreachable!()
+    unsafe fn free_chunk_insert(&mut self, region_index: usize)
+                                -> Result<(), MallocError> {
+        let dir: *mut Dir = mem::transmute(self);
+
+        let region = (*dir).regions.offset(region_index.to_isize().unwrap());
+        assert!((*region).is_chunk());
+
+        let index = chunk_index((*region).size);
+
+        if (*region).size == 0 {
+            assert!((*dir).chunks1[index] == NO_REGION);
         }
 
-        self.set_as_free();
+        (*dir).list_insert(&mut (*dir).chunks1[index],
+                           &mut (*dir).chunks2[index], region_index)
     }
-}
 
-impl Debug for Region {
-    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
-        if self.is_free() {
-            return write!(fmt, "Region: free\n");
-        }
+    unsafe fn free_chunk_remove(&mut self, region_index: usize)
+                                -> Result<(), MallocError> {
+        let dir: *mut Dir = mem::transmute(self);
 
-        try!(write!(fmt, "Region: used, type: {}, size: {}\n",
-                    self.kind as usize, self.size));
+        let region = (*dir).regions.offset(region_index.to_isize().unwrap());
+        assert!((*region).is_chunk() && (*region).size != 0);
 
-        if self.is_chunk() && self.size != 0 {
-            try!(write!(fmt, "mapping:"));
-            for i in 0_usize..max_slot_index(self.size) {
-                if i % 8 == 0 {
-                    try!(write!(fmt, " "));
-                }
-                if i > 0 && i % 32 == 0  {
-                    try!(write!(fmt, "\n         "));
-                }
-                try!(write!(fmt, "{}", self.chunk_slot_is_free(i) as usize));
-            }
-            try!(write!(fmt, "\n"));
-        }
+        let index = chunk_index((*region).size);
+        (*dir).list_remove(&mut (*dir).chunks1[index],
+                           &mut (*dir).chunks2[index], region_index)
+    }
 
-        Ok(())
+    unsafe fn free_span_insert(&mut self, region_index: usize)
+                               -> Result<(), MallocError> {
+        let dir: *mut Dir = mem::transmute(self);
+
+        let region = (*dir).regions.offset(region_index.to_isize().unwrap());
+        assert!((*region).is_span());
+
+        let index = medium_index((*region).size);
+        (*dir).list_insert(&mut (*dir).mediums1[index],
+                           &mut (*dir).mediums2[index], region_index)
     }
-}
 
+    unsafe fn free_span_remove(&mut self, region_index: usize)
+                               -> Result<(), MallocError> {
+        let dir: *mut Dir = mem::transmute(self);
 
-fn align_to_size(align: usize, size: usize) -> Option<usize> {
-    match align {
-        0 => Some(size),
-        algn if !algn.is_power_of_two() || algn >= mmap::page_size() => None,
-        algn if algn <= mmap::MIN_ALIGN => Some(size),
-        algn => Some(cmp::max(size.next_power_of_two(), algn))
+        let region = (*dir).regions.offset(region_index.to_isize().unwrap());
+        assert!((*region).is_span());
+
+        let index = medium_index((*region).size);
+        (*dir).list_remove(&mut (*dir).mediums1[index],
+                           &mut (*dir).mediums2[index], region_index)
     }
-}
 
-unsafe fn xmalloc(size: usize, align: usize, zero_fill: bool,
-                  force_large: bool) -> *mut u8 {
-    let sz = match align_to_size(align, size) {
-        Some(sz) => sz,
-        None => return ptr::null_mut()
-    };
+    #[inline]
+    fn can_cache_chunk(&self) -> bool {
+        self.cache_len < options().cache_size
+    }
 
-    thread_dir().alloc(sz, zero_fill, force_large)
-}
+    #[inline]
+    fn has_cached_chunk(&self) -> bool {
+        self.cache_len > 0
+    }
 
-/// Allocate memory
-///
-/// Provides the same interface than the usual `malloc` function along
-/// with the following specifities: the requested alignment `align` must
-/// be a power of two smaller than pagesize. Also, `align` is expected
-/// to be equal to zero if no specific alignment needs to be requested.
-/// This function returns `NULL` if `align` is invalid and otherwise
-/// `panic!` on error.
-pub unsafe fn malloc(size: usize, align: usize) -> *mut u8 {
-    xmalloc(size, align, false, false)
-}
+    unsafe fn cache_chunk_insert(&mut self, region_index: usize)
+                                 -> Result<(), MallocError> {
+        let dir: *mut Dir = mem::transmute(self);
 
-/// Allocate memory
-///
-/// Provides the same interface than the usual `calloc` function, see
-/// `malloc` for this implementation's specifities.
-pub unsafe fn calloc(count: usize, size: usize, align: usize) -> *mut u8 {
-    let full_sz = count.checked_mul(size).unwrap();
-    xmalloc(full_sz, align, true, false)
-}
+        let region = (*dir).regions.offset(region_index.to_isize().unwrap());
+        assert!((*region).is_chunk() && (*region).size != 0);
 
-/// Allocate memory and allow changes to memory protections
-///
-/// Similar to `malloc` but with the additional guarantee that its
-/// returned pointer can be used with `protect_read`, `protect_write`
-/// or `protect_none` to change memory protections on its allocated
-/// region.
-pub unsafe fn malloc_key(size: usize, align: usize) -> *mut u8 {
-    xmalloc(size, align, false, true)
-}
+        try!((*dir).list_insert(&mut (*dir).cache1, &mut (*dir).cache2,
+                                region_index));
 
+        (*dir).cache_len += 1;
+        (*dir).region_register((*region).object, Layout::none());
+        (*region).set_as_cache()
+    }
 
-unsafe fn xrealloc(ptr: *mut u8, size: usize, align: usize,
-                   force_large: bool) -> *mut u8 {
-    let sz = match align_to_size(align, size) {
-        Some(sz) => sz,
-        None => return ptr::null_mut()
-    };
+    unsafe fn cache_chunk_take(&mut self, chunk_size: usize)
+                               -> Result<(usize, *mut u8), MallocError> {
+        let dir: *mut Dir = mem::transmute(self);
 
-    thread_dir().realloc(ptr, sz, false, force_large)
-}
+        let region_index = if (*dir).rng.gen_range(0_usize, 2_usize) == 1 {
+            (*dir).cache1
+        } else {
+            (*dir).cache2
+        };
+        assert!(region_index != NO_REGION);
+
+        let region = try!((*dir).list_region(region_index));
+        let chunk = (*region).object;
+        // A chunk whose junk was overwritten is unmapped instead.
+        if options().validate_junk {
+            try!(mmap::protect(chunk, mmap::page_size(), Prot::Read));
+            if let Err(err) = check_junk(chunk, 0, mmap::page_size(),
+                                         chunk_size) {
+                if (*dir).cache_chunk_release(region_index).is_err() {
+                    let _ = mmap::protect(chunk, mmap::page_size(),
+                                          Prot::None);
+                }
+                return Err(err);
+            }
+        }
+        try!((*dir).list_remove(&mut (*dir).cache1, &mut (*dir).cache2,
+                                region_index));
 
-/// Reallocate memory
-///
-/// Provides the same interface than the usual `realloc` function, see
-/// `malloc` for this implementation's specifities.
-pub unsafe fn realloc(ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
-    xrealloc(ptr, size, align, false)
-}
+        (*dir).cache_len -= 1;
+        try!((*region).set_as_chunk(chunk_size));
+        (*dir).region_register(chunk, (*region).remote_layout());
 
-/// Reallocate memory and allow changes to memory protections
-///
-/// Must only be called after memory allocation with `malloc_key`.
-pub unsafe fn realloc_key(ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
-    xrealloc(ptr, size, align, true)
-}
+        Ok((region_index, chunk))
+    }
 
+    // Unmap the cached chunk of region `region_index`.
+    unsafe fn cache_chunk_release(&mut self, region_index: usize)
+                                  -> Result<(), MallocError> {
+        let dir: *mut Dir = mem::transmute(self);
 
-/// Free memory
-///
-/// Provides the same interface than the usual `free` function. This function
-/// has no effect if `ptr` is a `NULL` pointer or a pointer to a zero-sized
-/// area. But will `panic!` for any other kind of error.
-pub unsafe fn free(ptr: *mut u8) {
-    thread_dir().dealloc(ptr);
-}
+        let region = try!((*dir).list_region(region_index));
+        try!((*dir).list_remove(&mut (*dir).cache1, &mut (*dir).cache2,
+                                region_index));
 
+        (*dir).cache_len -= 1;
+        try!((*region).dealloc_data(false));
+        (*dir).region_delete(region_index);
+        if let Some(counters) = (*dir).counters() {
+            stat_update(&counters.cached, 1, false);
+        }
+        (*dir).stats_mapped(mmap::page_size(), false);
+        (*dir).usage_credit(0, mapping_pages(mmap::page_size()));
+        Ok(())
+    }
 
-/// Set memory protection to read-only
-///
-/// `ptr` must have been allocated through `malloc_key` exclusively.
-/// This function has no effect if `ptr` is `NULL` but `panic!` for
-/// any other kind of error.
-pub unsafe fn protect_read(ptr: *mut u8) {
-    thread_dir().protect(ptr, Prot::Read);
-}
+    #[inline]
+    fn has_free_chunk(&self, chunk_size: usize) -> bool {
+        self.chunks1[chunk_index(chunk_size)] != NO_REGION
+    }
 
-/// Set memory protection to write-only
-///
-/// See `protect_read` for its usage.
-///
-/// Note that due to hardware limitations on most architectures (such
-/// as x86, x86_64), setting a `write` page protection will also implicitly
-/// imply granting `read` access too (see `man mprotect`).
-pub unsafe fn protect_write(ptr: *mut u8) {
-    thread_dir().protect(ptr, Prot::Write);
-}
+    unsafe fn create_chunk(&mut self,
+                           chunk_size: usize) -> Result<(), MallocError> {
+        let (region_index, chunk) = if self.has_cached_chunk() {
+            let taken = try!(self.cache_chunk_take(chunk_size));
+            if let Some(counters) = self.counters() {
+                stat_update(&counters.cache_hits, 1, true);
+                stat_update(&counters.cached, 1, false);
+            }
+            taken
+        } else {
+            // Its slots must look like freed slots to be validated.
+            let fill = if options().validate_junk {
+                fill_byte_dealloc()
+            } else {
+                fill_byte_alloc(false)
+            };
+            let pages = mapping_pages(mmap::page_size());
+            try!(self.usage_charge(0, pages));
+            let chunk = match mmap::allocate(mmap::page_size(),
+                                             0,
+                                             fill,
+                                             Prot::ReadWrite,
+                                             RangePos::Start) {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    self.usage_credit(0, pages);
+                    return Err(From::from(err));
+                }
+            };
+            let region_index = match self.region_insert(chunk, chunk_size,
+                                                        RegionType::Chunk) {
+                Ok(index) => index,
+                Err(err) => {
+                    let _ = mmap::deallocate(chunk, mmap::page_size(), None);
+                    self.usage_credit(0, pages);
+                    return Err(err);
+                }
+            };
+            if let Some(counters) = self.counters() {
+                stat_update(&counters.cache_misses, 1, true);
+            }
+            self.stats_mapped(mmap::page_size(), true);
+            (region_index, chunk)
+        };
 
-/// Set memory protection to prevent any access
-///
-/// See `protect_read` for its usage.
-pub unsafe fn protect_none(ptr: *mut u8) {
-    thread_dir().protect(ptr, Prot::None);
-}
+        try!(self.free_chunk_insert(region_index));
 
+        // A static chunk is used for allocations of size 0 and is pointing
+        // to a non-readable-writable memory area.
+        if chunk_size == 0 {
+            try!(mmap::protect(chunk, mmap::page_size(), Prot::None));
+        }
+        Ok(())
+    }
 
-#[cfg(test)]
-mod test {
-    use alloc::heap;
-    use libc;
-    use std::cmp;
-    use std::collections::HashSet;
-    use std::ptr;
-    use std::sync::{Arc, Barrier};
-    use std::sync::mpsc::channel;
-    use std::thread;
-    use std::usize;
-    use test::Bencher;
+    unsafe fn take_chunk_slot(&mut self, chunk_size: usize, real_size: usize,
+                              zero_fill: bool) -> Result<*mut u8, MallocError> {
+        debug_assert!(self.has_free_chunk(chunk_size) &&
+                      chunk_size >= real_size);
 
-    use num::ToPrimitive;
-    use rand::{thread_rng, Rng};
+        let index = chunk_index(chunk_size);
+        // Either take the first chunk or the last one.
+        let region_index = if self.rng.gen_range(0_usize, 2_usize) == 1 {
+            self.chunks1[index]
+        } else {
+            self.chunks2[index]
+        };
+        assert!(region_index != NO_REGION);
 
-    use mmap;
-    use utils;
+        let region = try!(self.list_region(region_index));
+        let chunk = (*region).object;
+        if index == 0 {
+            return Ok(chunk);
+        }
 
+        let slot_index = (*region).pick_chunk_slot(&mut self.rng);
+        let junk = check_junk(chunk, slot_index * chunk_size, chunk_size,
+                              chunk_size);
+        (*region).take_slot(slot_index);
+        let chunk_now_full = (*region).is_full_chunk();
 
-    fn print_dir_state() {
-        info!("{:?}", super::thread_dir())
-    }
+        if chunk_now_full {
+            try!(self.free_chunk_remove(region_index));
+        }
+        let slot = chunk.offset((slot_index * chunk_size) as isize);
+        // A slot whose junk was overwritten stays taken for good, it is
+        // never handed out nor counted as used. Its canary keeps it
+        // consistent for `check_heap`.
+        if let Err(err) = junk {
+            if options().canaries {
+                write_canary(slot, chunk_size, real_size, self.canary_keys);
+            }
+            return Err(err);
+        }
+        self.stats_slots(chunk_size, 1, true);
 
-    fn write_byte(ptr: *mut u8, index: usize) {
-        unsafe {
-            *ptr.offset(index.to_isize().unwrap()) = (index % 256) as u8;
+        if let Some(fill_byte) = fill_byte_alloc(zero_fill) {
+            ptr::write_bytes(slot, fill_byte, chunk_size);
         }
+        if options().canaries {
+            write_canary(slot, chunk_size, real_size, self.canary_keys);
+        }
+
+        Ok(slot)
     }
 
-    fn read_byte(ptr: *const u8, index: usize) {
-        unsafe {
-            assert_eq!(*ptr.offset(index.to_isize().unwrap()),
-                       (index % 256) as u8);
+    // `align` is only honoured by large objects and keys, `size` must be
+    // enough to align chunks slots, see `align_to_size`.
+    pub unsafe fn alloc(&mut self, size: usize, align: usize, zero_fill: bool,
+                        force_large: bool) -> Result<*mut u8, MallocError> {
+        if !self.check_integrity() {
+            return Err(MallocError::IntegrityViolation);
+        }
+
+        let medium = is_medium(size, align, force_large);
+        let large = !medium &&
+            (force_large || slot_size(size) > max_chunk_size());
+        let bytes = if medium {
+            medium_size(size)
+        } else if large {
+            size
+        } else {
+            chunk_size(slot_size(size))
+        };
+
+        try!(self.usage_charge(bytes, 0));
+        let mut rv = self.alloc_object(size, align, zero_fill, force_large);
+        if self.should_retry(&rv) {
+            rv = self.alloc_object(size, align, zero_fill, force_large);
+        }
+        match rv {
+            Ok(ptr) => self.trace_event(TraceOp::Alloc, ptr, size, None),
+            Err(_) => self.usage_credit(bytes, 0)
         }
+        rv
     }
 
+    unsafe fn alloc_object(&mut self, size: usize, align: usize,
+                           zero_fill: bool, force_large: bool)
+                           -> Result<*mut u8, MallocError> {
+        if is_medium(size, align, force_large) {
+            self.alloc_medium(size, zero_fill)
+        } else if force_large || slot_size(size) > max_chunk_size() {
+            self.alloc_large(size, align, zero_fill, force_large)
+        } else {
+            self.alloc_chunk_slot(size, zero_fill)
+        }
+    }
 
-    #[test]
-    fn test_malloc_chunks() {
-        const NA: usize = 2048;
-        let mut p: [*mut u8; NA] = [ptr::null_mut(); NA];
-        let mut s: [usize; NA] = [0_usize; NA];
+    // Return `true` if `rv` failed for lack of memory and trimming the
+    // caches released some pages, the failed operation is then worth
+    // retrying once. Only this directory is trimmed, the caches of other
+    // threads are left alone even though the mlock limit is process-wide.
+    unsafe fn should_retry<T>(&mut self,
+                              rv: &Result<T, MallocError>) -> bool {
+        match *rv {
+            Err(MallocError::OutOfMemory) | Err(MallocError::MlockLimit) =>
+                self.trim().map_or(false, |pages| pages > 0),
+            _ => false
+        }
+    }
 
-        for i in 0_usize..NA {
-            p[i] = unsafe {
-                let size = thread_rng().gen_range(0_usize,
-                                                  utils::page_size() >> 1);
-                s[i] = size;
-                super::malloc(size, 0)
-            };
-            assert!(!p[i].is_null());
+    // Allocate `count` objects of `size` bytes, see `alloc`. Large objects
+    // are all mapped at once, other objects are allocated one by one. On
+    // error the objects already allocated are freed.
+    pub unsafe fn alloc_batch(&mut self, size: usize, count: usize,
+                              align: usize, zero_fill: bool)
+                              -> Result<Vec<*mut u8>, MallocError> {
+        if !self.check_integrity() {
+            return Err(MallocError::IntegrityViolation);
+        }
 
-            for j in 0_usize..s[i] {
-                write_byte(p[i], j);
+        let large = !is_medium(size, align, false) &&
+            slot_size(size) > max_chunk_size();
+        if !large || align > mmap::page_size() {
+            let mut objects = Vec::with_capacity(count);
+            for _ in 0_usize..count {
+                match self.alloc(size, align, zero_fill, false) {
+                    Ok(object) => objects.push(object),
+                    Err(err) => {
+                        for &object in objects.iter() {
+                            let _ = self.dealloc(object);
+                        }
+                        return Err(err);
+                    }
+                }
             }
+            return Ok(objects);
         }
 
-        for i in (0_usize..NA).step_by(16) {
-            for j in 0_usize..s[i] {
-                read_byte(p[i] as *const u8, j);
+        let pages = mapping_pages(size);
+        let bytes = try!(size.checked_mul(count)
+                         .ok_or(MallocError::Overflow));
+        let all_pages = try!(pages.checked_mul(count)
+                             .ok_or(MallocError::Overflow));
+        try!(self.usage_charge(bytes, all_pages));
+        let fill = fill_byte_alloc(zero_fill);
+        let mut rv = mmap::allocate_batch(size, count, fill, Prot::ReadWrite)
+            .map_err(MallocError::from);
+        if self.should_retry(&rv) {
+            rv = mmap::allocate_batch(size, count, fill, Prot::ReadWrite)
+                .map_err(MallocError::from);
+        }
+        let objects = match rv {
+            Ok(objects) => objects,
+            Err(err) => {
+                self.usage_credit(bytes, all_pages);
+                return Err(err);
             }
+        };
 
-            unsafe {
-                super::free(p[i]);
+        for (i, &object) in objects.iter().enumerate() {
+            match self.region_insert(object, size, RegionType::Large) {
+                Ok(index) => {
+                    self.region_at_index_mut(index).prot = Prot::ReadWrite;
+                    self.stats_large(size, false, true);
+                    self.trace_event(TraceOp::Alloc, object, size, None);
+                },
+                Err(err) => {
+                    // Inserted objects are freed as usual, the others are
+                    // unmapped and their charges released.
+                    for &inserted in objects[..i].iter() {
+                        let _ = self.dealloc(inserted);
+                    }
+                    for &left in objects[i..].iter() {
+                        let _ = mmap::deallocate(left, size, None);
+                        self.usage_credit(size, pages);
+                    }
+                    return Err(err);
+                }
             }
         }
 
-        for i in (0_usize..NA).step_by(16) {
-            p[i] = unsafe {
-                let size = thread_rng().gen_range(0_usize,
-                                                  utils::page_size() >> 1);
-                s[i] = size;
-                super::malloc(size, 0)
-            };
-            assert!(!p[i].is_null());
+        Ok(objects)
+    }
 
-            for j in 0_usize..s[i] {
-                write_byte(p[i], j);
+    unsafe fn alloc_large(&mut self, size: usize, align: usize,
+                          zero_fill: bool, force_large: bool)
+                          -> Result<*mut u8, MallocError> {
+        // Smaller alignments are already met by pages or by `size`.
+        let align = if align >= mmap::page_size() {
+            align
+        } else {
+            0
+        };
+        let (prot, pos, kind) = if force_large {
+            (Prot::Write, RangePos::End, RegionType::Key)
+        } else {
+            (Prot::ReadWrite, RangePos::Start, RegionType::Large)
+        };
+
+        if let Some(object) = try!(self.spare_take(size, align, zero_fill,
+                                                   prot, pos, kind)) {
+            self.stats_large(size, force_large, true);
+            return Ok(object);
+        }
+
+        let pages = mapping_pages(size);
+        try!(self.usage_charge(0, pages));
+        let object = match mmap::allocate(size, align,
+                                          fill_byte_alloc(zero_fill),
+                                          prot, pos) {
+            Ok(object) => object,
+            Err(err) => {
+                self.usage_credit(0, pages);
+                return Err(From::from(err));
+            }
+        };
+        match self.region_insert(object, size, kind) {
+            Ok(index) => self.region_at_index_mut(index).prot = prot,
+            Err(err) => {
+                let _ = mmap::deallocate(object, size, None);
+                self.usage_credit(0, pages);
+                return Err(err);
             }
         }
+        self.stats_large(size, force_large, true);
+
+        Ok(object)
+    }
+
+    unsafe fn alloc_chunk_slot(&mut self, size: usize, zero_fill: bool)
+                               -> Result<*mut u8, MallocError> {
+        let chunk_size = chunk_size(slot_size(size));
+
+        if !self.has_free_chunk(chunk_size) {
+            try!(self.create_chunk(chunk_size));
+        }
+
+        self.take_chunk_slot(chunk_size, size, zero_fill)
+    }
+
+    unsafe fn alloc_medium(&mut self, size: usize,
+                           zero_fill: bool) -> Result<*mut u8, MallocError> {
+        let medium_size = medium_size(size);
+
+        if self.mediums1[medium_index(medium_size)] == NO_REGION {
+            try!(self.create_span(medium_size));
+        }
+
+        self.take_span_slot(medium_size, zero_fill)
+    }
+
+    // Map a new span of slots of size-class `medium_size`, all its pages
+    // are inaccessible until their slot is taken.
+    unsafe fn create_span(&mut self,
+                          medium_size: usize) -> Result<(), MallocError> {
+        let size = span_size(medium_size);
+        let pages = mapping_pages(size);
+        try!(self.usage_charge(0, pages));
+        let span = match mmap::reserve(size, SPAN_PAGES * mmap::page_size()) {
+            Ok(span) => span,
+            Err(err) => {
+                self.usage_credit(0, pages);
+                return Err(From::from(err));
+            }
+        };
+        let region_index = match self.region_insert(span, medium_size,
+                                                    RegionType::Span) {
+            Ok(index) => index,
+            Err(err) => {
+                let _ = mmap::deallocate(span, size, None);
+                self.usage_credit(0, pages);
+                return Err(err);
+            }
+        };
+        self.stats_span(true);
+
+        self.free_span_insert(region_index)
+    }
+
+    unsafe fn take_span_slot(&mut self, medium_size: usize,
+                             zero_fill: bool) -> Result<*mut u8, MallocError> {
+        let index = medium_index(medium_size);
+        // Either take the first span or the last one.
+        let region_index = if self.rng.gen_range(0_usize, 2_usize) == 1 {
+            self.mediums1[index]
+        } else {
+            self.mediums2[index]
+        };
+        assert!(region_index != NO_REGION);
+
+        let region = try!(self.list_region(region_index));
+        let span = (*region).object;
+
+        let offset = (*region).take_chunk_slot(&mut self.rng) *
+            (*region).slot_stride();
+        let slot = span.offset(offset as isize);
+        if let Err(err) = mmap::revive(slot, medium_size,
+                                       fill_byte_alloc(zero_fill)) {
+            // Put the slot back, inaccessible again.
+            let _ = mmap::tombstone(slot, medium_size, None);
+            let _ = (*region).free_slot(offset);
+            return Err(From::from(err));
+        }
+
+        if (*region).is_full_chunk() {
+            try!(self.free_span_remove(region_index));
+        }
+        self.stats_mediums(medium_size, 1, true);
+        Ok(slot)
+    }
+
+    pub unsafe fn realloc(&mut self, ptr: *mut u8, size: usize, align: usize,
+                          zero_fill: bool, force_large: bool)
+                          -> Result<*mut u8, MallocError> {
+        if !self.check_integrity() {
+            return Err(MallocError::IntegrityViolation);
+        }
+
+        if ptr.is_null() {
+            return self.alloc(size, align, zero_fill, force_large);
+        }
+
+        // Check the previous pointer before allocating anything.
+        let region_index = try!(self.region_find(ptr)
+                                .ok_or(MallocError::UnknownPointer));
+        let prev_size = {
+            let region = self.region_at_index(region_index);
+
+            if !region.check_integrity(self.canary2) {
+                return Err(MallocError::IntegrityViolation);
+            }
+            match region.kind {
+                RegionType::Cache | RegionType::Tomb | RegionType::Spare =>
+                    return Err(MallocError::DoubleFree),
+                _ if self.in_quarantine(ptr) =>
+                    return Err(MallocError::DoubleFree),
+                _ => ()
+            }
+
+            region.size
+        };
+
+        if let Some(nptr) = try!(self.realloc_in_place(region_index, ptr,
+                                                       size, align,
+                                                       force_large)) {
+            self.trace_event(TraceOp::Realloc, nptr, size, None);
+            return Ok(nptr);
+        }
+
+        let nptr = try!(self.alloc(size, align, zero_fill, force_large));
+        assert!(!nptr.is_null());
+
+        let copied = cmp::min(size, prev_size);
+        ptr::copy_nonoverlapping(ptr as *const u8, nptr, copied);
+
+        if let Err(err) = self.dealloc(ptr) {
+            // The caller keeps `ptr`, the copy of its data is not leaked.
+            utils::zero_memory(nptr, copied);
+            let _ = self.dealloc(nptr);
+            return Err(err);
+        }
+        self.trace_event(TraceOp::Realloc, nptr, size, None);
+        Ok(nptr)
+    }
+
+    // Resize the object at `ptr` without allocating a new one. Return `None`
+    // if it must be moved to another object, as well if it is not aligned
+    // on `align`.
+    unsafe fn realloc_in_place(&mut self, region_index: usize, ptr: *mut u8,
+                               size: usize, align: usize, force_large: bool)
+                               -> Result<Option<*mut u8>, MallocError> {
+        if align > 0 && ptr as usize & (align - 1) != 0 {
+            return Ok(None);
+        }
+
+        let canary_dir = self.canary2;
+        let region = self.regions.offset(region_index.to_isize().unwrap());
+
+        let pos = match (*region).kind {
+            RegionType::Chunk if !force_large &&
+                                 slot_size(size) <= max_chunk_size() => {
+                let class = (*region).size;
+                if chunk_size(slot_size(size)) != class {
+                    return Ok(None);
+                }
+                // The static chunk is shared by all allocations of size 0.
+                if size != 0 {
+                    let offset = try!((ptr as usize)
+                                      .checked_sub((*region).object as usize)
+                                      .ok_or(MallocError::UnknownPointer));
+                    try!((*region).used_slot_index(offset));
+                    try!(self.check_canary(&*region, offset));
+                    if options().canaries {
+                        write_canary(ptr, class, size, self.canary_keys);
+                    }
+                }
+                return Ok(Some(ptr));
+            },
+            RegionType::Span if is_medium(size, align, force_large) => {
+                if medium_size(size) != (*region).size {
+                    return Ok(None);
+                }
+                let offset = try!((ptr as usize)
+                                  .checked_sub((*region).object as usize)
+                                  .ok_or(MallocError::UnknownPointer));
+                try!((*region).used_slot_index(offset));
+                return Ok(Some(ptr));
+            },
+            RegionType::Large if !force_large && size > max_chunk_size() &&
+                                 !is_medium(size, align, false) =>
+                RangePos::Start,
+            RegionType::Key if force_large => RangePos::End,
+            _ => return Ok(None)
+        };
+
+        if (*region).object != ptr {
+            return Err(MallocError::UnknownPointer);
+        }
+
+        // Growth is charged up front.
+        let prev_size = (*region).size;
+        let (prev_pages, pages) = (mapping_pages(prev_size),
+                                   mapping_pages(size));
+        let (more_bytes, more_pages) = (size.saturating_sub(prev_size),
+                                        pages.saturating_sub(prev_pages));
+        try!(self.usage_charge(more_bytes, more_pages));
+
+        let nptr = match mmap::reallocate(ptr, prev_size, size,
+                                          fill_byte_dealloc(),
+                                          (*region).prot, pos) {
+            Ok(Some(nptr)) => nptr,
+            Ok(None) => {
+                self.usage_credit(more_bytes, more_pages);
+                return Ok(None);
+            },
+            Err(err) => {
+                self.usage_credit(more_bytes, more_pages);
+                return Err(From::from(err));
+            }
+        };
+        self.usage_credit(prev_size.saturating_sub(size),
+                          prev_pages.saturating_sub(pages));
+
+        // Still located in the same first page, its hash is unchanged.
+        (*region).object = nptr;
+        (*region).canary = canary_dir ^ nptr as usize;
+        (*region).size = size;
+        self.region_register(nptr, (*region).remote_layout());
+        // The buffer is left writable, the region is kept consistent with
+        // the mapping if its protections can't be restored. Like keys
+        // moved by a copy, it is then handed back writable.
+        if (*region).prot != Prot::ReadWrite &&
+           mmap::protect(nptr, size, (*region).prot).is_err() {
+            (*region).prot = Prot::ReadWrite;
+        }
+
+        self.stats_large(prev_size, force_large, false);
+        self.stats_large(size, force_large, true);
+        Ok(Some(nptr))
+    }
+
+    unsafe fn free_chunk_slot(&mut self, region_index: usize,
+                              offset: usize) -> Result<(), MallocError> {
+        let (chunk_was_full, chunk_is_empty) = {
+            let region = self.region_at_index_mut(region_index);
+            assert!(region.is_chunk() && region.size != 0);
+
+            let was_full = region.is_full_chunk();
+
+            try!(region.free_chunk_slot(offset));
+
+            let is_empty = region.is_empty_chunk();
+            (was_full, is_empty)
+        };
+
+        if chunk_was_full {
+            try!(self.free_chunk_insert(region_index));
+        }
+
+        if chunk_is_empty {
+            try!(self.free_chunk_remove(region_index));
+        }
+        Ok(())
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8) -> Result<(), MallocError> {
+        self.free_object(ptr, false)
+    }
+
+    // Deallocate `ptr`, `wiped` if it was freed and wiped by another
+    // thread, the canary of its chunk slot is then gone.
+    unsafe fn free_object(&mut self, ptr: *mut u8,
+                          wiped: bool) -> Result<(), MallocError> {
+        if ptr.is_null() {
+            return Ok(());
+        }
+
+        if !self.check_integrity() {
+            return Err(MallocError::IntegrityViolation);
+        }
+        self.trace_event(TraceOp::Free, ptr, 0, None);
+
+        // Potentially signals a double free in case a region is not found
+        // neither here nor in another directory.
+        let region_index = match self.region_find(ptr) {
+            Some(index) => index,
+            None => {
+                let page = mmap::mask_pointer(ptr) as usize;
+                if registry::free_remote(self.id, page, ptr as usize) ||
+                   registry::free_remote(self.id, span_base(ptr) as usize,
+                                         ptr as usize) {
+                    return Ok(());
+                }
+                return Err(MallocError::UnknownPointer);
+            }
+        };
+
+        let region = &mut *self.regions.offset(region_index.to_isize().unwrap());
+        if !region.check_integrity(self.canary2) {
+            return Err(MallocError::IntegrityViolation);
+        }
+
+        match region.kind {
+            RegionType::Chunk => {
+                // Kind of static chunk, never deallocated.
+                if region.size == 0 {
+                    return Ok(());
+                }
+
+                let chunk_offset = try!((ptr as usize).checked_sub(
+                    region.object as usize).ok_or(MallocError::UnknownPointer));
+
+                // Detect overflows before the slot is wiped.
+                try!(region.used_slot_index(chunk_offset));
+                if self.in_quarantine(ptr) {
+                    return Err(MallocError::DoubleFree);
+                }
+                if !wiped {
+                    try!(self.check_canary(region, chunk_offset));
+                }
+                self.stats_slots(region.size, 1, false);
+                self.usage_credit(region.size, 0);
+
+                if options().quarantine > 0 {
+                    // Wiped now but still unallocatable.
+                    utils::set_memory(ptr, fill_byte_dealloc().unwrap(),
+                                      region.size);
+                    return self.quarantine_insert(ptr);
+                }
+
+                try!(self.release_chunk_slot(region_index, chunk_offset));
+            },
+            RegionType::Span => {
+                let offset = try!((ptr as usize).checked_sub(
+                    region.object as usize).ok_or(MallocError::UnknownPointer));
+                try!(region.used_slot_index(offset));
+                if self.in_quarantine(ptr) {
+                    return Err(MallocError::DoubleFree);
+                }
+
+                // Destroyed now, unmapped with its span once all the slots
+                // are freed.
+                let size = region.size;
+                try!(mmap::tombstone(ptr, size, fill_byte_dealloc()));
+                self.stats_mediums(size, 1, false);
+                self.usage_credit(size, 0);
+
+                if options().quarantine > 0 {
+                    return self.quarantine_insert(ptr);
+                }
+
+                try!(self.release_span_slot(region_index, offset));
+            },
+            RegionType::Large | RegionType::Key => {
+                if region.object != ptr {
+                    return Err(MallocError::UnknownPointer);
+                }
+                let (size, key) = (region.size, region.is_key());
+
+                if options().quarantine > 0 {
+                    self.region_register(ptr, Layout::none());
+                    try!(mmap::tombstone(ptr, size, fill_byte_dealloc()));
+                    region.kind = RegionType::Tomb;
+                    self.stats_large(size, key, false);
+                    self.usage_credit(size, 0);
+                    return self.quarantine_insert(ptr);
+                }
+
+                self.stats_large(size, key, false);
+                self.usage_credit(size, 0);
+                if try!(self.spare_insert(region_index)) {
+                    return Ok(());
+                }
+                try!(region.dealloc_data(false));
+                self.region_delete(region_index);
+                self.usage_credit(0, mapping_pages(size));
+            },
+            // Pointer into an empty cached chunk, all its slots were freed,
+            // or into a quarantined or spare large object.
+            RegionType::Cache | RegionType::Tomb | RegionType::Spare =>
+                return Err(MallocError::DoubleFree),
+            RegionType::Free => unreachable!()
+        }
+        Ok(())
+    }
+
+    // Free the slot at `offset` in the chunk of region `region_index`, the
+    // chunk is then cached or unmapped if it is empty.
+    unsafe fn release_chunk_slot(&mut self, region_index: usize,
+                                 offset: usize) -> Result<(), MallocError> {
+        let region = self.regions.offset(region_index.to_isize().unwrap());
+
+        try!(self.free_chunk_slot(region_index, offset));
+
+        if (*region).is_empty_chunk() {
+            if self.can_cache_chunk() {
+                // Cache region and its chunk object.
+                try!(self.cache_chunk_insert(region_index));
+                if let Some(counters) = self.counters() {
+                    stat_update(&counters.cached, 1, true);
+                }
+            } else {
+                // Delete object and regions's metadata.
+                try!((*region).dealloc_data(false));
+                self.region_delete(region_index);
+                self.stats_mapped(mmap::page_size(), false);
+                self.usage_credit(0, mapping_pages(mmap::page_size()));
+            }
+        }
+        Ok(())
+    }
+
+    // Reuse a spare mapping of the number of pages needed by `size` bytes
+    // and aligned on `align` for a large object or a key of kind `kind`,
+    // set up like by `alloc`. Return `None` if no such mapping is cached.
+    unsafe fn spare_take(&mut self, size: usize, align: usize,
+                         zero_fill: bool, prot: Prot, pos: RangePos,
+                         kind: RegionType)
+                         -> Result<Option<*mut u8>, MallocError> {
+        let region_sz = try!(mmap::page_round(size)
+                             .ok_or(MallocError::Overflow));
+        let canary_dir = self.canary2;
+
+        // Start at random among mappings of the same size.
+        let start = self.rng.gen_range(0_usize, MAX_SPARES);
+        for i in (0_usize..MAX_SPARES).map(|i| (start + i) % MAX_SPARES) {
+            let spare = self.spares[i];
+            if spare.is_null() {
+                continue;
+            }
+
+            let region_index = try!(self.region_find(spare)
+                                    .ok_or(MallocError::IntegrityViolation));
+            let region = self.regions.offset(region_index.to_isize().unwrap());
+            if !(*region).check_integrity(canary_dir) {
+                return Err(MallocError::IntegrityViolation);
+            }
+            if (*region).size != region_sz ||
+                (align > 0 && spare as usize & (align - 1) != 0) {
+                continue;
+            }
+
+            self.spares[i] = ptr::null_mut();
+            self.spare_pages -= region_sz / mmap::page_size();
+            self.stats_spare(region_sz, false);
+
+            let object = match mmap::reuse(spare, size,
+                                           fill_byte_alloc(zero_fill), prot,
+                                           pos) {
+                Ok(object) => object,
+                Err(err) => {
+                    let _ = (*region).dealloc_data(false);
+                    self.region_delete(region_index);
+                    return Err(From::from(err));
+                }
+            };
+            // Still located in the same first page, its hash is unchanged.
+            (*region).init(object, size, kind, canary_dir);
+            (*region).prot = prot;
+            self.region_register(object, (*region).remote_layout());
+
+            if let Some(counters) = self.counters() {
+                stat_update(&counters.spare_hits, 1, true);
+            }
+            return Ok(Some(object));
+        }
+        Ok(None)
+    }
+
+    // Free the slot at `offset` in the span of region `region_index`, its
+    // pages must be inaccessible already. The span is unmapped if it is
+    // empty.
+    unsafe fn release_span_slot(&mut self, region_index: usize,
+                                offset: usize) -> Result<(), MallocError> {
+        let region = self.regions.offset(region_index.to_isize().unwrap());
+
+        let was_full = (*region).is_full_chunk();
+        try!((*region).free_slot(offset));
+        if was_full {
+            try!(self.free_span_insert(region_index));
+        }
+
+        if (*region).is_empty_chunk() {
+            let size = span_size((*region).size);
+            try!(self.free_span_remove(region_index));
+            try!((*region).dealloc_data(false));
+            self.region_delete(region_index);
+            self.stats_span(false);
+            self.usage_credit(0, mapping_pages(size));
+        }
+        Ok(())
+    }
+
+    // Keep the mapping of the large object or key of region `region_index`
+    // for reuse, wiped and inaccessible. Spare mappings are released at
+    // random to make room for it. Return `false` if it can't be kept.
+    unsafe fn spare_insert(&mut self,
+                           region_index: usize) -> Result<bool, MallocError> {
+        let canary_dir = self.canary2;
+        let region = self.regions.offset(region_index.to_isize().unwrap());
+        let region_sz = try!(mmap::page_round((*region).size)
+                             .ok_or(MallocError::Overflow));
+        let pages = region_sz / mmap::page_size();
+
+        if pages > options().spare_pages {
+            return Ok(false);
+        }
+        while self.spare_pages + pages > options().spare_pages ||
+              self.spares.iter().all(|spare| !spare.is_null()) {
+            let index = self.rng.gen_range(0_usize, MAX_SPARES);
+            if !self.spares[index].is_null() {
+                try!(self.spare_release(index));
+            }
+        }
+
+        let page = mmap::mask_pointer((*region).object);
+        self.region_register(page, Layout::none());
+        try!(mmap::retire(page, region_sz, fill_byte_dealloc()));
+        (*region).init(page, region_sz, RegionType::Spare, canary_dir);
+        (*region).prot = Prot::None;
+
+        let index = self.spares.iter().position(|spare| spare.is_null())
+            .unwrap();
+        self.spares[index] = page;
+        self.spare_pages += pages;
+        self.stats_spare(region_sz, true);
+        Ok(true)
+    }
+
+    // Unmap the spare mapping at `index` in the spares.
+    unsafe fn spare_release(&mut self,
+                            index: usize) -> Result<(), MallocError> {
+        let spare = mem::replace(&mut self.spares[index], ptr::null_mut());
+        let region_index = try!(self.region_find(spare)
+                                .ok_or(MallocError::IntegrityViolation));
+        let region = self.regions.offset(region_index.to_isize().unwrap());
+        if !(*region).check_integrity(self.canary2) {
+            return Err(MallocError::IntegrityViolation);
+        }
+
+        let size = (*region).size;
+        self.spare_pages -= size / mmap::page_size();
+        try!((*region).dealloc_data(false));
+        self.region_delete(region_index);
+        self.stats_spare(size, false);
+        self.usage_credit(0, mapping_pages(size));
+        Ok(())
+    }
+
+    // Unmap the cached chunks and the spare mappings, then shrink the
+    // regions table to the regions left while keeping it as sparse as
+    // `regions_grow` does. Return the number of pages released.
+    pub unsafe fn trim(&mut self) -> Result<usize, MallocError> {
+        if !self.check_integrity() {
+            return Err(MallocError::IntegrityViolation);
+        }
+        self.trim_epoch = TRIM_EPOCH.load(Ordering::SeqCst);
+        let used_pages = self.used_pages;
+
+        while self.has_cached_chunk() {
+            let region_index = self.cache1;
+            try!(self.cache_chunk_release(region_index));
+        }
+        for index in 0_usize..MAX_SPARES {
+            if !self.spares[index].is_null() {
+                try!(self.spare_release(index));
+            }
+        }
+
+        let mut count = self.total;
+        while count / 2 >= INITIAL_REGIONS &&
+              self.regions_used().checked_mul(2).unwrap() <= count / 2 {
+            count /= 2;
+        }
+        if count < self.total {
+            try!(self.regions_realloc(count));
+        }
+        Ok(used_pages.saturating_sub(self.used_pages))
+    }
+
+    fn in_quarantine(&self, ptr: *mut u8) -> bool {
+        self.quarantine[..options().quarantine].iter().any(|&p| p == ptr)
+    }
+
+    // Put `ptr` in quarantine in place of a random entry, which is then
+    // really deallocated.
+    unsafe fn quarantine_insert(&mut self,
+                                ptr: *mut u8) -> Result<(), MallocError> {
+        let index = self.rng.gen_range(0_usize, options().quarantine);
+        let evicted = mem::replace(&mut self.quarantine[index], ptr);
+        if evicted.is_null() {
+            return Ok(());
+        }
+        self.evict(evicted)
+    }
+
+    // Really deallocate `ptr`, a pointer taken out of quarantine.
+    unsafe fn evict(&mut self, ptr: *mut u8) -> Result<(), MallocError> {
+        let region_index = try!(self.region_find(ptr)
+                                .ok_or(MallocError::IntegrityViolation));
+        let region = self.regions.offset(region_index.to_isize().unwrap());
+        if !(*region).check_integrity(self.canary2) {
+            return Err(MallocError::IntegrityViolation);
+        }
+
+        match (*region).kind {
+            RegionType::Chunk => {
+                let offset = ptr as usize - (*region).object as usize;
+                try!(check_junk((*region).object, offset, (*region).size,
+                                (*region).size));
+                self.release_chunk_slot(region_index, offset)
+            },
+            RegionType::Span => {
+                let offset = ptr as usize - (*region).object as usize;
+                self.release_span_slot(region_index, offset)
+            },
+            RegionType::Tomb => {
+                let size = (*region).size;
+                try!((*region).dealloc_data(false));
+                self.region_delete(region_index);
+                self.usage_credit(0, mapping_pages(size));
+                Ok(())
+            },
+            _ => Err(MallocError::IntegrityViolation)
+        }
+    }
+
+    // Deallocate all the pointers in quarantine. All of them are processed,
+    // the first error is returned.
+    pub unsafe fn flush_quarantine(&mut self) -> Result<(), MallocError> {
+        let mut res = Ok(());
+        for i in 0_usize..MAX_QUARANTINE {
+            let ptr = mem::replace(&mut self.quarantine[i], ptr::null_mut());
+            if !ptr.is_null() {
+                let rv = self.evict(ptr);
+                if res.is_ok() {
+                    res = rv;
+                }
+            }
+        }
+        res
+    }
+
+    pub unsafe fn protect(&mut self, ptr: *mut u8,
+                          prot: Prot) -> Result<(), MallocError> {
+        if ptr.is_null() {
+            return Ok(());
+        }
+
+        if !self.check_integrity() {
+            return Err(MallocError::IntegrityViolation);
+        }
+        self.trace_event(TraceOp::Protect, ptr, 0, Some(prot));
+
+        if let Some(counters) = self.counters() {
+            match prot {
+                Prot::Read => stat_update(&counters.prot_reads, 1, true),
+                Prot::Write => stat_update(&counters.prot_writes, 1, true),
+                Prot::None => stat_update(&counters.prot_nones, 1, true),
+                _ => ()
+            }
+        }
+
+        let canary_dir = self.canary2;
+        let region_index = try!(self.region_find(ptr)
+                                .ok_or(MallocError::UnknownPointer));
+        let region = self.region_at_index_mut(region_index);
+
+        if !region.check_integrity(canary_dir) {
+            return Err(MallocError::IntegrityViolation);
+        }
+        // Only live large objects can have their protections changed.
+        match region.kind {
+            RegionType::Large | RegionType::Key if region.object == ptr => (),
+            _ => return Err(MallocError::UnknownPointer)
+        }
+
+        try!(mmap::protect(region.object, region.size, prot));
+        region.prot = prot;
+        Ok(())
+    }
+
+    // Walk all the regions and lists and cross-check them.
+    fn check_heap(&self) -> HeapReport {
+        let mut report = HeapReport {
+            regions: 0,
+            errors: Vec::new()
+        };
+
+        if !self.check_integrity() {
+            report.errors.push("directory canary is corrupted".to_string());
+            return report;
+        }
+        if !self.total.is_power_of_two() || self.free > self.total {
+            report.errors.push(format!("invalid regions counts: total {}, \
+                                        free {}", self.total, self.free));
+            return report;
+        }
+
+        // Lists membership of each region.
+        let mut listed = vec![false; self.total];
+        for &(index, _) in [(0, 0)].iter().chain(size_classes().iter()) {
+            let name = format!("chunks list {}", index);
+            for i in self.check_list(&name, self.chunks1[index],
+                                     self.chunks2[index], &mut report) {
+                let region = self.region_at_index(i);
+                if !region.is_chunk() || chunk_index(region.size) != index {
+                    report.errors.push(format!("{}: region {} is not a chunk \
+                                                of this size-class", name, i));
+                }
+                listed[i] = true;
+            }
+        }
+        for &(index, _) in medium_classes().iter() {
+            let name = format!("spans list {}", index);
+            for i in self.check_list(&name, self.mediums1[index],
+                                     self.mediums2[index], &mut report) {
+                let region = self.region_at_index(i);
+                if !region.is_span() || medium_index(region.size) != index {
+                    report.errors.push(format!("{}: region {} is not a span \
+                                                of this size-class", name, i));
+                }
+                listed[i] = true;
+            }
+        }
+        let cached = self.check_list("cache list", self.cache1, self.cache2,
+                                     &mut report);
+        if cached.len() != self.cache_len {
+            report.errors.push(format!("cache list: {} chunks instead of {}",
+                                       cached.len(), self.cache_len));
+        }
+        for &i in cached.iter() {
+            if self.region_at_index(i).kind as usize !=
+                RegionType::Cache as usize {
+                report.errors.push(format!("cache list: region {} is not \
+                                            cached", i));
+            }
+            listed[i] = true;
+        }
+
+        let mut free = 0_usize;
+        let mut spare_pages = 0_usize;
+        for i in 0_usize..self.total {
+            let region = self.region_at_index(i);
+            if region.is_free() {
+                free += 1;
+                continue;
+            }
+            report.regions += 1;
+
+            if !region.check_integrity(self.canary2) {
+                report.errors.push(format!("region {}: canary is corrupted",
+                                           i));
+                continue;
+            }
+            if self.region_find(region.object) != Some(i) {
+                report.errors.push(format!("region {}: not reachable from \
+                                            its hash index", i));
+            }
+
+            match region.kind {
+                RegionType::Chunk if region.size != 0 => {
+                    self.check_chunk(i, listed[i], &mut report);
+                },
+                // Static chunks of adopted directories are never listed.
+                RegionType::Chunk => (),
+                RegionType::Span => self.check_span(i, listed[i], &mut report),
+                RegionType::Cache => {
+                    if !listed[i] {
+                        report.errors.push(format!("region {}: cached chunk \
+                                                    is not listed", i));
+                    }
+                },
+                RegionType::Tomb => {
+                    if !self.in_quarantine(region.object) {
+                        report.errors.push(format!("region {}: tombstone not \
+                                                    in quarantine", i));
+                    }
+                },
+                RegionType::Spare => {
+                    if !self.spares.contains(&region.object) {
+                        report.errors.push(format!("region {}: spare mapping \
+                                                    not in spares", i));
+                    }
+                    spare_pages += region.size / mmap::page_size();
+                },
+                _ => {
+                    if listed[i] {
+                        report.errors.push(format!("region {}: large object \
+                                                    is listed", i));
+                    }
+                }
+            }
+        }
+
+        if free != self.free {
+            report.errors.push(format!("{} free regions instead of {}", free,
+                                       self.free));
+        }
+        if spare_pages != self.spare_pages {
+            report.errors.push(format!("{} spare pages instead of {}",
+                                       spare_pages, self.spare_pages));
+        }
+
+        for &ptr in self.quarantine[..options().quarantine].iter() {
+            if ptr.is_null() {
+                continue;
+            }
+            let sound = self.region_find(ptr).map_or(false, |i| {
+                let region = self.region_at_index(i);
+                match region.kind {
+                    RegionType::Chunk | RegionType::Span if region.size != 0 =>
+                        region.used_slot_index(ptr as usize -
+                                               region.object as usize)
+                        .is_ok(),
+                    RegionType::Tomb => true,
+                    _ => false
+                }
+            });
+            if !sound {
+                report.errors.push(format!("quarantine: invalid pointer {:?}",
+                                           ptr));
+            }
+        }
+
+        report
+    }
+
+    // Walk the list from `start` to `end` and return the indexes of its
+    // regions.
+    fn check_list(&self, name: &str, start: usize, end: usize,
+                  report: &mut HeapReport) -> Vec<usize> {
+        let mut members = Vec::new();
+        let mut prev = NO_REGION;
+        let mut index = start;
+
+        while index != NO_REGION {
+            if members.len() >= self.total {
+                report.errors.push(format!("{}: cycle detected", name));
+                return members;
+            }
+            if index >= self.total || self.region_at_index(index).is_free() {
+                report.errors.push(format!("{}: invalid region {}", name,
+                                           index));
+                return members;
+            }
+            let region = self.region_at_index(index);
+            if region.prev != prev {
+                report.errors.push(format!("{}: broken back link of region \
+                                            {}", name, index));
+            }
+            members.push(index);
+            prev = index;
+            index = region.next;
+        }
+
+        if prev != end {
+            report.errors.push(format!("{}: ends at region {} instead of {}",
+                                       name, prev, end));
+        }
+        members
+    }
+
+    // Check bitmap, list membership and slots of chunk `index`.
+    fn check_chunk(&self, index: usize, listed: bool,
+                   report: &mut HeapReport) {
+        let region = self.region_at_index(index);
+        let size = region.size;
+
+        if size < min_chunk_size() || size > max_chunk_size() ||
+           chunk_size(size) != size {
+            report.errors.push(format!("region {}: invalid size-class {}",
+                                       index, size));
+            return;
+        }
+        if let Err(err) = region.check_mapping() {
+            report.errors.push(format!("region {}: {}", index, err));
+        }
+        if listed == region.is_full_chunk() {
+            report.errors.push(format!("region {}: full chunk listed or \
+                                        chunk with free slots not listed",
+                                       index));
+        }
+
+        for i in 0_usize..max_slot_index(size) {
+            let offset = i * size;
+            let slot = unsafe { region.object.offset(offset as isize) };
+            if region.chunk_slot_is_free(i) {
+                if let Err(err) = unsafe {
+                    check_junk(region.object, offset, size, size)
+                } {
+                    report.errors.push(format!("region {}: {}", index, err));
+                }
+            } else if !self.in_quarantine(slot) {
+                if let Err(err) = unsafe { self.check_canary(region, offset) } {
+                    report.errors.push(format!("region {}: {}", index, err));
+                }
+            }
+        }
+    }
+
+    // Check size-class, location, bitmap and list membership of span
+    // `index`.
+    fn check_span(&self, index: usize, listed: bool,
+                  report: &mut HeapReport) {
+        let region = self.region_at_index(index);
+        let size = region.size;
+
+        if !medium_classes().iter().any(|&(_, class)| class == size) {
+            report.errors.push(format!("region {}: invalid medium size-class \
+                                        {}", index, size));
+            return;
+        }
+        if span_base(region.object) != region.object {
+            report.errors.push(format!("region {}: misaligned span", index));
+        }
+        if let Err(err) = region.check_mapping() {
+            report.errors.push(format!("region {}: {}", index, err));
+        }
+        if region.is_empty_chunk() {
+            report.errors.push(format!("region {}: empty span still mapped",
+                                       index));
+        }
+        if listed == region.is_full_chunk() {
+            report.errors.push(format!("region {}: full span listed or span \
+                                        with free slots not listed", index));
+        }
+    }
+
+    // Check the canary of the slot in use at `offset` in the chunk of
+    // `region`.
+    unsafe fn check_canary(&self, region: &Region,
+                           offset: usize) -> Result<(), MallocError> {
+        if !options().canaries {
+            return Ok(());
+        }
+
+        let slot = region.object.offset(offset as isize);
+        match canary_tail(slot, region.size, self.canary_keys) {
+            Some(_) => Ok(()),
+            None => Err(MallocError::SlotOverflow {
+                slot: slot as usize,
+                size_class: region.size
+            })
+        }
+    }
+
+    // Key the canary of `slot` previously keyed by `keys` with this
+    // directory's keys. A corrupted canary is left as is to be reported
+    // later.
+    unsafe fn rekey_canary(&self, slot: *mut u8, chunk_size: usize,
+                           keys: (u64, u64)) {
+        if let Some(tail) = canary_tail(slot, chunk_size, keys) {
+            write_canary(slot, chunk_size, chunk_size - tail,
+                         self.canary_keys);
+        }
+    }
+
+    // Return statistics counters if statistics are collected.
+    #[inline]
+    fn counters(&self) -> Option<&Counters> {
+        if self.stats {
+            Some(unsafe { &(*self.shared).counters })
+        } else {
+            None
+        }
+    }
+
+    // Charge `bytes` and `pages` to this directory and to the process,
+    // nothing is charged if this goes over a quota.
+    fn usage_charge(&mut self, bytes: usize,
+                    pages: usize) -> Result<(), MallocError> {
+        if exceeds(self.used_bytes, bytes, self.quota.bytes) ||
+           exceeds(self.used_pages, pages, self.quota.pages) {
+            return Err(MallocError::QuotaExceeded);
+        }
+
+        let quota = process_quota();
+        let used_bytes = PROCESS_BYTES.fetch_add(bytes, Ordering::SeqCst);
+        let used_pages = PROCESS_PAGES.fetch_add(pages, Ordering::SeqCst);
+        if exceeds(used_bytes, bytes, quota.bytes) ||
+           exceeds(used_pages, pages, quota.pages) {
+            PROCESS_BYTES.fetch_sub(bytes, Ordering::SeqCst);
+            PROCESS_PAGES.fetch_sub(pages, Ordering::SeqCst);
+            return Err(MallocError::QuotaExceeded);
+        }
+
+        self.used_bytes += bytes;
+        self.used_pages += pages;
+        Ok(())
+    }
+
+    // Release `bytes` and `pages` charged by `usage_charge`.
+    fn usage_credit(&mut self, bytes: usize, pages: usize) {
+        self.used_bytes -= bytes;
+        self.used_pages -= pages;
+        PROCESS_BYTES.fetch_sub(bytes, Ordering::SeqCst);
+        PROCESS_PAGES.fetch_sub(pages, Ordering::SeqCst);
+    }
+
+    // Account for the mapping or unmapping of `size` bytes.
+    fn stats_mapped(&self, size: usize, add: bool) {
+        if let Some(counters) = self.counters() {
+            stat_update(&counters.locked_bytes, locked_size(size), add);
+        }
+    }
+
+    // Account for a large object or a key of `size` bytes.
+    fn stats_large(&self, size: usize, key: bool, add: bool) {
+        if let Some(counters) = self.counters() {
+            stat_update(&counters.larges, 1, add);
+            stat_update(&counters.larges_bytes, size, add);
+            if key {
+                stat_update(&counters.keys, 1, add);
+            }
+        }
+        self.stats_mapped(size, add);
+    }
+
+    // Account for a spare mapping of `size` bytes, still locked.
+    fn stats_spare(&self, size: usize, add: bool) {
+        if let Some(counters) = self.counters() {
+            stat_update(&counters.spares, 1, add);
+        }
+        self.stats_mapped(size, add);
+    }
+
+    // Account for a span of medium objects, its pages are only locked
+    // while its slots are in use.
+    fn stats_span(&self, add: bool) {
+        if let Some(counters) = self.counters() {
+            stat_update(&counters.spans, 1, add);
+        }
+    }
+
+    // Account for `count` medium objects of size-class `medium_size`.
+    fn stats_mediums(&self, medium_size: usize, count: usize, add: bool) {
+        if let Some(counters) = self.counters() {
+            stat_update(&counters.mediums, count, add);
+            stat_update(&counters.classes[CHUNK_CLASSES +
+                                          medium_index(medium_size)],
+                        count, add);
+        }
+        self.stats_mapped(medium_size * count, add);
+    }
+
+    // Account for `count` slots of size-class `chunk_size`.
+    fn stats_slots(&self, chunk_size: usize, count: usize, add: bool) {
+        if let Some(counters) = self.counters() {
+            stat_update(&counters.chunks, count, add);
+            stat_update(&counters.classes[chunk_index(chunk_size)], count,
+                        add);
+        }
+    }
+
+    fn stats_snapshot(&self) -> Option<Stats> {
+        self.counters().map(|counters| {
+            let mut stats = Stats::new();
+            stats.accumulate(counters);
+            stats
+        })
+    }
+
+    // Record `op` on the object at `ptr` of `size` bytes if tracing is
+    // enabled.
+    fn trace_event(&mut self, op: TraceOp, ptr: *mut u8, size: usize,
+                   prot: Option<Prot>) {
+        if !options().trace || ptr.is_null() {
+            return;
+        }
+
+        let region = self.region_find(ptr);
+        let size = region.map(|index| self.region_at_index(index).size)
+            .unwrap_or(size);
+        self.trace.record(TraceEvent {
+            op: op,
+            address: ptr as usize,
+            size: size,
+            region: region,
+            prot: prot
+        });
+    }
+
+    fn heap_snapshot(&self) -> HeapSnapshot {
+        let mut regions: Vec<RegionSnapshot> = (0_usize..self.total)
+            .filter(|&index| !self.region_at_index(index).is_free())
+            .map(|index| self.region_at_index(index).snapshot(index))
+            .collect();
+        regions.sort_by(|a, b| a.address.cmp(&b.address));
+        HeapSnapshot {
+            total: self.total,
+            regions: regions
+        }
+    }
+}
+
+impl Debug for Dir {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        try!(write!(fmt, "Current state:\n"));
+
+        assert!(!self.regions.is_null());
+        let mut num_free = 0_usize;
+        let mut num_chunk = 0_usize;
+        let mut num_large = 0_usize;
+        let mut num_key = 0_usize;
+        let mut num_cache = 0_usize;
+        let mut num_tomb = 0_usize;
+        let mut num_spare = 0_usize;
+        let mut num_span = 0_usize;
+        for i in 0_usize..self.total {
+            let region = self.region_at_index(i);
+
+            match region.kind {
+                RegionType::Free => num_free += 1,
+                RegionType::Chunk => num_chunk += 1,
+                RegionType::Large => num_large += 1,
+                RegionType::Key => num_key += 1,
+                RegionType::Cache => num_cache += 1,
+                RegionType::Tomb => num_tomb += 1,
+                RegionType::Spare => num_spare += 1,
+                RegionType::Span => num_span += 1
+            }
+        }
+        try!(write!(fmt, "regions:\n"));
+        try!(write!(fmt, "total:         {}\n", self.total));
+        try!(write!(fmt, "free:          {}\n",  num_free));
+        try!(write!(fmt, "chunks:        {}\n", num_chunk));
+        try!(write!(fmt, "large objects: {}\n", num_large));
+        try!(write!(fmt, "keys:          {}\n", num_key));
+        try!(write!(fmt, "cached chunks: {}\n", num_cache));
+        try!(write!(fmt, "tombstones:    {}\n", num_tomb));
+        try!(write!(fmt, "spares:        {}\n", num_spare));
+        try!(write!(fmt, "spans:         {}\n", num_span));
+
+        try!(write!(fmt, "chunks:\n"));
+        for &(i, size) in [(0, 0)].iter().chain(size_classes().iter()) {
+            if self.chunks1[i] == NO_REGION {
+                try!(write!(fmt, "chunk size: {:<5} -> empty\n", size));
+            } else {
+                let mut l: usize = 0;
+                let mut index = self.chunks1[i];
+                while index != NO_REGION {
+                    l += 1;
+                    index = self.region_at_index(index).next;
+                }
+                try!(write!(fmt, "chunk size: {:<5} -> free chunks: {}\n",
+                            size, l));
+            }
+        }
+
+        if let Some(stats) = self.stats_snapshot() {
+            try!(write!(fmt, "\n{:?}\n", stats));
+        }
+
+        Ok(())
+    }
+}
+
+
+impl Region {
+    fn init(&mut self, object: *mut u8, size: usize, kind: RegionType,
+            canary_dir: usize) {
+        assert!(!object.is_null());
+        self.object = object;
+        self.canary = canary_dir ^ object as usize;
+        self.kind = kind;
+        self.prot = Prot::ReadWrite;
+        self.size = size;
+        self.next = NO_REGION;
+        self.prev = NO_REGION;
+
+        if self.is_chunk() || self.is_span() {
+            self.init_chunk();
+        }
+    }
+
+    fn init_chunk(&mut self) {
+        if self.size == 0 {
+            return;
+        }
+
+        let max_index = self.slot_count();
+
+        // Cached chunks may be reused for another size-class.
+        self.mapping = [0; MAPPING_WORDS];
+        for i in 0_usize..max_index / WORD_BITS {
+            self.mapping[i] = !0;
+        }
+        if max_index % WORD_BITS != 0 {
+            self.mapping[max_index / WORD_BITS] =
+                (1 << (max_index % WORD_BITS)) - 1;
+        }
+        self.free_slots = max_index;
+    }
+
+    #[inline]
+    fn check_integrity(&self, canary_dir: usize) -> bool {
+        !self.is_free() && self.canary == canary_dir ^ self.object as usize
+    }
+
+    unsafe fn set_as_cache(&mut self) -> Result<(), MallocError> {
+        assert_eq!(self.kind as usize, RegionType::Chunk as usize);
+
+        self.kind = RegionType::Cache;
+        self.size = 0;
+
+        assert!(!self.object.is_null());
+        Ok(try!(mmap::protect(self.object, mmap::page_size(), Prot::None)))
+    }
+
+    unsafe fn set_as_chunk(&mut self,
+                           chunk_size: usize) -> Result<(), MallocError> {
+        assert_eq!(self.kind as usize, RegionType::Cache as usize);
+
+        self.kind = RegionType::Chunk;
+        self.size = chunk_size;
+        self.init_chunk();
+
+        assert!(!self.object.is_null());
+        Ok(try!(mmap::protect(self.object, mmap::page_size(),
+                              Prot::ReadWrite)))
+    }
+
+    // Layout of the objects wiped by other threads freeing them. Keys may
+    // be inaccessible and the other kinds hold no live object, they are
+    // never wiped. The slot of a medium object is inaccessible once freed,
+    // freeing it again from another thread faults.
+    fn remote_layout(&self) -> Layout {
+        match self.kind {
+            RegionType::Chunk | RegionType::Span if self.size != 0 =>
+                Layout {
+                    object: self.object as usize,
+                    size: self.size,
+                    stride: self.slot_stride(),
+                    count: self.slot_count()
+                },
+            RegionType::Large => Layout {
+                object: self.object as usize,
+                size: self.size,
+                stride: self.size,
+                count: 1
+            },
+            _ => Layout::none()
+        }
+    }
+
+    fn set_as_free(&mut self) {
+        self.object = ptr::null_mut();
+        self.kind = RegionType::Free;
+    }
+
+    #[inline]
+    fn is_free(&self) -> bool {
+        debug_assert_eq!(self.object.is_null(),
+                         (self.kind as usize == RegionType::Free as usize));
+        self.kind as usize == RegionType::Free as usize
+    }
+
+    #[inline]
+    fn is_chunk(&self) -> bool {
+        self.kind as usize == RegionType::Chunk as usize
+    }
+
+    #[inline]
+    fn is_key(&self) -> bool {
+        self.kind as usize == RegionType::Key as usize
+    }
+
+    #[inline]
+    fn is_span(&self) -> bool {
+        self.kind as usize == RegionType::Span as usize
+    }
+
+    // Number of slots of a chunk or a span.
+    #[inline]
+    fn slot_count(&self) -> usize {
+        if self.is_span() {
+            span_slots(self.size)
+        } else {
+            max_slot_index(self.size)
+        }
+    }
+
+    // Offset between two consecutive slots of a chunk or a span.
+    #[inline]
+    fn slot_stride(&self) -> usize {
+        if self.is_span() {
+            self.size + mmap::page_size()
+        } else {
+            self.size
+        }
+    }
+
+    #[inline]
+    fn is_full_chunk(&self) -> bool {
+        // Check this region represents a valid chunk or span.
+        debug_assert!((self.is_chunk() || self.is_span()) && self.size != 0);
+        self.free_slots == 0
+    }
+
+    #[inline]
+    fn is_empty_chunk(&self) -> bool {
+        debug_assert!((self.is_chunk() || self.is_span()) && self.size != 0);
+        self.free_slots == self.slot_count()
+    }
+
+    // Check that the bitmap only marks existing slots as free and that it
+    // agrees with the free slots counter.
+    fn check_mapping(&self) -> Result<(), String> {
+        let max_slot_index = self.slot_count();
+        if (max_slot_index..MAPPING_WORDS * WORD_BITS).any(|i| {
+            self.mapping[i / WORD_BITS] & (1 << (i % WORD_BITS)) != 0
+        }) {
+            return Err("bitmap marks missing slots as free".to_string());
+        }
+
+        let free: usize = self.mapping.iter()
+            .map(|word| word.count_ones().to_usize().unwrap()).sum();
+        if self.free_slots != free {
+            return Err(format!("{} free slots counted but {} marked as free \
+                                in bitmap", self.free_slots, free));
+        }
+        Ok(())
+    }
+
+    // Number of pages mapped for its object.
+    fn mapped_pages(&self) -> usize {
+        match self.kind {
+            RegionType::Chunk | RegionType::Cache =>
+                mapping_pages(mmap::page_size()),
+            RegionType::Span => mapping_pages(span_size(self.size)),
+            _ => mapping_pages(self.size)
+        }
+    }
+
+    // State of this region located at `index` in the regions table.
+    fn snapshot(&self, index: usize) -> RegionSnapshot {
+        let slotted = (self.is_chunk() || self.is_span()) && self.size != 0;
+        let (kind, prot) = match self.kind {
+            RegionType::Chunk if self.size == 0 =>
+                (RegionKind::Chunk, Prot::None),
+            RegionType::Chunk => (RegionKind::Chunk, Prot::ReadWrite),
+            RegionType::Large => (RegionKind::Large, self.prot),
+            RegionType::Key => (RegionKind::Key, self.prot),
+            RegionType::Cache => (RegionKind::Cache, Prot::None),
+            RegionType::Tomb => (RegionKind::Tomb, Prot::None),
+            RegionType::Spare => (RegionKind::Spare, Prot::None),
+            RegionType::Span => (RegionKind::Span, Prot::ReadWrite),
+            RegionType::Free => unreachable!()
+        };
+        let occupancy = if slotted {
+            (0_usize..self.slot_count())
+                .map(|i| !self.chunk_slot_is_free(i)).collect()
+        } else {
+            Vec::new()
+        };
+        RegionSnapshot {
+            index: index,
+            kind: kind,
+            address: mmap::mask_pointer(self.object) as usize,
+            size: self.size,
+            pages: self.mapped_pages(),
+            slots: if slotted { self.slot_count() } else { 0 },
+            used_slots: if slotted { self.used_slots() } else { 0 },
+            occupancy: occupancy,
+            prot: prot
+        }
+    }
+
+    // Number of slots in use.
+    #[inline]
+    fn used_slots(&self) -> usize {
+        self.slot_count() - self.free_slots
+    }
+
+    #[inline]
+    fn chunk_slot_is_free(&self, index: usize) -> bool {
+        debug_assert!(index < self.slot_count());
+        self.mapping[index / WORD_BITS] & (1 << (index % WORD_BITS)) != 0
+    }
+
+    // Index of the first free slot from `index` down to the first slot.
+    fn prev_free_slot(&self, index: usize) -> Option<usize> {
+        let mut word = index / WORD_BITS;
+        // Ignore the slots above index in its word.
+        let shift = WORD_BITS - 1 - index % WORD_BITS;
+        let mut bits = self.mapping[word] << shift >> shift;
+        loop {
+            if bits != 0 {
+                return Some(word * WORD_BITS + WORD_BITS - 1 -
+                            bits.leading_zeros().to_usize().unwrap());
+            }
+            if word == 0 {
+                return None;
+            }
+            word -= 1;
+            bits = self.mapping[word];
+        }
+    }
+
+    // Index of a random free slot, left free.
+    fn pick_chunk_slot(&self, rng: &mut PoolRng) -> usize {
+        debug_assert!((self.is_chunk() || self.is_span()) && self.size != 0 &&
+                      !self.is_full_chunk());
+
+        let max_slot_index = self.slot_count();
+        assert!(max_slot_index > 0 && self.free_slots > 0);
+
+        // First free slot down from a random one, wrapping around to the
+        // last slots.
+        let start = rng.gen_range(0_usize, max_slot_index);
+        let slot_index = self.prev_free_slot(start)
+            .or_else(|| self.prev_free_slot(max_slot_index - 1)).unwrap();
+        assert!(slot_index < max_slot_index);
+        slot_index
+    }
+
+    // Mark the free slot `slot_index` as taken.
+    fn take_slot(&mut self, slot_index: usize) {
+        debug_assert!(self.chunk_slot_is_free(slot_index));
+        self.mapping[slot_index / WORD_BITS] ^= 1 << (slot_index % WORD_BITS);
+        self.free_slots -= 1;
+    }
+
+    fn take_chunk_slot(&mut self, rng: &mut PoolRng) -> usize {
+        let slot_index = self.pick_chunk_slot(rng);
+        self.take_slot(slot_index);
+        slot_index
+    }
+
+    // Return the index of the slot in use located at `offset`.
+    fn used_slot_index(&self, offset: usize) -> Result<usize, MallocError> {
+        debug_assert!((self.is_chunk() || self.is_span()) && self.size != 0);
+
+        let stride = self.slot_stride();
+        if offset >= self.slot_count() * stride || offset % stride != 0 {
+            return Err(MallocError::UnknownPointer);
+        }
+        let slot_index = offset.checked_div(stride).unwrap();
+        assert!(slot_index < self.slot_count());
+
+        // Potentially detected a double free.
+        if self.chunk_slot_is_free(slot_index) {
+            return Err(MallocError::DoubleFree);
+        }
+        Ok(slot_index)
+    }
+
+    fn free_slot(&mut self, offset: usize) -> Result<(), MallocError> {
+        let slot_index = try!(self.used_slot_index(offset));
+
+        // Mark slot as free.
+        self.mapping[slot_index / WORD_BITS] |= 1 << (slot_index % WORD_BITS);
+        self.free_slots += 1;
+        Ok(())
+    }
+
+    unsafe fn free_chunk_slot(&mut self,
+                              offset: usize) -> Result<(), MallocError> {
+        try!(self.free_slot(offset));
+
+        utils::set_memory(self.object.offset(offset as isize),
+                          fill_byte_dealloc().unwrap(), self.size);
+        Ok(())
+    }
+
+    unsafe fn dealloc_data(&mut self, forced: bool) -> Result<(), MallocError> {
+        registry::unregister(mmap::mask_pointer(self.object) as usize);
+
+        match self.kind {
+            RegionType::Chunk => {
+                let fill = if forced {
+                    fill_byte_dealloc()
+                } else {
+                    None
+                };
+                try!(mmap::deallocate(self.object, mmap::page_size(), fill));
+            },
+            RegionType::Large | RegionType::Key => {
+                try!(mmap::deallocate(self.object, self.size,
+                                      fill_byte_dealloc()));
+            },
+            RegionType::Cache => {
+                try!(mmap::deallocate(self.object, mmap::page_size(), None));
+            },
+            RegionType::Tomb | RegionType::Spare => {
+                try!(mmap::deallocate(self.object, self.size, None));
+            },
+            RegionType::Span => {
+                let fill = if forced {
+                    fill_byte_dealloc()
+                } else {
+                    None
+                };
+                try!(mmap::deallocate(self.object, span_size(self.size),
+                                      fill));
+            },
+            _ => unreachable!()
+        }
+
+        self.set_as_free();
+        Ok(())
+    }
+}
+
+impl Debug for Region {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        if self.is_free() {
+            return write!(fmt, "Region: free\n");
+        }
+
+        try!(write!(fmt, "Region: used, type: {}, size: {}\n",
+                    self.kind as usize, self.size));
+
+        if (self.is_chunk() || self.is_span()) && self.size != 0 {
+            try!(write!(fmt, "mapping:"));
+            for i in 0_usize..self.slot_count() {
+                if i % 8 == 0 {
+                    try!(write!(fmt, " "));
+                }
+                if i > 0 && i % 32 == 0  {
+                    try!(write!(fmt, "\n         "));
+                }
+                try!(write!(fmt, "{}", self.chunk_slot_is_free(i) as usize));
+            }
+            try!(write!(fmt, "\n"));
+        }
+
+        Ok(())
+    }
+}
+
+
+// Size to allocate for `size` bytes aligned on `align`. Alignments of at
+// least a page are honoured by large objects whose size is then rounded to
+// whole pages, to be positioned at the start of their mapping.
+fn align_to_size(align: usize, size: usize) -> Result<usize, MallocError> {
+    match align {
+        0 => Ok(size),
+        algn if !algn.is_power_of_two() =>
+            Err(MallocError::InvalidAlignment),
+        algn if algn >= mmap::page_size() => {
+            let sz = try!(mmap::page_round(size).ok_or(MallocError::Overflow));
+            Ok(cmp::max(sz, mmap::page_size()))
+        },
+        algn if algn <= mmap::MIN_ALIGN => Ok(size),
+        algn => {
+            // Chunk slots are aligned if their size, canary included, is
+            // a power of two.
+            let extra = if options().canaries {
+                CANARY_LEN_BYTES
+            } else {
+                0
+            };
+            let sz = try!(size.checked_add(extra)
+                          .and_then(|sz| sz.checked_next_power_of_two())
+                          .ok_or(MallocError::Overflow));
+            Ok(cmp::max(sz, algn) - extra)
+        }
+    }
+}
+
+// Convert the result of a fallible allocation to the pointer returned
+// by its infallible counterpart.
+fn ptr_or_null(res: Result<*mut u8, MallocError>) -> *mut u8 {
+    match res {
+        Ok(ptr) => ptr,
+        Err(MallocError::InvalidAlignment) => ptr::null_mut(),
+        Err(err) => fail(err)
+    }
+}
+
+fn or_panic(res: Result<(), MallocError>) {
+    if let Err(err) = res {
+        fail(err);
+    }
+}
+
+// Panic on `err`, the traced events of the current thread are dumped
+// first. Panics raised from an opened Dir are dumped by `OpenDir`.
+fn fail(err: MallocError) -> ! {
+    dump_trace();
+    panic!("{}", err)
+}
+
+unsafe fn xmalloc(size: usize, align: usize, zero_fill: bool,
+                  force_large: bool) -> Result<*mut u8, MallocError> {
+    let sz = try!(align_to_size(align, size));
+    thread_dir().alloc(sz, align, zero_fill, force_large)
+}
+
+/// Allocate memory
+///
+/// Provides the same interface than the usual `malloc` function along
+/// with the following specifities: the requested alignment `align` must
+/// be a power of two, alignments of at least pagesize round the allocated
+/// size up to whole pages. Also, `align` is expected to be equal to zero
+/// if no specific alignment needs to be requested.
+/// This function returns `NULL` if `align` is invalid and otherwise
+/// `panic!` on error.
+pub unsafe fn malloc(size: usize, align: usize) -> *mut u8 {
+    ptr_or_null(try_malloc(size, align))
+}
+
+/// Allocate memory or return an error
+///
+/// Same as `malloc` but return `MallocError::InvalidAlignment` if `align`
+/// is invalid and an error for any other failure instead of `panic!`ing.
+pub unsafe fn try_malloc(size: usize,
+                         align: usize) -> Result<*mut u8, MallocError> {
+    xmalloc(size, align, false, false)
+}
+
+/// Allocate memory
+///
+/// Provides the same interface than the usual `calloc` function, see
+/// `malloc` for this implementation's specifities.
+pub unsafe fn calloc(count: usize, size: usize, align: usize) -> *mut u8 {
+    ptr_or_null(try_calloc(count, size, align))
+}
+
+/// Allocate memory or return an error
+///
+/// Same as `calloc` but return `MallocError::Overflow` if `count * size`
+/// overflows, see `try_malloc` for other errors.
+pub unsafe fn try_calloc(count: usize, size: usize,
+                         align: usize) -> Result<*mut u8, MallocError> {
+    let full_sz = try!(count.checked_mul(size).ok_or(MallocError::Overflow));
+    xmalloc(full_sz, align, true, false)
+}
+
+/// Allocate memory and allow changes to memory protections
+///
+/// Similar to `malloc` but with the additional guarantee that its
+/// returned pointer can be used with `protect_read`, `protect_write`
+/// or `protect_none` to change memory protections on its allocated
+/// region.
+pub unsafe fn malloc_key(size: usize, align: usize) -> *mut u8 {
+    ptr_or_null(try_malloc_key(size, align))
+}
+
+/// Allocate memory allowing changes to memory protections or return
+/// an error
+///
+/// See `malloc_key` and `try_malloc`.
+pub unsafe fn try_malloc_key(size: usize,
+                             align: usize) -> Result<*mut u8, MallocError> {
+    xmalloc(size, align, false, true)
+}
+
+/// Allocate several objects at once
+///
+/// Same as calling `malloc` `count` times, but the pages of objects larger
+/// than medium objects are mapped, guarded and locked for the whole batch
+/// in as few system calls as possible. Each returned pointer must still
+/// be deallocated on its own with `free`. This function returns an empty
+/// vector if `align` is invalid and otherwise `panic!` on error.
+pub unsafe fn malloc_batch(size: usize, count: usize,
+                           align: usize) -> Vec<*mut u8> {
+    match try_malloc_batch(size, count, align) {
+        Ok(ptrs) => ptrs,
+        Err(MallocError::InvalidAlignment) => Vec::new(),
+        Err(err) => fail(err)
+    }
+}
+
+/// Allocate several objects at once or return an error
+///
+/// See `malloc_batch` and `try_malloc`, no object is left allocated on
+/// error.
+pub unsafe fn try_malloc_batch(size: usize, count: usize, align: usize)
+                               -> Result<Vec<*mut u8>, MallocError> {
+    let sz = try!(align_to_size(align, size));
+    thread_dir().alloc_batch(sz, count, align, false)
+}
+
+
+unsafe fn xrealloc(ptr: *mut u8, size: usize, align: usize,
+                   force_large: bool) -> Result<*mut u8, MallocError> {
+    let sz = try!(align_to_size(align, size));
+    thread_dir().realloc(ptr, sz, align, false, force_large)
+}
+
+/// Reallocate memory
+///
+/// Provides the same interface than the usual `realloc` function, see
+/// `malloc` for this implementation's specifities.
+pub unsafe fn realloc(ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
+    ptr_or_null(try_realloc(ptr, size, align))
+}
+
+/// Reallocate memory or return an error
+///
+/// Same as `realloc` but return an error instead of `panic!`ing. On error
+/// `ptr` is left untouched and must still be freed.
+pub unsafe fn try_realloc(ptr: *mut u8, size: usize,
+                          align: usize) -> Result<*mut u8, MallocError> {
+    xrealloc(ptr, size, align, false)
+}
+
+/// Reallocate memory and allow changes to memory protections
+///
+/// Must only be called after memory allocation with `malloc_key`.
+pub unsafe fn realloc_key(ptr: *mut u8, size: usize, align: usize) -> *mut u8 {
+    ptr_or_null(try_realloc_key(ptr, size, align))
+}
+
+/// Reallocate memory allowing changes to memory protections or return
+/// an error
+///
+/// See `realloc_key` and `try_realloc`.
+pub unsafe fn try_realloc_key(ptr: *mut u8, size: usize,
+                              align: usize) -> Result<*mut u8, MallocError> {
+    xrealloc(ptr, size, align, true)
+}
+
+
+/// Free memory
+///
+/// Provides the same interface than the usual `free` function. This function
+/// has no effect if `ptr` is a `NULL` pointer or a pointer to a zero-sized
+/// area. But will `panic!` for any other kind of error.
+pub unsafe fn free(ptr: *mut u8) {
+    or_panic(try_free(ptr));
+}
+
+/// Free memory or return an error
+///
+/// Same as `free` but return an error such as `MallocError::DoubleFree`
+/// or `MallocError::UnknownPointer` instead of `panic!`ing.
+pub unsafe fn try_free(ptr: *mut u8) -> Result<(), MallocError> {
+    thread_dir().dealloc(ptr)
+}
+
+
+/// Set memory protection to read-only
+///
+/// `ptr` must have been allocated through `malloc_key` exclusively.
+/// This function has no effect if `ptr` is `NULL` but `panic!` for
+/// any other kind of error.
+pub unsafe fn protect_read(ptr: *mut u8) {
+    or_panic(try_protect(ptr, Prot::Read));
+}
+
+/// Set memory protection to write-only
+///
+/// See `protect_read` for its usage.
+///
+/// Note that due to hardware limitations on most architectures (such
+/// as x86, x86_64), setting a `write` page protection will also implicitly
+/// imply granting `read` access too (see `man mprotect`).
+pub unsafe fn protect_write(ptr: *mut u8) {
+    or_panic(try_protect(ptr, Prot::Write));
+}
+
+/// Set memory protection to prevent any access
+///
+/// See `protect_read` for its usage.
+pub unsafe fn protect_none(ptr: *mut u8) {
+    or_panic(try_protect(ptr, Prot::None));
+}
+
+/// Set memory protection or return an error
+///
+/// `ptr` must have been allocated through `malloc_key` exclusively,
+/// `MallocError::UnknownPointer` is returned otherwise. This function
+/// has no effect if `ptr` is `NULL`.
+pub unsafe fn try_protect(ptr: *mut u8, prot: Prot) -> Result<(), MallocError> {
+    thread_dir().protect(ptr, prot)
+}
+
+
+/// Set the quota of the current thread
+///
+/// Only following allocations are checked against `quota`, memory already
+/// in use is kept even if it goes over.
+pub fn set_thread_quota(quota: Quota) -> Result<(), MallocError> {
+    thread_dir().set_quota(quota)
+}
+
+/// Set the quota of the whole process
+///
+/// Enforced in addition to the quota of each thread, see
+/// `set_thread_quota`.
+pub fn set_process_quota(quota: Quota) {
+    QUOTA_BYTES.store(quota.bytes.map_or(0, |bytes| bytes.saturating_add(1)),
+                      Ordering::SeqCst);
+    QUOTA_PAGES.store(quota.pages.map_or(0, |pages| pages.saturating_add(1)),
+                      Ordering::SeqCst);
+}
+
+/// Return the memory used by the current thread and its quota
+///
+/// Return `None` if the thread's allocator could not be initialized.
+///
+/// ```rust
+/// # use tars::malloc::{self, Quota};
+/// let usage = malloc::thread_usage().unwrap();
+/// assert_eq!(usage.quota, Quota::unlimited());
+/// ```
+pub fn thread_usage() -> Option<Usage> {
+    thread_dir().usage()
+}
+
+/// Return the memory used by all threads and the process' quota
+pub fn process_usage() -> Usage {
+    Usage {
+        bytes: PROCESS_BYTES.load(Ordering::SeqCst),
+        pages: PROCESS_PAGES.load(Ordering::SeqCst),
+        quota: process_quota()
+    }
+}
+
+/// Release the memory cached by the current thread
+///
+/// Pending remote frees are processed, then the empty chunks kept in cache
+/// and the spare mappings of freed large objects and keys are unmapped and
+/// the regions table is shrunk to fit the regions left. Objects in
+/// quarantine are kept. Return the number of pages released.
+///
+/// ```rust
+/// # use tars::malloc;
+/// unsafe {
+///     malloc::free(malloc::malloc(42, 0));
+/// }
+/// let pages = malloc::trim().unwrap();
+/// println!("{} pages released", pages);
+/// ```
+pub fn trim() -> Result<usize, MallocError> {
+    thread_dir().trim()
+}
+
+/// Release the memory cached by all threads
+///
+/// The current thread is trimmed right away like by `trim`, other threads
+/// trim their caches on their next call to the allocator. Threads which
+/// don't call it again keep their caches until they exit. Return the
+/// number of pages released by the current thread only, the errors of the
+/// other threads are returned to them by `take_deferred_errors`.
+pub fn trim_all() -> Result<usize, MallocError> {
+    TRIM_EPOCH.fetch_add(1, Ordering::SeqCst);
+    trim()
+}
+
+/// Take the errors met on behalf of the current thread outside of its
+/// calls
+///
+/// They are the errors met by other threads while deallocating pointers
+/// freed by the current thread, such as double frees, and the errors met
+/// by the current thread while adopting orphaned directories or trimming
+/// its caches on request of `trim_all`. None of them fail the unrelated
+/// calls during which they are met.
+///
+/// ```rust
+/// # use tars::malloc;
+/// for err in malloc::take_deferred_errors() {
+///     println!("deferred error: {}", err);
+/// }
+/// ```
+pub fn take_deferred_errors() -> Vec<MallocError> {
+    thread_dir().take_deferred_errors()
+}
+
+/// Return the number of orphaned directories found corrupted
+///
+/// Directories left with live objects by exiting threads are adopted by
+/// other threads. Those failing their integrity checks are closed and
+/// never adopted, their objects are leaked and freeing them fails.
+pub fn corrupted_orphans() -> usize {
+    registry::corrupted()
+}
+
+/// Report the objects still allocated
+///
+/// Return `None` unless leak tracking is enabled with the `T` option.
+/// Objects allocated by all threads and not freed yet are grouped by the
+/// backtrace of their allocation. Objects of size 0 aren't reported.
+///
+/// ```rust
+/// # use tars::malloc;
+/// if let Some(report) = malloc::report_leaks() {
+///     println!("{}", report);
+/// }
+/// ```
+pub fn report_leaks() -> Option<LeakReport> {
+    if options().track_leaks {
+        Some(leaks::report(None))
+    } else {
+        None
+    }
+}
+
+/// Check the consistency of the current thread's heap
+///
+/// Walk every region of the current thread's allocator and cross-check
+/// their placement in the hash table, their types against the chunks and
+/// cache lists, their bitmaps against their lists membership, the regions
+/// counts and all the canaries, chunk slots' canaries included. Freed
+/// slots are also checked for writes after free if `validate_junk` is
+/// enabled. The report lists every inconsistency found.
+///
+/// ```rust
+/// # use tars::malloc;
+/// let report = malloc::check_heap();
+/// assert!(report.is_ok(), "{}", report);
+/// ```
+pub fn check_heap() -> HeapReport {
+    thread_dir().check_heap()
+}
+
+/// Return statistics of the current thread
+///
+/// Return `None` if statistics are not collected, see `Options`.
+pub fn stats() -> Option<Stats> {
+    thread_dir().stats()
+}
+
+/// Return statistics aggregated across all threads
+///
+/// Objects of exited threads that are still alive are accounted for as
+/// well. Return `None` if statistics are not collected, see `Options`.
+pub fn stats_all() -> Option<Stats> {
+    if !options().stats {
+        return None;
+    }
+
+    let mut stats = Stats::new();
+    registry::fold_counters(|counters| stats.accumulate(counters));
+    Some(stats)
+}
+
+/// Return the state of every region of the current thread's heap
+///
+/// Regions of other threads, orphaned ones included, are left out.
+/// Return `None` if the allocator of the current thread is unavailable.
+///
+/// ```rust
+/// # use tars::malloc;
+/// if let Some(snapshot) = malloc::snapshot() {
+///     println!("{} pages: {}", snapshot.pages(), snapshot.to_json());
+/// }
+/// ```
+pub fn snapshot() -> Option<HeapSnapshot> {
+    thread_dir().snapshot()
+}
+
+/// Draw the current thread's heap as an SVG image
+///
+/// Shortcut for `HeapSnapshot::to_svg` on the result of `snapshot`, meant
+/// for debugging fragmentation.
+///
+/// ```rust
+/// # use tars::malloc;
+/// if let Some(svg) = malloc::render_svg() {
+///     assert!(svg.starts_with("<svg"));
+/// }
+/// ```
+pub fn render_svg() -> Option<String> {
+    snapshot().map(|snapshot| snapshot.to_svg())
+}
+
+/// Set the hook called on each traced event of any thread
+///
+/// `None` removes the current hook. The hook is called from within the
+/// allocator, it must not allocate nor free memory with this module.
+/// Events are only traced while tracing is enabled, see `Options`.
+pub fn set_trace_hook(hook: Option<fn(&TraceEvent)>) {
+    trace::set_hook(hook);
+}
+
+/// Return the last events traced by the current thread, oldest first
+///
+/// Return `None` if tracing is disabled, see `Options`.
+///
+/// ```rust
+/// # use tars::malloc;
+/// if let Some(events) = malloc::trace_events() {
+///     for event in events.iter() {
+///         println!("{}", event);
+///     }
+/// }
+/// ```
+pub fn trace_events() -> Option<Vec<TraceEvent>> {
+    if !options().trace {
+        return None;
+    }
+    thread_dir().trace_events()
+}
+
+/// Write the last events traced by the current thread on the standard
+/// error
+///
+/// Nothing is written if tracing is disabled.
+pub fn dump_trace() {
+    if let Some(events) = trace_events() {
+        trace::dump(&events);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use alloc::heap;
+    use libc;
+    use std::cmp;
+    use std::collections::HashSet;
+    use std::mem;
+    use std::ptr;
+    use std::sync::{Arc, Barrier};
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::usize;
+    use test::Bencher;
+
+    use num::ToPrimitive;
+    use rand::{thread_rng, Rng};
+
+    use mmap;
+    use rng::PoolRng;
+    use utils;
+
+    use super::{AllocKind, Dir, LocalDir, MallocError, Options, Prot, Quota,
+                Region, RegionKind, RegionType, Stats, TraceEvent, TraceOp};
+
+
+    fn print_dir_state() {
+        info!("{:?}", super::thread_dir())
+    }
+
+    fn write_byte(ptr: *mut u8, index: usize) {
+        unsafe {
+            *ptr.offset(index.to_isize().unwrap()) = (index % 256) as u8;
+        }
+    }
+
+    fn read_byte(ptr: *const u8, index: usize) {
+        unsafe {
+            assert_eq!(*ptr.offset(index.to_isize().unwrap()),
+                       (index % 256) as u8);
+        }
+    }
+
+    // Number of spare mappings kept by `dir`.
+    fn spares(dir: &super::Dir) -> usize {
+        dir.spares.iter().filter(|spare| !spare.is_null()).count()
+    }
+
+
+    #[test]
+    fn test_malloc_chunks() {
+        const NA: usize = 2048;
+        let mut p: [*mut u8; NA] = [ptr::null_mut(); NA];
+        let mut s: [usize; NA] = [0_usize; NA];
+
+        for i in 0_usize..NA {
+            p[i] = unsafe {
+                let size = thread_rng().gen_range(0_usize,
+                                                  utils::page_size() >> 1);
+                s[i] = size;
+                super::malloc(size, 0)
+            };
+            assert!(!p[i].is_null());
+
+            for j in 0_usize..s[i] {
+                write_byte(p[i], j);
+            }
+        }
+
+        for i in (0_usize..NA).step_by(16) {
+            for j in 0_usize..s[i] {
+                read_byte(p[i] as *const u8, j);
+            }
+
+            unsafe {
+                super::free(p[i]);
+            }
+        }
+
+        for i in (0_usize..NA).step_by(16) {
+            p[i] = unsafe {
+                let size = thread_rng().gen_range(0_usize,
+                                                  utils::page_size() >> 1);
+                s[i] = size;
+                super::malloc(size, 0)
+            };
+            assert!(!p[i].is_null());
+
+            for j in 0_usize..s[i] {
+                write_byte(p[i], j);
+            }
+        }
+
+        for i in 0_usize..NA {
+            for j in 0_usize..s[i] {
+                read_byte(p[i] as *const u8, j);
+            }
+
+            unsafe {
+                super::free(p[i]);
+            }
+        }
+
+        let d = super::thread_dir();
+        let dir = d.open().unwrap();
+        assert!(dir.total - dir.cache_len - spares(&dir) <= dir.free + 1);
+    }
+
+    #[test]
+    fn test_malloc_large() {
+        const NA: usize = 2048;
+        let mut p: [*mut u8; NA] = [ptr::null_mut(); NA];
+        let mut s: [usize; NA] = [0_usize; NA];
+
+        for i in 0_usize..NA {
+            p[i] = unsafe {
+                let size = thread_rng().gen_range((utils::page_size() >> 1) + 1,
+                                                  utils::page_size() << 3);
+                s[i] = size;
+                super::malloc(size, 0)
+            };
+            assert!(!p[i].is_null());
+
+            for j in 0_usize..s[i] {
+                write_byte(p[i], j);
+            }
+        }
+
+        for i in 0_usize..NA {
+            for j in 0_usize..s[i] {
+                read_byte(p[i] as *const u8, j);
+            }
+
+            unsafe {
+                super::free(p[i]);
+            }
+        }
+
+        let d = super::thread_dir();
+        let dir = d.open().unwrap();
+        assert_eq!(dir.total - spares(&dir), dir.free);
+    }
+
+    #[test]
+    fn test_malloc_keys() {
+        const NA: usize = 2048;
+        let mut p: [*mut u8; NA] = [ptr::null_mut(); NA];
+        let mut s: [usize; NA] = [0_usize; NA];
+
+        for i in 0_usize..NA {
+            p[i] = unsafe {
+                let size = thread_rng().gen_range(0, utils::page_size() << 3);
+                s[i] = size;
+                super::malloc_key(size, 0)
+            };
+            assert!(!p[i].is_null());
+
+            for j in 0_usize..s[i] {
+                write_byte(p[i], j);
+            }
+        }
+
+        for i in 0_usize..NA {
+            for j in 0_usize..s[i] {
+                read_byte(p[i] as *const u8, j);
+            }
+
+            unsafe {
+                super::free(p[i]);
+            }
+        }
+
+        let d = super::thread_dir();
+        let dir = d.open().unwrap();
+        assert_eq!(dir.total - spares(&dir), dir.free);
+    }
+
+    #[test]
+    fn test_malloc_mixed() {
+        const NA: usize = 8192;
+        let mut p: [*mut u8; NA] = [ptr::null_mut(); NA];
+        let mut s: [usize; NA] = [0_usize; NA];
+
+        for i in 0_usize..NA {
+            p[i] = unsafe {
+                let size = thread_rng().gen_range(0_usize,
+                                                  utils::page_size() << 2);
+                s[i] = size;
+                super::malloc(size, 0)
+            };
+            assert!(!p[i].is_null());
+
+            for j in 0_usize..s[i] {
+                write_byte(p[i], j);
+            }
+        }
+
+        print_dir_state();
+
+        for i in (0_usize..NA).step_by(16) {
+            for j in 0_usize..s[i] {
+                read_byte(p[i] as *const u8, j);
+            }
+
+            unsafe {
+                super::free(p[i]);
+            }
+        }
+
+        print_dir_state();
+
+        for i in (0_usize..NA).step_by(16) {
+            p[i] = unsafe {
+                let size = thread_rng().gen_range(0_usize,
+                                                  utils::page_size() << 4);
+                s[i] = size;
+                super::malloc(size, 0)
+            };
+            assert!(!p[i].is_null());
+
+            for j in 0_usize..s[i] {
+                write_byte(p[i], j);
+            }
+        }
+
+        print_dir_state();
+
+        for i in 0_usize..NA {
+            for j in 0_usize..s[i] {
+                read_byte(p[i] as *const u8, j);
+            }
+
+            unsafe {
+                super::free(p[i]);
+            }
+        }
+
+        print_dir_state();
+
+        let d = super::thread_dir();
+        let dir = d.open().unwrap();
+        assert!(dir.total - dir.cache_len - spares(&dir) <= dir.free + 1);
+    }
+
+    #[test]
+    fn test_size_classes() {
+        let classes = super::size_classes();
+        let mut last = 0_usize;
+        for &(index, size) in classes.iter() {
+            assert!(size > last && size % mmap::MIN_ALIGN == 0);
+            assert_eq!(super::chunk_size(size), size);
+            assert_eq!(super::chunk_index(size), index);
+            last = size;
+        }
+        assert_eq!(last, super::max_chunk_size());
+
+        // Sizes are rounded up to the closest size-class, by less than a
+        // quarter of their size once steps are larger than MIN_ALIGN.
+        for size in 1_usize..super::max_chunk_size() + 1 {
+            let cs = super::chunk_size(size);
+            assert!(classes.iter().any(|&(_, s)| s == cs));
+            assert!(classes.iter().all(|&(_, s)| s < size || s >= cs));
+            if size > super::min_chunk_size() &&
+               size >= mmap::MIN_ALIGN * super::CHUNK_SUBCLASSES {
+                assert!((cs - size) * super::CHUNK_SUBCLASSES < size);
+            }
+        }
+
+        if super::min_chunk_size() <= 64 {
+            assert_eq!(super::chunk_size(68), 80);
+            assert_eq!(super::chunk_size(208), 224);
+        }
+    }
+
+    // Pages mapped by the chunks holding `ptrs`, guard pages included.
+    fn chunk_pages(ptrs: &[*mut u8]) -> usize {
+        let chunks: HashSet<usize> = ptrs.iter().map(|&p| {
+            mmap::mask_pointer(p) as usize
+        }).collect();
+        chunks.len() * super::mapping_pages(utils::page_size())
+    }
+
+    // Pages that the chunks of `count` objects of `size` bytes would map
+    // with the former power of two size-classes.
+    fn power_of_two_pages(size: usize, count: usize) -> usize {
+        let class = cmp::max(super::slot_size(size).next_power_of_two(),
+                             super::min_chunk_size());
+        let slots = super::max_slot_index(class);
+        (count + slots - 1) / slots * super::mapping_pages(utils::page_size())
+    }
+
+    // Allocate `count` objects of `size` bytes and return the pages mapped
+    // by their chunks, then free them.
+    fn batch_pages(size: usize, count: usize) -> usize {
+        let ptrs: Vec<*mut u8> = (0_usize..count).map(|_| unsafe {
+            super::malloc(size, 0)
+        }).collect();
+        let pages = chunk_pages(&ptrs);
+        for &p in ptrs.iter() {
+            unsafe {
+                super::free(p);
+            }
+        }
+        pages
+    }
+
+    #[test]
+    fn test_batch_density() {
+        // Poly1305 states and Curve41417 buffers.
+        for &size in [68_usize, 208].iter() {
+            let count = 1024;
+            let pages = batch_pages(size, count);
+            assert!(pages < power_of_two_pages(size, count),
+                    "{} pages for {} objects of {} bytes, {} with power of \
+                     two size-classes", pages, count, size,
+                    power_of_two_pages(size, count));
+        }
+    }
+
+    #[test]
+    fn test_malloc_align() {
+        let mut sptr: *mut u8;
+        let mut kptr: *mut u8;
+        let mut align = 1;
+        let mut size;
+
+        while align <= utils::page_size() << 2 {
+            size = thread_rng().gen_range(0_usize, utils::page_size() << 2);
+
+            sptr = unsafe {
+                super::malloc(size, align)
+            };
+            kptr = unsafe {
+                super::malloc_key(size, align)
+            };
+            assert!(!sptr.is_null() && !kptr.is_null());
+            assert!(sptr as usize % align == 0 && kptr as usize % align == 0);
+
+            for i in 0_usize..size {
+                write_byte(sptr, i);
+                write_byte(kptr, i);
+            }
+
+            for i in 0_usize..size {
+                read_byte(sptr as *const u8, i);
+                read_byte(kptr as *const u8, i);
+            }
+
+            unsafe {
+                super::free(sptr);
+                super::free(kptr);
+            }
+
+            align *= 2;
+        }
+
+        unsafe {
+            // Slots of power of two size-classes, canaries included.
+            for &(size, align) in [(64, 64), (62, 64), (1, 32),
+                                   (100, 128), (512, 256)].iter() {
+                sptr = super::malloc(size, align);
+                assert!(!sptr.is_null() && sptr as usize % align == 0);
+                assert_eq!(super::try_free(sptr), Ok(()));
+            }
+
+            // Huge alignment, grown in place or moved by `realloc`.
+            align = 2 << 20;
+            sptr = super::malloc(42, align);
+            kptr = super::malloc_key(42, align);
+            assert!(sptr as usize % align == 0 && kptr as usize % align == 0);
+            write_byte(sptr, 41);
+            sptr = super::realloc(sptr, utils::page_size() * 3, align);
+            kptr = super::realloc_key(kptr, utils::page_size() * 3, align);
+            assert!(sptr as usize % align == 0 && kptr as usize % align == 0);
+            read_byte(sptr as *const u8, 41);
+            write_byte(kptr, utils::page_size() * 3 - 1);
+            super::free(sptr);
+            super::free(kptr);
+
+            assert!(super::malloc(42, 3).is_null());
+            assert!(super::malloc_key(42, utils::page_size() + 1).is_null());
+        }
+    }
+
+    #[test]
+    #[should_panic(message = "double free")]
+    fn test_double_free_chunk1() {
+        unsafe {
+            let p1 = super::malloc(42, 0);
+            let p2 = super::malloc(42, 0);
+            assert!(!p1.is_null() && !p2.is_null());
+
+            super::free(p1);
+            super::free(p1);
+        }
+    }
+
+    #[test]
+    #[should_panic(message = "double free")]
+    fn test_double_free_chunk2() {
+        unsafe {
+            let p = super::malloc(42, 0);
+            assert!(!p.is_null());
+            super::free(p);
+            super::free(p);
+        }
+    }
+
+    #[test]
+    #[should_panic(message = "invalid free")]
+    fn test_free_invalid1() {
+        let p: *mut u8 = 42 as *mut u8;
+        unsafe {
+            super::free(p);
+        }
+    }
+
+    #[test]
+    #[should_panic(message = "invalid pointer")]
+    fn test_free_invalid2() {
+        unsafe {
+            // Stored in a chunk of size 64.
+            let p1 = super::malloc(42, 0);
+            let p2 = if mmap::mask_pointer(p1) == p1 {
+                p1.offset(64)
+            } else {
+                mmap::mask_pointer(p1)
+            };
+            super::free(p2);
+        }
+    }
+
+    #[test]
+    #[should_panic(message = "invalid pointer")]
+    fn test_free_invalid3() {
+        unsafe {
+            let p = super::malloc(utils::page_size(), 0);
+            super::free(p.offset(64));
+        }
+    }
+
+    #[test]
+    #[should_panic(message = "invalid pointer")]
+    fn test_protect_chunk() {
+        unsafe {
+            let p = super::malloc(42, 0);
+            assert!(!p.is_null());
+            super::protect_read(p);
+        }
+    }
+
+    #[test]
+    #[should_panic(message = "invalid pointer")]
+    fn test_protect_missing() {
+        let p: *mut u8 = 42 as *mut u8;
+
+        unsafe {
+            super::protect_read(p);
+        }
+    }
+
+    #[test]
+    fn test_protect() {
+        // Actually can't test access violations with #[should_panic]
+        // without killing the test runner.
+
+        unsafe {
+            let p = super::malloc_key(42, 0);
+            assert!(!p.is_null());
+
+            super::protect_write(p);
+            write_byte(p, 42);
+
+            super::protect_none(p);
+
+            super::protect_read(p);
+            read_byte(p as *const u8, 42);
+
+            super::protect_none(p);
+            super::free(p);
+        }
+    }
+
+    #[test]
+    fn test_null() {
+        unsafe {
+            super::free(ptr::null_mut());
+            super::protect_none(ptr::null_mut());
+            super::protect_read(ptr::null_mut());
+            super::protect_write(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_zero() {
+        // Actually can't test dereferencing this pointer while expecting
+        // #[should_panic] without killing the test runner.
+
+        unsafe {
+            let p = super::malloc(0, 0);
+            assert!(!p.is_null());
+            super::free(p);
+        }
+    }
+
+    #[test]
+    fn test_realloc() {
+        let size1 = thread_rng().gen_range(0_usize, utils::page_size() << 2);
+        let size2 = thread_rng().gen_range(0_usize, utils::page_size() << 2);
+
+        unsafe {
+            let mut p1 = super::malloc(size1, 0);
+            let mut p2 = super::malloc_key(size1, 0);
+            assert!(!p1.is_null());
+            assert!(!p2.is_null());
+
+            for i in 0_usize..size1 {
+                write_byte(p1, i);
+                write_byte(p2, i);
+            }
+
+            p1 = super::realloc(p1, size2, 0);
+            p2 = super::realloc_key(p2, size2, 0);
+            assert!(!p1.is_null());
+            assert!(!p2.is_null());
+
+            for i in 0_usize..cmp::min(size1, size2) {
+                read_byte(p1 as *const u8, i);
+                read_byte(p2 as *const u8, i);
+            }
+
+            if size2 > size1 {
+                for i in size1..size2 {
+                    write_byte(p1, i);
+                    write_byte(p2, i);
+                }
+
+                for i in 0_usize..size2 {
+                    read_byte(p1 as *const u8, i);
+                    read_byte(p2 as *const u8, i);
+                }
+            }
+
+            super::free(p1);
+            super::free(p2);
+        }
+    }
+
+    #[test]
+    fn test_realloc_zero() {
+        let size = thread_rng().gen_range(0_usize, utils::page_size() << 2);
+
+        unsafe {
+            let mut p1 = super::malloc(size, 0);
+            assert!(!p1.is_null());
+
+            for i in 0_usize..size {
+                write_byte(p1, i);
+            }
+
+            p1 = super::realloc(p1, 0, 0);
+            assert!(!p1.is_null());
+
+            let p2 = super::realloc(p1, 0, 0);
+            assert!(!p2.is_null());
+
+            assert_eq!(p1, p2);
+
+            super::free(p1);
+            super::free(p2);
+        }
+    }
+
+    #[test]
+    fn test_realloc_in_place() {
+        let pagesize = utils::page_size();
+
+        unsafe {
+            // Same chunk size-class.
+            let p1 = super::malloc(50, 0);
+            for i in 0_usize..50 {
+                write_byte(p1, i);
+            }
+            let p2 = super::realloc(p1, 60, 0);
+            assert_eq!(p1, p2);
+            for i in 0_usize..50 {
+                read_byte(p2 as *const u8, i);
+            }
+            super::free(p2);
+
+            // Same medium size-class.
+            let p1 = super::malloc(pagesize + 42, 0);
+            let p2 = super::realloc(p1, pagesize << 1, 0);
+            assert_eq!(p1, p2);
+            super::free(p2);
 
-        for i in 0_usize..NA {
-            for j in 0_usize..s[i] {
-                read_byte(p[i] as *const u8, j);
-            }
+            // Shrinking large objects and keys never move their mapping.
+            for &key in [false, true].iter() {
+                let size = pagesize * 7 + 42;
+                let mut p = if key {
+                    super::malloc_key(size, 0)
+                } else {
+                    super::malloc(size, 0)
+                };
+                for i in 0_usize..size {
+                    write_byte(p, i);
+                }
 
-            unsafe {
-                super::free(p[i]);
+                let new_size = pagesize * 5 + 42;
+                let np = if key {
+                    super::realloc_key(p, new_size, 0)
+                } else {
+                    super::realloc(p, new_size, 0)
+                };
+                assert_eq!(mmap::mask_pointer(np), mmap::mask_pointer(p));
+                for i in 0_usize..new_size {
+                    read_byte(np as *const u8, i);
+                }
+
+                // Grown in place or moved, data must be kept either way.
+                p = if key {
+                    super::protect_read(np);
+                    super::realloc_key(np, size, 0)
+                } else {
+                    super::realloc(np, size, 0)
+                };
+                for i in 0_usize..new_size {
+                    read_byte(p as *const u8, i);
+                }
+                super::free(p);
             }
         }
 
         let d = super::thread_dir();
-        assert!((d.dir.borrow().total - d.dir.borrow().cache_len) <=
-                d.dir.borrow().free + 1);
+        let dir = d.open().unwrap();
+        assert!(dir.total - dir.cache_len - spares(&dir) <= dir.free + 1);
     }
 
     #[test]
-    fn test_malloc_large() {
-        const NA: usize = 2048;
-        let mut p: [*mut u8; NA] = [ptr::null_mut(); NA];
-        let mut s: [usize; NA] = [0_usize; NA];
-
-        for i in 0_usize..NA {
-            p[i] = unsafe {
-                let size = thread_rng().gen_range((utils::page_size() >> 1) + 1,
-                                                  utils::page_size() << 3);
-                s[i] = size;
-                super::malloc(size, 0)
-            };
-            assert!(!p[i].is_null());
+    fn test_calloc() {
+        unsafe {
+            // Large
+            let mut p = super::calloc(1, utils::page_size(), 0);
+            assert!(!p.is_null());
 
-            for j in 0_usize..s[i] {
-                write_byte(p[i], j);
+            for i in 0_usize..utils::page_size() {
+                assert_eq!(*p.offset(i as isize), 0);
             }
-        }
+            super::free(p);
 
-        for i in 0_usize..NA {
-            for j in 0_usize..s[i] {
-                read_byte(p[i] as *const u8, j);
-            }
+            // Chunk
+            p = super::calloc(1, 42, 0);
+            assert!(!p.is_null());
 
-            unsafe {
-                super::free(p[i]);
+            for i in 0_usize..42 {
+                assert_eq!(*p.offset(i as isize), 0);
             }
+            super::free(p);
         }
-
-        let d = super::thread_dir();
-        assert_eq!(d.dir.borrow().total, d.dir.borrow().free);
     }
 
     #[test]
-    fn test_malloc_keys() {
-        const NA: usize = 2048;
-        let mut p: [*mut u8; NA] = [ptr::null_mut(); NA];
-        let mut s: [usize; NA] = [0_usize; NA];
-
-        for i in 0_usize..NA {
-            p[i] = unsafe {
-                let size = thread_rng().gen_range(0, utils::page_size() << 3);
-                s[i] = size;
-                super::malloc_key(size, 0)
-            };
-            assert!(!p[i].is_null());
-
-            for j in 0_usize..s[i] {
-                write_byte(p[i], j);
-            }
+    #[should_panic(message = "integer overflow")]
+    fn test_calloc_overflow() {
+        unsafe {
+            super::calloc(usize::MAX, 2, 0);
         }
+    }
 
-        for i in 0_usize..NA {
-            for j in 0_usize..s[i] {
-                read_byte(p[i] as *const u8, j);
-            }
-
-            unsafe {
-                super::free(p[i]);
-            }
+    #[test]
+    fn test_try_errors() {
+        unsafe {
+            assert_eq!(super::try_malloc(42, 3),
+                       Err(MallocError::InvalidAlignment));
+            assert_eq!(super::try_calloc(usize::MAX, 2, 0),
+                       Err(MallocError::Overflow));
+            assert_eq!(super::try_free(42 as *mut u8),
+                       Err(MallocError::UnknownPointer));
+            assert_eq!(super::try_protect(42 as *mut u8, Prot::Read),
+                       Err(MallocError::UnknownPointer));
+
+            let p1 = super::try_malloc(42, 0).unwrap();
+            let p2 = super::try_malloc(42, 0).unwrap();
+            assert_eq!(super::try_protect(p1, Prot::Read),
+                       Err(MallocError::UnknownPointer));
+            assert_eq!(super::try_free(p1), Ok(()));
+            assert_eq!(super::try_free(p1), Err(MallocError::DoubleFree));
+            assert_eq!(super::try_free(p2), Ok(()));
+
+            let p3 = super::try_malloc_key(42, 0).unwrap();
+            assert_eq!(super::try_protect(p3, Prot::None), Ok(()));
+            assert_eq!(super::try_free(p3.offset(1)),
+                       Err(MallocError::UnknownPointer));
+            assert_eq!(super::try_free(p3), Ok(()));
+            assert_eq!(super::try_free(p3), Err(MallocError::UnknownPointer));
+
+            let p4 = super::try_realloc(ptr::null_mut(), 42, 0).unwrap();
+            assert_eq!(super::try_realloc(p4, 4242, 3),
+                       Err(MallocError::InvalidAlignment));
+            let p5 = super::try_realloc(p4, 4242, 0).unwrap();
+            assert_eq!(super::try_free(p5), Ok(()));
         }
-
-        let d = super::thread_dir();
-        assert_eq!(d.dir.borrow().total, d.dir.borrow().free);
     }
 
     #[test]
-    fn test_malloc_mixed() {
-        const NA: usize = 8192;
-        let mut p: [*mut u8; NA] = [ptr::null_mut(); NA];
-        let mut s: [usize; NA] = [0_usize; NA];
+    fn test_canaries() {
+        let mut slot = [0_u8; 64];
+        let ptr = slot.as_mut_ptr();
+        let keys: (u64, u64) = thread_rng().gen();
+        let other = (keys.0, !keys.1);
 
-        for i in 0_usize..NA {
-            p[i] = unsafe {
-                let size = thread_rng().gen_range(0_usize,
-                                                  utils::page_size() << 2);
-                s[i] = size;
-                super::malloc(size, 0)
-            };
-            assert!(!p[i].is_null());
+        unsafe {
+            super::write_canary(ptr, 64, 42, keys);
+            assert_eq!(super::canary_tail(ptr, 64, keys), Some(22));
+            assert_eq!(super::canary_tail(ptr, 64, other), None);
 
-            for j in 0_usize..s[i] {
-                write_byte(p[i], j);
-            }
+            *ptr.offset(42) ^= 1;
+            assert_eq!(super::canary_tail(ptr, 64, keys), None);
+
+            super::write_canary(ptr, 64, 62, keys);
+            assert_eq!(super::canary_tail(ptr, 64, keys), Some(2));
         }
 
-        print_dir_state();
+        if !super::options().canaries {
+            return;
+        }
 
-        for i in (0_usize..NA).step_by(16) {
-            for j in 0_usize..s[i] {
-                read_byte(p[i] as *const u8, j);
+        unsafe {
+            let p = super::try_malloc(42, 0).unwrap();
+            for i in 0_usize..43 {
+                write_byte(p, i);
             }
-
-            unsafe {
-                super::free(p[i]);
+            match super::try_free(p) {
+                Err(MallocError::SlotOverflow { slot, size_class }) => {
+                    assert_eq!(slot, p as usize);
+                    assert_eq!(size_class,
+                               super::chunk_size(super::slot_size(42)));
+                },
+                res => panic!("unexpected result {:?}", res)
             }
         }
+    }
 
-        print_dir_state();
-
-        for i in (0_usize..NA).step_by(16) {
-            p[i] = unsafe {
-                let size = thread_rng().gen_range(0_usize,
-                                                  utils::page_size() << 4);
-                s[i] = size;
-                super::malloc(size, 0)
-            };
-            assert!(!p[i].is_null());
+    #[test]
+    fn test_validate_junk() {
+        assert!(Options::parse("V").unwrap().validate_junk);
+        assert!(!Options::parse("Vv").unwrap().validate_junk);
 
-            for j in 0_usize..s[i] {
-                write_byte(p[i], j);
-            }
+        let opts = super::options();
+        if !opts.validate_junk || opts.quarantine != 0 ||
+           opts.cache_size == 0 {
+            return;
         }
 
-        print_dir_state();
-
-        for i in 0_usize..NA {
-            for j in 0_usize..s[i] {
-                read_byte(p[i] as *const u8, j);
+        unsafe {
+            // Find two slots of the same chunk, the chunk stays mapped
+            // while one of them is still allocated.
+            let ptrs: Vec<*mut u8> = (0_usize..256).map(|_| {
+                super::try_malloc(42, 0).unwrap()
+            }).collect();
+            let (p1, p2) = ptrs.iter().enumerate().filter_map(|(i, &p)| {
+                ptrs[i + 1..].iter().find(|&&q| {
+                    mmap::mask_pointer(q) == mmap::mask_pointer(p)
+                }).map(|&q| (p, q))
+            }).next().unwrap();
+
+            assert_eq!(super::try_free(p1), Ok(()));
+            *p1 = 42;
+
+            let mut more = Vec::new();
+            let mut res = Ok(());
+            for _ in 0_usize..4096 {
+                match super::try_malloc(42, 0) {
+                    Ok(p) => more.push(p),
+                    Err(err) => {
+                        res = Err(err);
+                        break;
+                    }
+                }
+            }
+            match res {
+                Err(MallocError::WriteAfterFree { chunk, size_class, .. }) => {
+                    assert_eq!(chunk, mmap::mask_pointer(p1) as usize);
+                    assert_eq!(size_class,
+                               super::chunk_size(super::slot_size(42)));
+                },
+                res => panic!("unexpected result {:?}", res)
             }
 
-            unsafe {
-                super::free(p[i]);
+            // The corrupted slot is never handed out again.
+            for _ in 0_usize..1024 {
+                let p = super::try_malloc(42, 0).unwrap();
+                assert!(p != p1);
+                more.push(p);
+            }
+            for &p in ptrs.iter().chain(more.iter()).filter(|&&p| p != p1) {
+                assert_eq!(super::try_free(p), Ok(()));
             }
+            assert!(p2 != p1);
         }
+    }
 
-        print_dir_state();
+    #[test]
+    fn test_quarantine() {
+        assert_eq!(Options::parse("Q").unwrap().quarantine,
+                   super::DEFAULT_QUARANTINE);
+        assert_eq!(Options::parse("QQ").unwrap().quarantine,
+                   super::DEFAULT_QUARANTINE << 1);
+        assert_eq!(Options::parse("QQQQQQQQQQ").unwrap().quarantine,
+                   super::MAX_QUARANTINE);
+        assert_eq!(Options::parse("QQq").unwrap().quarantine, 0);
+
+        if super::options().quarantine == 0 {
+            return;
+        }
 
-        let d = super::thread_dir();
-        assert!((d.dir.borrow().total - d.dir.borrow().cache_len) <=
-                d.dir.borrow().free + 1);
+        unsafe {
+            let p1 = super::try_malloc(42, 0).unwrap();
+            let p2 = super::try_malloc(utils::page_size() << 1, 0).unwrap();
+            let p3 = super::try_malloc_key(42, 0).unwrap();
+
+            assert_eq!(super::try_free(p1), Ok(()));
+            assert_eq!(super::try_free(p2), Ok(()));
+            assert_eq!(super::try_free(p3), Ok(()));
+
+            // Either still in quarantine or already evicted.
+            assert!(super::try_free(p1).is_err());
+            assert!(super::try_free(p2).is_err());
+            assert!(super::try_protect(p3, Prot::Read).is_err());
+        }
     }
 
     #[test]
-    fn test_malloc_align() {
-        let mut sptr: *mut u8;
-        let mut kptr: *mut u8;
-        let mut align = 1;
-        let mut size;
-
-        while align < utils::page_size() {
-            size = thread_rng().gen_range(0_usize, utils::page_size() << 2);
+    fn test_quota() {
+        let size = utils::page_size() << 1;
+        let before = super::thread_usage().unwrap();
+        assert_eq!(before.quota, Quota::unlimited());
 
-            sptr = unsafe {
-                super::malloc(size, align)
-            };
-            kptr = unsafe {
-                super::malloc_key(size, align)
+        unsafe {
+            let p1 = super::try_malloc(size, 0).unwrap();
+            let usage = super::thread_usage().unwrap();
+            assert_eq!(usage.bytes, before.bytes + size);
+            assert!(usage.pages >= before.pages);
+            assert!(super::process_usage().bytes >= usage.bytes);
+
+            let quota = Quota {
+                bytes: Some(usage.bytes + size - 1),
+                pages: None
             };
-            assert!(!sptr.is_null() && !kptr.is_null());
-            assert!(sptr as usize % align == 0 && kptr as usize % align == 0);
+            assert!(super::set_thread_quota(quota).is_ok());
+            assert_eq!(super::try_malloc(size, 0),
+                       Err(MallocError::QuotaExceeded));
+            assert_eq!(super::thread_usage().unwrap().bytes, usage.bytes);
 
-            for i in 0_usize..size {
-                write_byte(sptr, i);
-                write_byte(kptr, i);
-            }
+            // Freed memory can be allocated again.
+            super::free(p1);
+            let p2 = super::try_malloc(size, 0).unwrap();
+            super::free(p2);
 
-            for i in 0_usize..size {
-                read_byte(sptr as *const u8, i);
-                read_byte(kptr as *const u8, i);
-            }
+            let quota = Quota {
+                bytes: None,
+                pages: Some(usage.pages)
+            };
+            assert!(super::set_thread_quota(quota).is_ok());
+            assert_eq!(super::try_malloc(size << 2, 0),
+                       Err(MallocError::QuotaExceeded));
+            assert!(super::set_thread_quota(Quota::unlimited()).is_ok());
+        }
 
-            unsafe {
-                super::free(sptr);
-                super::free(kptr);
-            }
+        assert_eq!(super::thread_usage().unwrap().bytes, before.bytes);
+    }
 
-            align *= 2;
+    #[test]
+    fn test_report_leaks() {
+        assert!(Options::parse("T").unwrap().track_leaks);
+        assert!(!Options::parse("Tt").unwrap().track_leaks);
+
+        if !super::options().track_leaks {
+            assert!(super::report_leaks().is_none());
+            return;
         }
 
+        let find = |ptr: *mut u8| {
+            super::report_leaks().unwrap().sites.iter()
+                .flat_map(|site| site.allocations.iter())
+                .find(|&&(addr, _, _)| addr == ptr as usize)
+                .map(|&(_, size, kind)| (size, kind))
+        };
+
         unsafe {
-            assert!(super::malloc(42, align).is_null());
-            assert!(super::malloc_key(42, align).is_null());
+            let p1 = super::malloc(42, 0);
+            let p2 = super::malloc(utils::page_size() << 3, 0);
+            let p3 = super::malloc_key(42, 0);
+            let p4 = super::realloc(super::malloc(42, 0), 84, 0);
+            let p5 = super::malloc(utils::page_size() + 42, 0);
+
+            assert_eq!(find(p1), Some((42, AllocKind::Chunk)));
+            assert_eq!(find(p2), Some((utils::page_size() << 3,
+                                       AllocKind::Large)));
+            assert_eq!(find(p3), Some((42, AllocKind::Key)));
+            assert_eq!(find(p4), Some((84, AllocKind::Chunk)));
+            assert_eq!(find(p5), Some((utils::page_size() + 42,
+                                       AllocKind::Medium)));
+
+            for &ptr in [p1, p2, p3, p4, p5].iter() {
+                super::free(ptr);
+                assert_eq!(find(ptr), None);
+            }
         }
     }
 
     #[test]
-    #[should_panic(message = "double free")]
-    fn test_double_free_chunk1() {
-        unsafe {
-            let p1 = super::malloc(42, 0);
-            let p2 = super::malloc(42, 0);
-            assert!(!p1.is_null() && !p2.is_null());
+    fn test_spares() {
+        assert_eq!(Options::parse("S").unwrap().spare_pages,
+                   super::DEFAULT_SPARE_PAGES << 1);
+        assert_eq!(Options::parse("sS").unwrap().spare_pages,
+                   super::DEFAULT_SPARE_PAGES);
+        assert_eq!(Options::parse("SSSSSSSSSS").unwrap().spare_pages,
+                   super::MAX_SPARE_PAGES);
+
+        let opts = super::options();
+        if opts.spare_pages < 6 || opts.quarantine != 0 {
+            return;
+        }
 
+        let size = utils::page_size() * 6;
+        unsafe {
+            let p1 = super::malloc(size, 0);
+            ptr::write_bytes(p1, 0x42, size);
             super::free(p1);
-            super::free(p1);
+            assert_eq!(super::try_free(p1), Err(MallocError::DoubleFree));
+
+            // The only spare mapping of this size is reused and wiped.
+            let p2 = super::calloc(1, size, 0);
+            assert_eq!(p2, p1);
+            assert!((0..size).all(|i| *p2.offset(i as isize) == 0));
+            super::free(p2);
+
+            let p3 = super::malloc_key(42, 0);
+            super::free(p3);
+            let p4 = super::malloc_key(42, 0);
+            assert_eq!(p4, p3);
+            super::protect_read(p4);
+            super::free(p4);
         }
+
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
     }
 
     #[test]
-    #[should_panic(message = "double free")]
-    fn test_double_free_chunk2() {
+    fn test_trim() {
+        // Enough chunks to grow the regions and a large object, all freed
+        // so that empty chunks are cached and its mapping kept as spare.
+        let page = utils::page_size();
+        let mut ptrs: Vec<*mut u8> = (0_usize..1024).map(|_| {
+            unsafe {
+                super::malloc(page >> 2, 0)
+            }
+        }).collect();
+        ptrs.push(unsafe {
+            super::malloc(page * 6, 0)
+        });
+        for &ptr in ptrs.iter() {
+            unsafe {
+                super::free(ptr);
+            }
+        }
+
+        let (total, used_pages, cached) = {
+            let d = super::thread_dir();
+            let dir = d.open().unwrap();
+            (dir.total, dir.used_pages, dir.cache_len + spares(&dir))
+        };
+        let pages = super::trim().unwrap();
+        {
+            let d = super::thread_dir();
+            let dir = d.open().unwrap();
+            assert_eq!(dir.cache_len, 0);
+            assert_eq!(spares(&dir), 0);
+            assert_eq!(dir.used_pages, used_pages - pages);
+            assert!(cached == 0 || pages > 0);
+            assert!(dir.total <= total);
+            assert!(dir.total == super::INITIAL_REGIONS ||
+                    dir.regions_used() * 4 > dir.total);
+        }
+        assert_eq!(super::trim().unwrap(), 0);
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
+
+        // Released memory is mapped again on demand.
         unsafe {
-            let p = super::malloc(42, 0);
-            assert!(!p.is_null());
-            super::free(p);
+            let p = super::malloc(page >> 2, 0);
+            write_byte(p, 42);
+            read_byte(p, 42);
             super::free(p);
         }
     }
 
     #[test]
-    #[should_panic(message = "invalid free")]
-    fn test_free_invalid1() {
-        let p: *mut u8 = 42 as *mut u8;
+    fn test_trim_epoch() {
+        let opts = super::options();
+        if opts.cache_size == 0 || opts.quarantine != 0 {
+            return;
+        }
+
         unsafe {
-            super::free(p);
+            super::free(super::malloc(42, 0));
+        }
+        {
+            let d = super::thread_dir();
+            let mut dir = d.open().unwrap();
+            assert!(dir.cache_len > 0);
+            // As if `trim_all` was called since, without trimming the
+            // threads of the other tests.
+            dir.trim_epoch = dir.trim_epoch.wrapping_sub(1);
+        }
+
+        // Trimmed on the next call to the allocator.
+        unsafe {
+            super::free(ptr::null_mut());
         }
+        let d = super::thread_dir();
+        let dir = d.open().unwrap();
+        assert_eq!(dir.cache_len, 0);
+        assert_eq!(dir.trim_epoch,
+                   super::TRIM_EPOCH.load(Ordering::SeqCst));
     }
 
     #[test]
-    #[should_panic(message = "invalid pointer")]
-    fn test_free_invalid2() {
+    fn test_mediums() {
+        let pagesize = utils::page_size();
+        let before = super::thread_usage().unwrap();
+
         unsafe {
-            // Stored in a chunk of size 64.
-            let p1 = super::malloc(42, 0);
-            let p2 = if mmap::mask_pointer(p1) == p1 {
-                p1.offset(64)
-            } else {
-                mmap::mask_pointer(p1)
-            };
-            super::free(p2);
+            // Slots of a span are separated by guard pages.
+            let mut ptrs: Vec<*mut u8> = (0_usize..8).map(|_| {
+                super::malloc((pagesize >> 1) + 42, 0)
+            }).collect();
+            for &p in ptrs.iter() {
+                assert_eq!(mmap::mask_pointer(p), p);
+                for &q in ptrs.iter() {
+                    let dist = cmp::max(p as usize, q as usize) -
+                        cmp::min(p as usize, q as usize);
+                    assert!(p == q || dist >= pagesize << 1);
+                }
+                for i in 0_usize..pagesize {
+                    write_byte(p, i);
+                }
+            }
+            assert_eq!(super::thread_usage().unwrap().bytes,
+                       before.bytes + 8 * pagesize);
+
+            // Grown in its size-class, then moved to a large object.
+            let mut p = super::malloc(pagesize * 3, 0);
+            for i in 0_usize..pagesize * 3 {
+                write_byte(p, i);
+            }
+            assert_eq!(super::realloc(p, pagesize << 2, 0), p);
+            p = super::realloc(p, pagesize * 5, 0);
+            for i in 0_usize..pagesize * 3 {
+                read_byte(p as *const u8, i);
+            }
+            ptrs.push(p);
+
+            // Freed from another thread, located through its span.
+            if let Some(pos) = ptrs.iter().position(|&p| {
+                super::span_base(p) != p
+            }) {
+                let addr = ptrs.remove(pos) as usize;
+                thread::spawn(move|| {
+                    super::free(addr as *mut u8);
+                }).join().unwrap();
+            }
+
+            let report = super::check_heap();
+            assert!(report.is_ok(), "{}", report);
+
+            for &p in ptrs.iter() {
+                for i in 0_usize..pagesize {
+                    read_byte(p as *const u8, i);
+                }
+                super::free(p);
+                if p == ptrs[0] {
+                    assert_eq!(super::try_free(p),
+                               Err(MallocError::DoubleFree));
+                }
+            }
         }
-    }
 
-    #[test]
-    #[should_panic(message = "invalid pointer")]
-    fn test_free_invalid3() {
-        unsafe {
-            let p = super::malloc(utils::page_size(), 0);
-            super::free(p.offset(64));
+        assert_eq!(super::thread_usage().unwrap().bytes, before.bytes);
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
+
+        // Empty spans are unmapped.
+        if super::options().quarantine == 0 {
+            let d = super::thread_dir();
+            let dir = d.open().unwrap();
+            assert!(dir.mediums1.iter().all(|&span| span == super::NO_REGION));
         }
     }
 
     #[test]
-    #[should_panic(message = "invalid pointer")]
-    fn test_protect_chunk() {
+    fn test_snapshot() {
+        let pagesize = utils::page_size();
+        let large = pagesize * 5 + 42;
+
         unsafe {
-            let p = super::malloc(42, 0);
-            assert!(!p.is_null());
-            super::protect_read(p);
+            let p1 = super::malloc(42, 0);
+            let p2 = super::malloc(large, 0);
+            let p3 = super::malloc_key(42, 0);
+            let p4 = super::malloc(pagesize + 42, 0);
+            super::protect_read(p3);
+
+            let snapshot = super::snapshot().unwrap();
+            assert!(snapshot.regions.windows(2).all(|pair| {
+                pair[0].address < pair[1].address
+            }));
+            let pages: usize = snapshot.regions.iter()
+                .map(|region| region.pages).sum();
+            assert_eq!(snapshot.pages(), pages);
+
+            let find = |ptr: *mut u8| snapshot.regions.iter().find(|region| {
+                region.address == mmap::mask_pointer(ptr) as usize
+            }).unwrap().clone();
+
+            let chunk = find(p1);
+            assert_eq!(chunk.kind, RegionKind::Chunk);
+            assert_eq!(chunk.size, super::chunk_size(super::slot_size(42)));
+            assert_eq!(chunk.pages, 3);
+            assert_eq!(chunk.slots, super::max_slot_index(chunk.size));
+            assert!(chunk.used_slots >= 1 && chunk.used_slots <= chunk.slots);
+            assert_eq!(chunk.prot, Prot::ReadWrite);
+
+            let object = find(p2);
+            assert_eq!((object.kind, object.size, object.pages, object.slots),
+                       (RegionKind::Large, large, 8, 0));
+            assert_eq!(object.prot, Prot::ReadWrite);
+
+            let key = find(p3);
+            assert_eq!((key.kind, key.size, key.pages, key.prot),
+                       (RegionKind::Key, 42, 3, Prot::Read));
+
+            let span = find(super::span_base(p4));
+            assert_eq!(span.kind, RegionKind::Span);
+            assert_eq!(span.size, pagesize << 1);
+            assert_eq!(span.slots, super::span_slots(pagesize << 1));
+            assert!(span.used_slots >= 1);
+
+            let json = snapshot.to_json();
+            assert!(json.starts_with(&format!(
+                "{{\"total\":{},\"regions\":[{{\"index\":",
+                snapshot.total)[..]));
+            assert!(snapshot.regions.iter().all(|region| {
+                region.index < snapshot.total &&
+                    region.occupancy.len() == region.slots &&
+                    region.occupancy.iter().filter(|&&used| used).count() ==
+                    region.used_slots
+            }));
+            assert!(json.ends_with("}]}"));
+            let key_json = format!(
+                "{{\"index\":{},\"kind\":\"key\",\"address\":{},\
+                 \"size\":42,\"pages\":3,\"slots\":0,\"used_slots\":0,\
+                 \"occupancy\":\"\",\"prot\":\"read\"}}", key.index,
+                key.address);
+            assert!(json.contains(&key_json[..]));
+            assert_eq!(json.matches("\"kind\"").count(),
+                       snapshot.regions.len());
+
+            let svg = super::render_svg().unwrap();
+            assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+            let key_label = format!("{:#x} key 42", key.address);
+            assert!(svg.contains(&key_label[..]));
+
+            for &p in [p1, p2, p3, p4].iter() {
+                super::free(p);
+            }
+
+            let after = super::snapshot().unwrap();
+            assert!(!after.regions.iter().any(|region| {
+                region.address == object.address &&
+                    region.kind == RegionKind::Large
+            }));
         }
     }
 
+    // Number of events passed to `count_event`.
+    static HOOK_EVENTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn count_event(_: &TraceEvent) {
+        HOOK_EVENTS.fetch_add(1, Ordering::SeqCst);
+    }
+
     #[test]
-    #[should_panic(message = "invalid pointer")]
-    fn test_protect_missing() {
-        let p: *mut u8 = 42 as *mut u8;
+    fn test_trace_events() {
+        assert!(Options::parse("E").unwrap().trace);
+        assert!(!Options::parse("Ee").unwrap().trace);
+
+        let event = TraceEvent {
+            op: TraceOp::Protect,
+            address: 0x1000,
+            size: 42,
+            region: Some(3),
+            prot: Some(Prot::Read)
+        };
+        assert_eq!(format!("{}", event),
+                   "protect 0x1000 size 42 region 3 prot Read");
 
-        unsafe {
-            super::protect_read(p);
+        if !super::options().trace {
+            assert!(super::trace_events().is_none());
+            return;
         }
+
+        super::set_trace_hook(Some(count_event));
+        let (p1, p2, k) = unsafe {
+            let p1 = super::malloc(42, 0);
+            let p2 = super::realloc(p1, 42 << 2, 0);
+            let k = super::malloc_key(42, 0);
+            super::protect_read(k);
+            super::free(k);
+            super::free(p2);
+            (p1, p2, k)
+        };
+        super::set_trace_hook(None);
+        assert!(HOOK_EVENTS.load(Ordering::SeqCst) >= 6);
+
+        let events = super::trace_events().unwrap();
+        let alloc = events.iter().rev().find(|event| {
+            event.op == TraceOp::Alloc && event.address == p1 as usize
+        }).unwrap();
+        assert_eq!(alloc.size, super::chunk_size(super::slot_size(42)));
+        assert!(alloc.region.is_some() && alloc.prot.is_none());
+        assert!(events.iter().any(|event| {
+            event.op == TraceOp::Realloc && event.address == p2 as usize
+        }));
+
+        let last: Vec<(TraceOp, usize, Option<Prot>)> = events.iter()
+            .skip(events.len() - 3)
+            .map(|event| (event.op, event.address, event.prot)).collect();
+        assert_eq!(last, vec![(TraceOp::Protect, k as usize, Some(Prot::Read)),
+                              (TraceOp::Free, k as usize, None),
+                              (TraceOp::Free, p2 as usize, None)]);
+        assert_eq!(events[events.len() - 2].size, 42);
     }
 
     #[test]
-    fn test_protect() {
-        // Actually can't test access violations with #[should_panic]
-        // without killing the test runner.
+    fn test_malloc_batch() {
+        let pagesize = utils::page_size();
+        let before = super::thread_usage().unwrap();
 
         unsafe {
-            let p = super::malloc_key(42, 0);
-            assert!(!p.is_null());
-
-            super::protect_write(p);
-            write_byte(p, 42);
+            // Large objects keep their own guard pages.
+            let large = pagesize * 5 + 42;
+            let mut ptrs = super::malloc_batch(large, 6, 0);
+            assert_eq!(ptrs.len(), 6);
+            for &p in ptrs.iter() {
+                assert_eq!(mmap::mask_pointer(p), p);
+                for &q in ptrs.iter() {
+                    let dist = cmp::max(p as usize, q as usize) -
+                        cmp::min(p as usize, q as usize);
+                    assert!(p == q || dist >= pagesize * 8);
+                }
+                for i in 0_usize..large {
+                    write_byte(p, i);
+                }
+            }
+            assert_eq!(super::thread_usage().unwrap().bytes,
+                       before.bytes + 6 * large);
+
+            // Other objects are allocated one by one.
+            ptrs.extend(super::malloc_batch(42, 4, 0));
+            ptrs.extend(super::malloc_batch(pagesize + 42, 2, 0));
+            ptrs.extend(super::malloc_batch(42, 2, pagesize << 1));
+            assert_eq!(ptrs.len(), 14);
+            assert_eq!(ptrs[12] as usize % (pagesize << 1), 0);
+            for &p in ptrs.iter() {
+                write_byte(p, 41);
+            }
 
-            super::protect_none(p);
+            assert!(super::malloc_batch(42, 2, 3).is_empty());
+            assert_eq!(super::try_malloc_batch(usize::MAX, 2, 0).err(),
+                       Some(MallocError::Overflow));
+            assert!(super::malloc_batch(large, 0, 0).is_empty());
 
-            super::protect_read(p);
-            read_byte(p as *const u8, 42);
+            let report = super::check_heap();
+            assert!(report.is_ok(), "{}", report);
 
-            super::protect_none(p);
-            super::free(p);
+            // Freed independently, in random order.
+            thread_rng().shuffle(&mut ptrs);
+            for &p in ptrs.iter() {
+                read_byte(p as *const u8, 41);
+                super::free(p);
+            }
         }
+
+        assert_eq!(super::thread_usage().unwrap().bytes, before.bytes);
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
     }
 
     #[test]
-    fn test_null() {
+    fn test_protect_metadata() {
+        assert!(Options::parse("P").unwrap().protect_metadata);
+        assert!(!Options::parse("Pp").unwrap().protect_metadata);
+
+        let opts = super::options();
+        let before = super::stats();
         unsafe {
-            super::free(ptr::null_mut());
-            super::protect_none(ptr::null_mut());
-            super::protect_read(ptr::null_mut());
-            super::protect_write(ptr::null_mut());
+            let p1 = super::malloc(42, 0);
+            let p2 = super::malloc(utils::page_size() << 1, 0);
+            super::free(p1);
+            super::free(p2);
         }
-    }
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
 
-    #[test]
-    fn test_zero() {
-        // Actually can't test dereferencing this pointer while expecting
-        // #[should_panic] without killing the test runner.
+        if let (Some(before), Some(after)) = (before, super::stats()) {
+            assert_eq!(after.metadata_prots > before.metadata_prots,
+                       opts.protect_metadata);
+        }
 
+        // Closed metadata of a directory of this test are inaccessible
+        // whatever the options.
         unsafe {
-            let p = super::malloc(0, 0);
-            assert!(!p.is_null());
-            super::free(p);
+            let local = LocalDir {
+                dir: Dir::init().unwrap()
+            };
+            let dir = local.dir as *const u8;
+            let regions = (*local.dir).regions as *const u8;
+            assert!(super::metadata_prot(local.dir, false).is_ok());
+            assert!(!readable(dir) && !readable(regions));
+            assert!(super::metadata_prot(local.dir, true).is_ok());
+            assert!(readable(dir) && readable(regions));
         }
     }
 
-    #[test]
-    fn test_realloc() {
-        let size1 = thread_rng().gen_range(0_usize, utils::page_size() << 2);
-        let size2 = thread_rng().gen_range(0_usize, utils::page_size() << 2);
+    // Return `true` if the byte at `ptr` is readable. Writing it to a pipe
+    // fails instead of faulting when it is not.
+    fn readable(ptr: *const u8) -> bool {
+        use libc::funcs::posix88::unistd::{close, pipe, write};
 
+        let mut fds = [0; 2];
         unsafe {
-            let mut p1 = super::malloc(size1, 0);
-            let mut p2 = super::malloc_key(size1, 0);
-            assert!(!p1.is_null());
-            assert!(!p2.is_null());
+            assert_eq!(pipe(fds.as_mut_ptr()), 0);
+            let rv = write(fds[1], ptr as *const libc::c_void, 1);
+            close(fds[0]);
+            close(fds[1]);
+            rv == 1
+        }
+    }
 
-            for i in 0_usize..size1 {
-                write_byte(p1, i);
-                write_byte(p2, i);
+    #[test]
+    fn test_check_heap() {
+        let mut ptrs = Vec::new();
+        unsafe {
+            for i in 0_usize..512 {
+                ptrs.push(super::malloc(i * 7, 0));
             }
+            ptrs.push(super::malloc(utils::page_size() << 2, 0));
+            ptrs.push(super::malloc_key(42, 0));
+        }
 
-            p1 = super::realloc(p1, size2, 0);
-            p2 = super::realloc_key(p2, size2, 0);
-            assert!(!p1.is_null());
-            assert!(!p2.is_null());
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
+        assert!(report.regions > 0);
 
-            for i in 0_usize..cmp::min(size1, size2) {
-                read_byte(p1 as *const u8, i);
-                read_byte(p2 as *const u8, i);
+        for i in (0_usize..ptrs.len()).step_by(3) {
+            unsafe {
+                super::free(ptrs[i]);
             }
-
-            if size2 > size1 {
-                for i in size1..size2 {
-                    write_byte(p1, i);
-                    write_byte(p2, i);
-                }
-
-                for i in 0_usize..size2 {
-                    read_byte(p1 as *const u8, i);
-                    read_byte(p2 as *const u8, i);
+        }
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
+
+        // Corrupt the regions count.
+        let dir = super::thread_dir();
+        dir.local().unwrap().free += 1;
+        let report = super::check_heap();
+        assert!(!report.is_ok());
+        assert!(format!("{}", report).contains("free regions"));
+        dir.local().unwrap().free -= 1;
+
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            if i % 3 != 0 {
+                unsafe {
+                    super::free(ptr);
                 }
             }
-
-            super::free(p1);
-            super::free(p2);
         }
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
     }
 
     #[test]
-    fn test_realloc_zero() {
-        let size = thread_rng().gen_range(0_usize, utils::page_size() << 2);
-
-        unsafe {
-            let mut p1 = super::malloc(size, 0);
-            assert!(!p1.is_null());
+    fn test_region_relocation() {
+        // Enough chunks of a few size-classes to grow the regions, freed in
+        // random order so that listed regions are moved and relinked.
+        let mut ptrs: Vec<*mut u8> = (0_usize..4096).map(|i| {
+            unsafe {
+                super::malloc(16 << (i % 5), 0)
+            }
+        }).collect();
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
 
-            for i in 0_usize..size {
-                write_byte(p1, i);
+        thread_rng().shuffle(&mut ptrs);
+        for (i, &ptr) in ptrs.iter().enumerate() {
+            unsafe {
+                super::free(ptr);
             }
+            if i % 1024 == 0 {
+                let report = super::check_heap();
+                assert!(report.is_ok(), "{}", report);
+            }
+        }
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
+    }
 
-            p1 = super::realloc(p1, 0, 0);
-            assert!(!p1.is_null());
+    #[test]
+    fn test_region_hash_keys() {
+        let keys = || {
+            let d = super::thread_dir();
+            let dir = d.open().unwrap();
+            dir.hash_keys
+        };
+        let local = keys();
+        let remote = thread::spawn(move|| keys()).join().unwrap();
+        assert!(local != remote && local != (0, 0));
+    }
 
-            let p2 = super::realloc(p1, 0, 0);
-            assert!(!p2.is_null());
+    #[test]
+    fn test_chunk_slots() {
+        let mut rng = PoolRng::new();
+        let mut object = 0_u8;
+        let mut region: Region = unsafe { mem::zeroed() };
+        region.init(&mut object, super::min_chunk_size(), RegionType::Chunk,
+                    0);
+        let count = region.slot_count();
+        assert!(region.is_empty_chunk() && region.check_mapping().is_ok());
+
+        let slots: HashSet<usize> = (0_usize..count).map(|_| {
+            region.take_chunk_slot(&mut rng)
+        }).collect();
+        assert_eq!(slots.len(), count);
+        assert!(slots.iter().all(|&i| i < count));
+        assert!(region.is_full_chunk() && region.check_mapping().is_ok());
+
+        // The only free slot is found from any random slot.
+        let index = count / 2 + 1;
+        for _ in 0_usize..16 {
+            assert!(region.free_slot(index * region.size).is_ok());
+            assert_eq!(region.used_slots(), count - 1);
+            assert_eq!(region.take_chunk_slot(&mut rng), index);
+        }
+        assert_eq!(region.free_slot(index * region.size + 1),
+                   Err(MallocError::UnknownPointer));
 
-            assert_eq!(p1, p2);
+        assert!(region.free_slot(0).is_ok());
+        assert_eq!(region.free_slot(0), Err(MallocError::DoubleFree));
+        region.free_slots += 1;
+        assert!(region.check_mapping().is_err());
+    }
 
-            super::free(p1);
-            super::free(p2);
-        }
+    #[test]
+    fn test_map_error() {
+        use std::io;
+        use mmap::MapError;
+
+        let err = io::Error::from_raw_os_error(22);
+        assert_eq!(MallocError::from(MapError::Sys(err)),
+                   MallocError::SysError { errno: 22 });
+        let err = io::Error::from_raw_os_error(12);
+        assert_eq!(MallocError::from(MapError::Map(err)),
+                   MallocError::OutOfMemory);
+        assert_eq!(format!("{}", MallocError::SysError { errno: 22 }),
+                   "system call failed with errno 22");
     }
 
     #[test]
-    fn test_calloc() {
-        unsafe {
-            // Large
-            let mut p = super::calloc(1, utils::page_size(), 0);
-            assert!(!p.is_null());
+    fn test_options() {
+        let opts = Options::parse("").unwrap();
+        assert_eq!(opts, Options::new());
 
-            for i in 0_usize..utils::page_size() {
-                assert_eq!(*p.offset(i as isize), 0);
-            }
-            super::free(p);
+        let opts = Options::parse("jDLl<C").unwrap();
+        assert!(!opts.junk && opts.stats && !opts.mlock && opts.canaries);
+        assert_eq!(opts.cache_size, super::DEFAULT_CACHE_SIZE >> 1);
 
-            // Chunk
-            p = super::calloc(1, 42, 0);
-            assert!(!p.is_null());
+        let opts = Options::parse(">>>>>>>>>>").unwrap();
+        assert_eq!(opts.cache_size, super::MAX_CACHE_SIZE);
 
-            for i in 0_usize..42 {
-                assert_eq!(*p.offset(i as isize), 0);
-            }
-            super::free(p);
+        assert_eq!(Options::parse("Jx"), Err('x'));
+
+        // Options are frozen after the first allocation.
+        unsafe {
+            super::free(super::malloc(42, 0));
         }
+        assert!(!super::set_options(Options::new()));
+        assert_eq!(super::options(), super::options());
     }
 
     #[test]
-    #[should_panic(message = "integer overflow")]
-    fn test_calloc_overflow() {
+    fn test_stats() {
+        if !super::options().stats {
+            assert!(super::stats().is_none() && super::stats_all().is_none());
+            return;
+        }
+
+        let before = super::stats().unwrap();
         unsafe {
-            super::calloc(usize::MAX, 2, 0);
+            let p1 = super::malloc(42, 0);
+            let p2 = super::malloc(utils::page_size() << 3, 0);
+            let p3 = super::malloc_key(42, 0);
+            let p4 = super::malloc(utils::page_size(), 0);
+            super::protect_read(p3);
+
+            let now = super::stats().unwrap();
+            assert_eq!(now.chunks, before.chunks + 1);
+            assert_eq!(now.larges, before.larges + 2);
+            assert_eq!(now.larges_bytes,
+                       before.larges_bytes + (utils::page_size() << 3) + 42);
+            assert_eq!(now.mediums, before.mediums + 1);
+            assert_eq!(now.mediums_bytes[0].1,
+                       before.mediums_bytes[0].1 + utils::page_size());
+            assert_eq!(now.keys, before.keys + 1);
+            assert_eq!(now.prot_reads, before.prot_reads + 1);
+            let bytes = |stats: &Stats| stats.chunks_bytes.iter()
+                .fold(0, |acc, &(_, bytes)| acc + bytes);
+            assert_eq!(bytes(&now), bytes(&before) +
+                       super::chunk_size(super::slot_size(42)));
+
+            let all = super::stats_all().unwrap();
+            assert!(all.larges >= now.larges && all.keys >= now.keys);
+
+            super::free(p1);
+            super::free(p2);
+            super::free(p3);
+            super::free(p4);
         }
+
+        let after = super::stats().unwrap();
+        assert_eq!(after.chunks, before.chunks);
+        assert_eq!(after.larges, before.larges);
+        assert_eq!(after.keys, before.keys);
+        assert_eq!(after.mediums, before.mediums);
+    }
+
+    #[test]
+    fn test_stats_prometheus() {
+        let stats = Stats {
+            larges: 2,
+            larges_bytes: 8192,
+            keys: 1,
+            mediums: 1,
+            mediums_bytes: vec![(4096, 4096), (8192, 0), (16384, 0)],
+            spans: 1,
+            chunks: 3,
+            chunks_bytes: vec![(16, 32), (32, 32)],
+            cached: 0,
+            cache_hits: 4,
+            cache_misses: 5,
+            spares: 1,
+            spare_hits: 7,
+            prot_reads: 1,
+            prot_writes: 2,
+            prot_nones: 3,
+            metadata_prots: 6,
+            locked_bytes: 16384
+        };
+        let text = stats.to_prometheus();
+
+        assert!(text.contains("# TYPE tars_malloc_larges gauge\n\
+                               tars_malloc_larges 2\n"));
+        assert!(text.contains("tars_malloc_chunks_bytes\
+                               {size_class=\"16\"} 32\n"));
+        assert!(text.contains("tars_malloc_mediums_bytes\
+                               {size_class=\"8192\"} 0\n"));
+        assert!(text.contains("tars_malloc_spans 1\n"));
+        assert!(text.contains("tars_malloc_cache_hits_total 4\n"));
+        assert!(text.contains("tars_malloc_spare_hits_total 7\n"));
+        assert!(text.contains("tars_malloc_protections_total\
+                               {prot=\"none\"} 3\n"));
+        assert!(text.contains("tars_malloc_metadata_protections_total 6\n"));
+        assert!(text.contains("tars_malloc_locked_bytes 16384\n"));
+        assert!(text.lines().all(|line| line.starts_with("# ") ||
+                                 line.starts_with("tars_malloc_")));
     }
 
     #[test]
@@ -1950,6 +6431,130 @@ mod test {
         assert!(!addrs.contains(&0));
     }
 
+    #[test]
+    fn test_remote_free() {
+        let size = utils::page_size() << 1;
+        let p = unsafe {
+            super::malloc(size, 0)
+        };
+        assert!(!p.is_null());
+
+        // Freed by another thread, queued until this thread calls the
+        // allocator again.
+        let addr = p as usize;
+        thread::spawn(move|| {
+            unsafe {
+                super::free(addr as *mut u8);
+            }
+        }).join().unwrap();
+
+        unsafe {
+            super::free(ptr::null_mut());
+        }
+
+        let d = super::thread_dir();
+        let dir = d.open().unwrap();
+        assert_eq!(dir.total - spares(&dir), dir.free);
+    }
+
+    #[test]
+    fn test_remote_free_wipe() {
+        let size = utils::page_size() * 6;
+        let (p1, p2) = unsafe {
+            (super::malloc(42, 0), super::malloc(size, 0))
+        };
+        unsafe {
+            ptr::write_bytes(p1, 0x42, 42);
+            ptr::write_bytes(p2, 0x42, size);
+        }
+
+        let (addr1, addr2) = (p1 as usize, p2 as usize);
+        thread::spawn(move|| {
+            unsafe {
+                super::free(addr1 as *mut u8);
+                super::free(addr2 as *mut u8);
+            }
+        }).join().unwrap();
+
+        // Wiped by the freeing thread, before this thread deallocates them
+        // on its next call.
+        unsafe {
+            assert!((0_isize..42).all(|i| *p1.offset(i) == 0));
+            assert!((0..size as isize).all(|i| *p2.offset(i) == 0));
+            super::free(ptr::null_mut());
+        }
+        let report = super::check_heap();
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test]
+    fn test_remote_double_free() {
+        let addr = unsafe {
+            super::malloc(42, 0)
+        } as usize;
+        let (tx_freed, rx_freed) = channel();
+        let (tx_drained, rx_drained) = channel();
+
+        let remote = thread::spawn(move|| {
+            unsafe {
+                assert_eq!(super::try_free(addr as *mut u8), Ok(()));
+                assert_eq!(super::try_free(addr as *mut u8), Ok(()));
+            }
+            tx_freed.send(()).unwrap();
+            rx_drained.recv().unwrap();
+
+            // Reported to the freeing thread without failing its calls.
+            unsafe {
+                assert_eq!(super::try_free(ptr::null_mut()), Ok(()));
+            }
+            super::take_deferred_errors()
+        });
+
+        rx_freed.recv().unwrap();
+        unsafe {
+            assert_eq!(super::try_free(ptr::null_mut()), Ok(()));
+        }
+        tx_drained.send(()).unwrap();
+        assert_eq!(remote.join().unwrap(), vec![MallocError::DoubleFree]);
+        assert!(super::take_deferred_errors().is_empty());
+    }
+
+    #[test]
+    fn test_orphan_adoption() {
+        let size = utils::page_size() << 1;
+        let (tx, rx) = channel();
+
+        // The allocating thread exits while its objects are still alive.
+        thread::spawn(move|| {
+            let p1 = unsafe {
+                super::malloc(42, 0)
+            };
+            let p2 = unsafe {
+                super::malloc(size, 0)
+            };
+            for i in 0_usize..42 {
+                write_byte(p1, i);
+            }
+            for i in 0_usize..size {
+                write_byte(p2, i);
+            }
+            tx.send((p1 as usize, p2 as usize)).unwrap();
+        }).join().unwrap();
+
+        let (p1, p2) = rx.recv().unwrap();
+        for i in 0_usize..42 {
+            read_byte(p1 as *const u8, i);
+        }
+        for i in 0_usize..size {
+            read_byte(p2 as *const u8, i);
+        }
+
+        unsafe {
+            super::free(p1 as *mut u8);
+            super::free(p2 as *mut u8);
+        }
+    }
+
     #[test]
     #[should_panic(message = "buffer overrun")]
     fn test_overrun1() {
@@ -2001,6 +6606,26 @@ mod test {
         })
     }
 
+    // Take then free a slot of a half full chunk, the bookkeeping done for
+    // each chunk slot allocation.
+    #[bench]
+    fn bench_chunk_slots(b: &mut Bencher) {
+        let mut rng = PoolRng::new();
+        let mut object = 0_u8;
+        let mut region: Region = unsafe { mem::zeroed() };
+        region.init(&mut object, super::chunk_size(42), RegionType::Chunk,
+                    0);
+        for _ in 0_usize..region.slot_count() / 2 {
+            region.take_chunk_slot(&mut rng);
+        }
+        b.iter(|| {
+            let index = region.take_chunk_slot(&mut rng);
+            assert!(!region.is_full_chunk());
+            region.free_slot(index * region.size).unwrap();
+            region.is_empty_chunk()
+        })
+    }
+
     #[bench]
     fn bench_chunk_alloc(b: &mut Bencher) {
         b.iter(|| {
@@ -2011,6 +6636,38 @@ mod test {
         })
     }
 
+    // Allocate then free a batch of objects of `size` bytes, typical of
+    // crypto states. The density of their size-class, pages mapped per
+    // batch, is printed along with the density of the former power of two
+    // size-classes, `test_batch_density` checks it.
+    fn bench_batch_alloc(b: &mut Bencher, size: usize) {
+        let mut ptrs = vec![ptr::null_mut(); 256];
+        b.bytes = (ptrs.len() * size) as u64;
+        b.iter(|| {
+            unsafe {
+                for p in ptrs.iter_mut() {
+                    *p = super::malloc(size, 0);
+                }
+                for &p in ptrs.iter() {
+                    super::free(p);
+                }
+            }
+        });
+        println!("{} bytes: {} pages per {} objects, {} with power of two \
+                  size-classes", size, batch_pages(size, ptrs.len()),
+                 ptrs.len(), power_of_two_pages(size, ptrs.len()));
+    }
+
+    #[bench]
+    fn bench_poly1305_batch_alloc(b: &mut Bencher) {
+        bench_batch_alloc(b, 68);
+    }
+
+    #[bench]
+    fn bench_curve41417_batch_alloc(b: &mut Bencher) {
+        bench_batch_alloc(b, 208);
+    }
+
     #[bench]
     fn bench_page_alloc(b: &mut Bencher) {
         let pagesize = utils::page_size();
@@ -1,5 +1,5 @@
 //! mmap wrapper
-use libc::consts::os::posix88::{MAP_ANON, MAP_PRIVATE, MAP_FAILED,
+use libc::consts::os::posix88::{MAP_ANON, MAP_PRIVATE, MAP_FAILED, MAP_FIXED,
                                 PROT_READ, PROT_WRITE, PROT_NONE};
 use libc::funcs::posix88::mman;
 use libc::types::common::c95::c_void;
@@ -11,21 +11,10 @@ use std::sync::{Once, ONCE_INIT};
 
 use num::ToPrimitive;
 
+use malloc;
 use utils;
 
 
-/// Used to prevent `mlock` calls on allocated regions. This may be
-/// needed on systems with heavy restrictions on `RLIMIT_MEMLOCK`
-/// (see `man mprotect` for more details).
-///
-/// Set `false` to this constant or pass `--cfg feature="no_mlock"` to
-/// `rustc` or use `cargo build --features no_mlock` with cargo to disable
-/// `mlock` calls (it might be needed in virtual environments).
-#[cfg(feature = "no_mlock")]
-const USE_MLOCK: bool = false;
-#[cfg(not(feature = "no_mlock"))]
-const USE_MLOCK: bool = true;
-
 pub const MIN_ALIGN: usize = 16;
 
 
@@ -53,8 +42,36 @@ pub fn mask_pointer<T>(ptr: *mut T) -> *mut T {
 }
 
 #[inline]
-fn page_round(size: usize) -> usize {
-    size.checked_add(page_mask()).unwrap() & !page_mask()
+pub fn page_round(size: usize) -> Option<usize> {
+    size.checked_add(page_mask()).map(|sz| sz & !page_mask())
+}
+
+
+/// Errors returned by mapping operations
+#[derive(Debug)]
+pub enum MapError {
+    /// `mmap` failed to map new pages.
+    Map(io::Error),
+    /// `mlock` failed to lock pages in memory.
+    Lock(io::Error),
+    /// Any other system call failed (`mprotect`, `munmap`,...).
+    Sys(io::Error),
+    /// Requested size overflows.
+    Overflow
+}
+
+impl MapError {
+    fn last_map() -> MapError {
+        MapError::Map(io::Error::last_os_error())
+    }
+
+    fn last_lock() -> MapError {
+        MapError::Lock(io::Error::last_os_error())
+    }
+
+    fn last_sys() -> MapError {
+        MapError::Sys(io::Error::last_os_error())
+    }
 }
 
 
@@ -64,13 +81,14 @@ fn page_round(size: usize) -> usize {
 pub enum RangePos {
     Start,
     End,
-    Rand
+    /// At an offset derived from the given random word.
+    Rand(usize)
 }
 
 
 /// Memory protection flags. `None` means no `Read` and no `Write`
 /// allowed.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Prot {
     None,
     Read,
@@ -97,23 +115,43 @@ impl Prot {
 ///
 /// `size` is the size of memory to be allocated, it is rounded to the next
 /// page size multiple. `align` is equal to 0 if no alignment hint is provided,
-/// otherwise it must be a power of two smaller than the current page size.
+/// otherwise it must be a power of two. Alignments of at least the page
+/// size are obtained by over-reserving the mapping and trimming its excess
+/// pages, the buffer is then always positioned at the start of the region.
 /// `fill` indicates if allocated pages must be filled with a specified byte
 /// value. `prot` set the initial pages protections. `pos` hints how the
-/// buffer should be positionned inside the allocated region. This function
-/// `panic!` on error, only valid non-null pointers are returned.
+/// buffer should be positionned inside the allocated region. Only valid
+/// non-null pointers are returned on success, on error the pages that
+/// may have been mapped are released before returning.
 pub unsafe fn allocate(size: usize, align: usize, fill: Option<u8>,
-                       prot: Prot, pos: RangePos) -> *mut u8 {
-    let region_sz = page_round(size);
-    let full_sz = region_sz.checked_add((2 * page_size())).unwrap();
+                       prot: Prot,
+                       pos: RangePos) -> Result<*mut u8, MapError> {
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
+    let full_sz = try!(region_sz.checked_add(2 * page_size())
+                       .ok_or(MapError::Overflow));
 
      // Check align is compatible.
     if align > 0 {
-        assert!(align < page_size() && align.is_power_of_two());
+        assert!(align.is_power_of_two());
     }
 
+    // Over-page alignments leave no room to move the buffer.
+    let pos = if align >= page_size() {
+        RangePos::Start
+    } else {
+        pos
+    };
+    // Excess reserved so that an aligned region can be carved out.
+    let slack = if align > page_size() {
+        align - page_size()
+    } else {
+        0
+    };
+    let reserve_sz = try!(full_sz.checked_add(slack)
+                          .ok_or(MapError::Overflow));
+
     let align_sz = match (align, pos) {
-        (0, RangePos::Rand) => MIN_ALIGN,
+        (0, RangePos::Rand(_)) => MIN_ALIGN,
         (0, RangePos::End) => MIN_ALIGN,
         (_, RangePos::Start) => 1, // Aligned on page's size
         (_, _) => cmp::max(align, MIN_ALIGN)
@@ -125,69 +163,207 @@ pub unsafe fn allocate(size: usize, align: usize, fill: Option<u8>,
     // to make a read on a write protection but it is counter to the
     // practical behavior where PROT_WRITE usually implies PROT_READ.
     let object = mman::mmap(null_addr as *mut c_void,
-                            full_sz as size_t,
+                            reserve_sz as size_t,
                             Prot::to_mprot(prot),
                             MAP_ANON | MAP_PRIVATE |
                             map_imp::additional_map_flags(),
                             -1,
                             0);
     if object == MAP_FAILED {
-        panic!("mmap failed: {}", io::Error::last_os_error());
+        return Err(MapError::last_map());
     }
 
-    // Use first and last pages as guarded pages.
-    let mut rv = mman::mprotect(object, page_size() as size_t, PROT_NONE);
-    if rv != 0 {
-        panic!("mprotect failed: {}", io::Error::last_os_error());
+    let start = try!(trim(object as *mut u8, reserve_sz, full_sz, align));
+
+    if let Err(err) = setup_mapping(start, full_sz) {
+        // munmap also unlocks pages that might have been locked.
+        mman::munmap(start as *mut c_void, full_sz as size_t);
+        return Err(err);
     }
 
-    let start = object as *mut u8;
+    if let Some(fill_byte) = fill {
+        ptr::write_bytes(start.offset(page_size() as isize),
+                         fill_byte, region_sz);
+    }
 
-    let lp_offset = full_sz.to_isize().unwrap().checked_sub(
-        page_size().to_isize().unwrap()).unwrap();
-    rv = mman::mprotect(start.offset(lp_offset) as *mut c_void,
-                        page_size() as size_t, PROT_NONE);
-    if rv != 0 {
-        panic!("mprotect failed: {}", io::Error::last_os_error());
+    Ok(place(start.offset(page_size() as isize), region_sz, size, align_sz,
+             pos))
+}
+
+/// Allocate memory for several buffers at once
+///
+/// Same as calling `allocate` `count` times with an `align` of 0 and a
+/// `pos` of `RangePos::Start`, but the buffers are all carved out of a
+/// single mapping. Each one lies between its own two guard pages so that
+/// it can later be released on its own by `deallocate`, adjacent guard
+/// pages are protected together and the whole mapping is advised at once.
+/// Buffers are returned in address order, on error the whole mapping is
+/// released.
+pub unsafe fn allocate_batch(size: usize, count: usize, fill: Option<u8>,
+                             prot: Prot) -> Result<Vec<*mut u8>, MapError> {
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
+    let full_sz = try!(region_sz.checked_add(2 * page_size())
+                       .ok_or(MapError::Overflow));
+    let batch_sz = try!(full_sz.checked_mul(count)
+                        .ok_or(MapError::Overflow));
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let null_addr: *const u8 = ptr::null();
+    let object = mman::mmap(null_addr as *mut c_void,
+                            batch_sz as size_t,
+                            Prot::to_mprot(prot),
+                            MAP_ANON | MAP_PRIVATE |
+                            map_imp::additional_map_flags(),
+                            -1,
+                            0);
+    if object == MAP_FAILED {
+        return Err(MapError::last_map());
     }
 
-    let mut region = start.offset(page_size() as isize);
+    let start = object as *mut u8;
+    if let Err(err) = setup_batch(start, full_sz, count) {
+        // munmap also unlocks pages that might have been locked.
+        mman::munmap(start as *mut c_void, batch_sz as size_t);
+        return Err(err);
+    }
 
-    // mlock
-    if USE_MLOCK {
-        // Do not lock guarded pages.
-        rv = mman::mlock(region as *const c_void, region_sz as size_t);
+    let regions: Vec<*mut u8> = (0_usize..count).map(|i| {
+        start.offset((i * full_sz + page_size()) as isize)
+    }).collect();
+    if let Some(fill_byte) = fill {
+        for &region in regions.iter() {
+            ptr::write_bytes(region, fill_byte, region_sz);
+        }
+    }
+    Ok(regions)
+}
+
+// Set guard pages, lock and advise `count` consecutive areas of `full_sz`
+// bytes each (including their two guard pages) mapped at `start`.
+unsafe fn setup_batch(start: *mut u8, full_sz: usize,
+                      count: usize) -> Result<(), MapError> {
+    let region_sz = full_sz - 2 * page_size();
+    let batch_sz = full_sz * count;
+
+    // The trailing guard page of an area and the leading guard page of the
+    // next one are protected at once.
+    for i in 0_usize..count + 1 {
+        let (offset, guard_sz) = match i {
+            0 => (0, page_size()),
+            i if i == count => (batch_sz - page_size(), page_size()),
+            i => (i * full_sz - page_size(), 2 * page_size())
+        };
+        let rv = mman::mprotect(start.offset(offset as isize) as *mut c_void,
+                                guard_sz as size_t, PROT_NONE);
         if rv != 0 {
-            panic!("mlock failed: {}", io::Error::last_os_error());
+            return Err(MapError::last_sys());
         }
     }
 
-    // madvise and minherit
-    self::adv_imp::madvise(region, region_sz);
-    self::inh_imp::minherit(start, full_sz);
+    // Do not lock guarded pages.
+    if malloc::options().mlock {
+        for i in 0_usize..count {
+            let region = start.offset((i * full_sz + page_size()) as isize);
+            let rv = mman::mlock(region as *const c_void,
+                                 region_sz as size_t);
+            if rv != 0 {
+                return Err(MapError::last_lock());
+            }
+        }
+    }
+
+    try!(self::adv_imp::madvise(start, batch_sz));
+    self::inh_imp::minherit(start, batch_sz)
+}
+
+// Release the pages of the area of `reserve_sz` bytes mapped at `object`
+// located before and after the area of `full_sz` bytes whose first page
+// following its leading guard page is aligned on `align`. Return the
+// start of the remaining area, or `object` if there is nothing to trim.
+unsafe fn trim(object: *mut u8, reserve_sz: usize, full_sz: usize,
+               align: usize) -> Result<*mut u8, MapError> {
+    if align <= page_size() {
+        return Ok(object);
+    }
 
+    let region = (object as usize + page_size() + align - 1) & !(align - 1);
+    let head_sz = region - page_size() - object as usize;
+    let tail_sz = reserve_sz - head_sz - full_sz;
+    let start = object.offset(head_sz as isize);
+
+    if head_sz > 0 &&
+        mman::munmap(object as *mut c_void, head_sz as size_t) != 0 {
+        let err = MapError::last_sys();
+        mman::munmap(object as *mut c_void, reserve_sz as size_t);
+        return Err(err);
+    }
+    if tail_sz > 0 &&
+        mman::munmap(start.offset(full_sz as isize) as *mut c_void,
+                     tail_sz as size_t) != 0 {
+        let err = MapError::last_sys();
+        mman::munmap(start as *mut c_void, (full_sz + tail_sz) as size_t);
+        return Err(err);
+    }
+    Ok(start)
+}
+
+// Position a buffer of `size` bytes aligned on `align_sz` in `region` of
+// `region_sz` bytes according to `pos`.
+unsafe fn place(region: *mut u8, region_sz: usize, size: usize,
+                align_sz: usize, pos: RangePos) -> *mut u8 {
     match pos {
-        _ if size == region_sz => (),
+        _ if size == region_sz => region,
         RangePos::End => {
             let offset = (region_sz - size) & !(align_sz - 1);
-            region = region.offset(offset as isize);
+            region.offset(offset as isize)
         },
-        RangePos::Rand => {
-            let r = (region_sz - size).checked_div(
-                align_sz).unwrap().to_isize().unwrap();
-            let offset = utils::gen_range(&mut utils::rng(), 0, r) *
-                align_sz as isize;
-            region = region.offset(offset);
+        RangePos::Rand(random) => {
+            let r = (region_sz - size).checked_div(align_sz).unwrap();
+            let offset = random.checked_rem(r).unwrap_or(0) * align_sz;
+            region.offset(offset.to_isize().unwrap())
         },
-        _ => ()
+        _ => region
     }
+}
 
-    if let Some(fill_byte) = fill {
-        ptr::write_bytes(start.offset(page_size() as isize),
-                         fill_byte, region_sz);
+// Set guard pages, lock and advise a newly mapped area starting at
+// `start` of size `full_sz` (including its two guard pages).
+unsafe fn setup_mapping(start: *mut u8,
+                        full_sz: usize) -> Result<(), MapError> {
+    let region_sz = full_sz - 2 * page_size();
+
+    // Use first and last pages as guarded pages.
+    let mut rv = mman::mprotect(start as *mut c_void, page_size() as size_t,
+                                PROT_NONE);
+    if rv != 0 {
+        return Err(MapError::last_sys());
     }
 
-    region
+    let lp_offset = full_sz.to_isize().unwrap().checked_sub(
+        page_size().to_isize().unwrap()).unwrap();
+    rv = mman::mprotect(start.offset(lp_offset) as *mut c_void,
+                        page_size() as size_t, PROT_NONE);
+    if rv != 0 {
+        return Err(MapError::last_sys());
+    }
+
+    let region = start.offset(page_size() as isize);
+
+    // mlock, may be disabled on systems with heavy restrictions on
+    // `RLIMIT_MEMLOCK` (see `man mlock` for more details).
+    if malloc::options().mlock {
+        // Do not lock guarded pages.
+        rv = mman::mlock(region as *const c_void, region_sz as size_t);
+        if rv != 0 {
+            return Err(MapError::last_lock());
+        }
+    }
+
+    // madvise and minherit
+    try!(self::adv_imp::madvise(region, region_sz));
+    self::inh_imp::minherit(start, full_sz)
 }
 
 /// Deallocate memory
@@ -195,15 +371,16 @@ pub unsafe fn allocate(size: usize, align: usize, fill: Option<u8>,
 /// `ptr` must be a pointer returned by `allocate` where `size` was
 /// used as argument. `fill` indicates if the memory must be filled with
 /// a specified byte value before deallocation. This function returns
-/// immediately without any effect if `ptr` is `NULL` and `panic!` on
-/// error.
-pub unsafe fn deallocate(ptr: *mut u8, size: usize, fill: Option<u8>) {
+/// immediately without any effect if `ptr` is `NULL`.
+pub unsafe fn deallocate(ptr: *mut u8, size: usize,
+                         fill: Option<u8>) -> Result<(), MapError> {
     if ptr.is_null() {
-        return;
+        return Ok(());
     }
 
-    let region_sz = page_round(size);
-    let full_sz = region_sz.checked_add((2 * page_size())).unwrap();
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
+    let full_sz = try!(region_sz.checked_add(2 * page_size())
+                       .ok_or(MapError::Overflow));
 
     // Assuming the pointer is rightly located (as it should) in the first
     // page after the initial page guard.
@@ -211,42 +388,321 @@ pub unsafe fn deallocate(ptr: *mut u8, size: usize, fill: Option<u8>) {
 
     if let Some(fill_byte) = fill {
         // Make sure the region can be written.
-        protect(region, region_sz, Prot::Write);
+        try!(protect(region, region_sz, Prot::Write));
 
         utils::set_memory(region, fill_byte, region_sz);
     }
 
     // munlock
-    if USE_MLOCK {
+    if malloc::options().mlock {
         let rv = mman::munlock(region as *const c_void, region_sz as size_t);
         if rv != 0 {
-            panic!("munlock failed: {}", io::Error::last_os_error());
+            return Err(MapError::last_sys());
         }
     }
 
     let start = region.offset(-(page_size() as isize));
     let rv = mman::munmap(start as *mut c_void, full_sz as size_t);
     if rv != 0 {
-        panic!("munmap failed: {}", io::Error::last_os_error());
+        return Err(MapError::last_sys());
+    }
+
+    Ok(())
+}
+
+/// Replace memory by an inaccessible mapping
+///
+/// `ptr` must be a pointer returned by `allocate` where `size` was used
+/// as argument, or the first page of `size` bytes made usable by `revive`.
+/// Its pages are filled with `fill`, unlocked, then replaced by new pages
+/// that can't be accessed. Any access through a dangling pointer faults
+/// until `deallocate` is called with a `fill` of `None`.
+pub unsafe fn tombstone(ptr: *mut u8, size: usize,
+                        fill: Option<u8>) -> Result<(), MapError> {
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
+    let region = mask_pointer(ptr);
+
+    if region_sz == 0 {
+        return Ok(());
+    }
+
+    if let Some(fill_byte) = fill {
+        // Make sure the region can be written.
+        try!(protect(region, region_sz, Prot::Write));
+
+        utils::set_memory(region, fill_byte, region_sz);
+    }
+
+    if malloc::options().mlock {
+        let rv = mman::munlock(region as *const c_void, region_sz as size_t);
+        if rv != 0 {
+            return Err(MapError::last_sys());
+        }
+    }
+
+    // Atomically replace the previous pages and release their memory.
+    let object = mman::mmap(region as *mut c_void,
+                            region_sz as size_t,
+                            PROT_NONE,
+                            MAP_ANON | MAP_PRIVATE | MAP_FIXED |
+                            map_imp::additional_map_flags(),
+                            -1,
+                            0);
+    if object == MAP_FAILED {
+        return Err(MapError::last_map());
+    }
+
+    Ok(())
+}
+
+/// Reserve memory
+///
+/// Map `size` bytes, rounded to the next page size multiple, between two
+/// guard pages like `allocate` with an `align` of at least the page size,
+/// but without allowing any access nor locking any page. Parts of it are
+/// then made usable by `revive` and inaccessible again by `tombstone`. The
+/// whole area must be released by `deallocate` with a `fill` of `None`.
+pub unsafe fn reserve(size: usize, align: usize) -> Result<*mut u8, MapError> {
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
+    let full_sz = try!(region_sz.checked_add(2 * page_size())
+                       .ok_or(MapError::Overflow));
+    assert!(align.is_power_of_two() && align >= page_size());
+    let reserve_sz = try!(full_sz.checked_add(align - page_size())
+                          .ok_or(MapError::Overflow));
+
+    let null_addr: *const u8 = ptr::null();
+    let object = mman::mmap(null_addr as *mut c_void,
+                            reserve_sz as size_t,
+                            PROT_NONE,
+                            MAP_ANON | MAP_PRIVATE |
+                            map_imp::additional_map_flags(),
+                            -1,
+                            0);
+    if object == MAP_FAILED {
+        return Err(MapError::last_map());
+    }
+
+    let start = try!(trim(object as *mut u8, reserve_sz, full_sz, align));
+
+    if let Err(err) = self::inh_imp::minherit(start, full_sz) {
+        mman::munmap(start as *mut c_void, full_sz as size_t);
+        return Err(err);
+    }
+    Ok(start.offset(page_size() as isize))
+}
+
+/// Make memory replaced by `tombstone` usable again
+///
+/// `ptr` must be the first page of `size` bytes either reserved by
+/// `reserve` or replaced by `tombstone`. Its pages are made readable and
+/// writable, locked and advised like by `allocate`, then filled with
+/// `fill`. On error they must be replaced by `tombstone` again.
+pub unsafe fn revive(ptr: *mut u8, size: usize,
+                     fill: Option<u8>) -> Result<(), MapError> {
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
+    debug_assert!(ptr == mask_pointer(ptr));
+
+    try!(setup_extension(ptr, region_sz, Prot::ReadWrite));
+    if let Some(fill_byte) = fill {
+        utils::set_memory(ptr, fill_byte, region_sz);
+    }
+    Ok(())
+}
+
+/// Make memory inaccessible while keeping it mapped
+///
+/// `ptr` must be a pointer returned by `allocate` where `size` was used
+/// as argument. Its pages are filled with `fill` then made inaccessible,
+/// they stay mapped and locked until they are either handed out again by
+/// `reuse` or released by `deallocate` with a `fill` of `None`.
+pub unsafe fn retire(ptr: *mut u8, size: usize,
+                     fill: Option<u8>) -> Result<(), MapError> {
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
+    let region = mask_pointer(ptr);
+
+    if let Some(fill_byte) = fill {
+        // Make sure the region can be written.
+        try!(protect(region, region_sz, Prot::Write));
+
+        utils::set_memory(region, fill_byte, region_sz);
+    }
+
+    protect(region, region_sz, Prot::None)
+}
+
+/// Reuse memory made inaccessible by `retire`
+///
+/// `region` must be the first page of a mapping retired by `retire`, the
+/// page rounded `size` must be its size. Its pages are filled with `fill`
+/// and set with protections `prot`, the buffer of `size` bytes is then
+/// positioned according to `pos` like with `allocate` and an `align` of 0.
+pub unsafe fn reuse(region: *mut u8, size: usize, fill: Option<u8>,
+                    prot: Prot, pos: RangePos) -> Result<*mut u8, MapError> {
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
+    debug_assert!(region == mask_pointer(region));
+
+    if let Some(fill_byte) = fill {
+        try!(protect(region, region_sz, Prot::Write));
+        utils::set_memory(region, fill_byte, region_sz);
+    }
+    try!(protect(region, region_sz, prot));
+
+    let align_sz = match pos {
+        RangePos::Start => 1,
+        _ => MIN_ALIGN
+    };
+    Ok(place(region, region_sz, size, align_sz, pos))
+}
+
+/// Resize memory in place
+///
+/// `ptr` must be a pointer returned by `allocate` where `size`, `pos`
+/// and an `align` of 0 were used as arguments, `prot` must be its current
+/// protections. The mapping is grown or shrunk without being moved and
+/// the buffer is moved inside it if needed to keep its position. Guard
+/// pages, locked pages and advices are preserved, the buffer is left
+/// readable and writable and its protections must be restored by the
+/// caller. Bytes of the previous buffer not covered by the new one are
+/// filled with `fill`. Return the new location of the buffer of
+/// `new_size` bytes or `None` if the mapping can't be resized in place.
+///
+/// On error or if `None` is returned, the mapping and the buffer are left
+/// untouched. Nothing fails once the buffer is moved: if the pages
+/// released by a shrink can't be unmapped they are leaked instead, still
+/// accessible up to the previous guard page.
+pub unsafe fn reallocate(ptr: *mut u8, size: usize, new_size: usize,
+                         fill: Option<u8>, prot: Prot, pos: RangePos)
+                         -> Result<Option<*mut u8>, MapError> {
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
+    let new_region_sz = try!(page_round(new_size).ok_or(MapError::Overflow));
+    try!(new_region_sz.checked_add(2 * page_size())
+         .ok_or(MapError::Overflow));
+
+    let offset = match pos {
+        RangePos::Start => 0,
+        RangePos::End => (new_region_sz - new_size) & !(MIN_ALIGN - 1),
+        RangePos::Rand(_) => return Ok(None)
+    };
+
+    // Buffers are always located in the first page after the guard page.
+    let region = mask_pointer(ptr);
+    let new_ptr = region.offset(offset as isize);
+
+    let writable = match prot {
+        Prot::ReadWrite => true,
+        _ => false
+    };
+    if !writable {
+        try!(protect(region, region_sz, Prot::ReadWrite));
+    }
+
+    if new_region_sz > region_sz {
+        // Extend the trailing guard page, it then becomes the first of
+        // the new pages while the last one is the new guard page.
+        let guard = region.offset(region_sz as isize);
+        let delta = new_region_sz - region_sz;
+        if !remap_imp::grow(guard, page_size(), page_size() + delta) {
+            if !writable {
+                let _ = protect(region, region_sz, prot);
+            }
+            return Ok(None);
+        }
+        if let Err(err) = setup_extension(guard, delta, Prot::ReadWrite) {
+            // Restore the previous guard page.
+            if malloc::options().mlock {
+                mman::munlock(guard as *const c_void, page_size() as size_t);
+            }
+            mman::mprotect(guard as *mut c_void, page_size() as size_t,
+                           PROT_NONE);
+            mman::munmap(guard.offset(page_size() as isize) as *mut c_void,
+                         delta as size_t);
+            if !writable {
+                let _ = protect(region, region_sz, prot);
+            }
+            return Err(err);
+        }
+    }
+
+    // Move the buffer then wipe what remains of the previous one.
+    ptr::copy(ptr as *const u8, new_ptr, cmp::min(size, new_size));
+    if let Some(fill_byte) = fill {
+        let (start, end) = (ptr as usize, ptr as usize + size);
+        let (new_start, new_end) = (new_ptr as usize, new_ptr as usize +
+                                    new_size);
+        if start < new_start {
+            utils::set_memory(ptr, fill_byte,
+                              cmp::min(end, new_start) - start);
+        }
+        if new_end < end {
+            let from = cmp::max(start, new_end);
+            utils::set_memory(from as *mut u8, fill_byte, end - from);
+        }
+    }
+
+    if new_region_sz < region_sz {
+        // Turn the first released page into the new guard page and unmap
+        // the others along with the previous guard page. Released pages
+        // are only unlocked once wiped, on failure they are leaked.
+        let guard = region.offset(new_region_sz as isize);
+        let delta = region_sz - new_region_sz;
+        let mut rv = 0;
+        if malloc::options().mlock {
+            rv = mman::munlock(guard as *const c_void, delta as size_t);
+        }
+        if rv == 0 {
+            rv = mman::mprotect(guard as *mut c_void, page_size() as size_t,
+                                PROT_NONE);
+        }
+        if rv == 0 {
+            mman::munmap(guard.offset(page_size() as isize) as *mut c_void,
+                         delta as size_t);
+        }
+    }
+
+    Ok(Some(new_ptr))
+}
+
+// Set protections, lock and advise `size` bytes of new pages starting at
+// `start` and appended to an existing mapping.
+unsafe fn setup_extension(start: *mut u8, size: usize,
+                          prot: Prot) -> Result<(), MapError> {
+    let rv = mman::mprotect(start as *mut c_void, size as size_t,
+                            Prot::to_mprot(prot));
+    if rv != 0 {
+        return Err(MapError::last_sys());
     }
+
+    if malloc::options().mlock {
+        let rv = mman::mlock(start as *const c_void, size as size_t);
+        if rv != 0 {
+            return Err(MapError::last_lock());
+        }
+    }
+
+    try!(self::adv_imp::madvise(start, size));
+    self::inh_imp::minherit(start, size)
 }
 
 /// Change memory protections
 ///
 /// `ptr` must be a pointer returned by `allocate` where `size` was
 /// used as argument. This function returns immediately if `ptr` is
-/// `NULL` and `panic!` on error.
-pub unsafe fn protect(ptr: *mut u8, size: usize, prot: Prot) {
+/// `NULL`.
+pub unsafe fn protect(ptr: *mut u8, size: usize,
+                      prot: Prot) -> Result<(), MapError> {
     if ptr.is_null() {
-        return;
+        return Ok(());
     }
 
+    let region_sz = try!(page_round(size).ok_or(MapError::Overflow));
     let rv = mman::mprotect(mask_pointer(ptr) as *mut c_void,
-                            page_round(size) as size_t,
+                            region_sz as size_t,
                             Prot::to_mprot(prot));
     if rv != 0 {
-        panic!("mprotect failed: {}", io::Error::last_os_error());
+        return Err(MapError::last_sys());
     }
+
+    Ok(())
 }
 
 
@@ -269,6 +725,34 @@ mod map_imp {
     }
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod remap_imp {
+    use libc::consts::os::posix88::MAP_FAILED;
+    use libc::types::common::c95::c_void;
+    use libc::types::os::arch::c95::{c_int, size_t};
+
+
+    extern {
+        fn mremap(addr: *mut c_void, old_len: size_t, new_len: size_t,
+                  flags: c_int, ...) -> *mut c_void;
+    }
+
+    // Grow the mapping of `size` bytes at `ptr` to `new_size` bytes without
+    // moving it. Return `false` if the following pages are not free.
+    pub unsafe fn grow(ptr: *mut u8, size: usize, new_size: usize) -> bool {
+        mremap(ptr as *mut c_void, size as size_t, new_size as size_t,
+               0) != MAP_FAILED
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+mod remap_imp {
+    pub unsafe fn grow(_: *mut u8, _: usize, _: usize) -> bool {
+        false
+    }
+}
+
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 mod adv_imp {
     use libc::consts::os::bsd44::MADV_DONTFORK;
@@ -278,8 +762,10 @@ mod adv_imp {
     use libc::types::os::arch::c95::{c_int, size_t};
     use std::io;
 
+    use super::MapError;
+
 
-    pub unsafe fn madvise(ptr: *mut u8, size: usize) {
+    pub unsafe fn madvise(ptr: *mut u8, size: usize) -> Result<(), MapError> {
         let dont_dump: c_int = 16;
         let rv = bsd44::madvise(ptr as *mut c_void, size as size_t,
                                 dont_dump | MADV_DONTFORK);
@@ -292,9 +778,10 @@ mod adv_imp {
             // kernel's version - to check for the availability of these
             // flags in the kernel.
             if err.raw_os_error().unwrap() != EINVAL {
-                panic!("madvise failed: {}", err);
+                return Err(MapError::Sys(err));
             }
         }
+        Ok(())
     }
 }
 
@@ -306,20 +793,27 @@ mod adv_imp {
     use libc::types::os::arch::c95::size_t;
     use std::io;
 
+    use super::MapError;
 
-    pub unsafe fn madvise(ptr: *mut u8, size: usize) {
+
+    pub unsafe fn madvise(ptr: *mut u8, size: usize) -> Result<(), MapError> {
         let rv = bsd44::madvise(ptr as *mut c_void, size as size_t,
                                 MADV_ZERO_WIRED_PAGES);
         if rv != 0 {
-            panic!("madvise failed: {}", io::Error::last_os_error());
+            return Err(MapError::Sys(io::Error::last_os_error()));
         }
+        Ok(())
     }
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android",
               target_os = "macos", target_os = "ios")))]
 mod adv_imp {
-    pub unsafe fn madvise(_: *mut u8, _: usize) {
+    use super::MapError;
+
+
+    pub unsafe fn madvise(_: *mut u8, _: usize) -> Result<(), MapError> {
+        Ok(())
     }
 }
 
@@ -330,6 +824,8 @@ mod inh_imp {
     pub use libc::types::os::arch::c95::{c_int, size_t};
     use std::io;
 
+    use super::MapError;
+
 
     mod bsdext {
         extern {
@@ -338,21 +834,27 @@ mod inh_imp {
         }
     }
 
-    pub unsafe fn minherit(ptr: *mut u8, size: usize) {
+    pub unsafe fn minherit(ptr: *mut u8,
+                           size: usize) -> Result<(), MapError> {
         // Value named INHERIT_NONE on freebsd and VM_INHERIT_NONE on
         // macos/ios.
         let inherit_none: c_int = 2;
         let rv = bsdext::minherit(ptr as *mut c_void, size as size_t,
                                   inherit_none);
         if rv != 0 {
-            panic!("minherit failed: {}", io::Error::last_os_error());
+            return Err(MapError::Sys(io::Error::last_os_error()));
         }
+        Ok(())
     }
 }
 
 #[cfg(not(any(target_os = "macos", target_os = "ios",
               target_os = "freebsd")))]
 mod inh_imp {
-    pub unsafe fn minherit(_: *mut u8, _: usize) {
+    use super::MapError;
+
+
+    pub unsafe fn minherit(_: *mut u8, _: usize) -> Result<(), MapError> {
+        Ok(())
     }
 }
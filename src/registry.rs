@@ -0,0 +1,402 @@
+//! Registry of thread directories
+//!
+//! Keep track of the directory owning each mapped object in order to
+//! route deallocations made from other threads back to their owner, and
+//! of the directories left with live objects by their exiting threads.
+//! Also hold the statistics counters of each directory so that they can
+//! be aggregated from any thread.
+//!
+//! Objects freed by another thread than their owner are wiped right away
+//! by this thread, the errors met by their owner while deallocating them
+//! are reported back to it. Orphans failing their integrity checks are
+//! only counted, they are never adopted.
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Mutex, MutexGuard, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use malloc::MallocError;
+use mmap;
+use utils;
+
+
+// Number of shards of the owners of mapped objects.
+const OWNERS_SHARDS: usize = 64;
+
+// Number of orphaned directories waiting to be adopted.
+static ORPHANS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Number of orphaned directories found corrupted.
+static CORRUPTED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Last attributed directory identifier, 0 is never used.
+static LAST_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+/// Statistics counters of a directory. Only updated by its owner but
+/// readable from any thread.
+pub struct Counters {
+    /// Number of live large objects, keys included.
+    pub larges: AtomicUsize,
+    /// Cumulated size of live large objects.
+    pub larges_bytes: AtomicUsize,
+    /// Number of live keys.
+    pub keys: AtomicUsize,
+    /// Number of live medium objects.
+    pub mediums: AtomicUsize,
+    /// Number of mapped spans of medium objects.
+    pub spans: AtomicUsize,
+    /// Number of live chunks slots.
+    pub chunks: AtomicUsize,
+    /// Number of live chunks slots indexed by size-class, followed by the
+    /// number of live medium objects of each size-class.
+    pub classes: Vec<AtomicUsize>,
+    /// Number of currently cached empty chunks.
+    pub cached: AtomicUsize,
+    /// Number of chunks reused from cache.
+    pub cache_hits: AtomicUsize,
+    /// Number of chunks mapped while the cache was empty.
+    pub cache_misses: AtomicUsize,
+    /// Number of currently cached mappings of freed large objects.
+    pub spares: AtomicUsize,
+    /// Number of large objects reusing a cached mapping.
+    pub spare_hits: AtomicUsize,
+    /// Number of modifications of memory protections for read, write,
+    /// none.
+    pub prot_reads: AtomicUsize,
+    pub prot_writes: AtomicUsize,
+    pub prot_nones: AtomicUsize,
+    /// Number of modifications of the protections of metadata.
+    pub metadata_prots: AtomicUsize,
+    /// Number of bytes currently locked in memory.
+    pub locked_bytes: AtomicUsize
+}
+
+impl Counters {
+    fn new(classes: usize) -> Counters {
+        Counters {
+            larges: AtomicUsize::new(0),
+            larges_bytes: AtomicUsize::new(0),
+            keys: AtomicUsize::new(0),
+            mediums: AtomicUsize::new(0),
+            spans: AtomicUsize::new(0),
+            chunks: AtomicUsize::new(0),
+            classes: (0..classes).map(|_| AtomicUsize::new(0)).collect(),
+            cached: AtomicUsize::new(0),
+            cache_hits: AtomicUsize::new(0),
+            cache_misses: AtomicUsize::new(0),
+            spares: AtomicUsize::new(0),
+            spare_hits: AtomicUsize::new(0),
+            prot_reads: AtomicUsize::new(0),
+            prot_writes: AtomicUsize::new(0),
+            prot_nones: AtomicUsize::new(0),
+            metadata_prots: AtomicUsize::new(0),
+            locked_bytes: AtomicUsize::new(0)
+        }
+    }
+
+    // Add the cumulative counters of `other`, live objects counters are
+    // left out as they are either released or adopted with their objects.
+    fn retire(&self, other: &Counters) {
+        let pairs = [(&self.cache_hits, &other.cache_hits),
+                     (&self.cache_misses, &other.cache_misses),
+                     (&self.spare_hits, &other.spare_hits),
+                     (&self.prot_reads, &other.prot_reads),
+                     (&self.prot_writes, &other.prot_writes),
+                     (&self.prot_nones, &other.prot_nones),
+                     (&self.metadata_prots, &other.metadata_prots)];
+        for &(counter, value) in pairs.iter() {
+            counter.fetch_add(value.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Data of a directory shared with other threads.
+pub struct Shared {
+    /// Number of pointers freed by other threads and waiting to be
+    /// deallocated, checked by the owner without locking.
+    pub pending: AtomicUsize,
+    /// Statistics counters.
+    pub counters: Counters
+}
+
+/// Objects of a mapping wiped by the threads freeing them when they are
+/// not their owner.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// Address of the first object.
+    pub object: usize,
+    /// Number of bytes wiped of each object, 0 if they are left as is.
+    pub size: usize,
+    /// Distance between two consecutive objects.
+    pub stride: usize,
+    /// Number of objects.
+    pub count: usize
+}
+
+impl Layout {
+    /// Layout of a mapping whose objects are never wiped.
+    pub fn none() -> Layout {
+        Layout {
+            object: 0,
+            size: 0,
+            stride: 0,
+            count: 0
+        }
+    }
+
+    // Number of bytes to wipe at `ptr`, 0 unless an object starts there.
+    fn wipe_size(&self, ptr: usize) -> usize {
+        let offset = match ptr.checked_sub(self.object) {
+            Some(offset) if self.stride != 0 => offset,
+            _ => return 0
+        };
+        if offset % self.stride != 0 || offset / self.stride >= self.count {
+            return 0;
+        }
+        self.size
+    }
+}
+
+/// Pointer freed by another thread than its owner.
+#[derive(Copy, Clone, Debug)]
+pub struct Pending {
+    /// Freed pointer.
+    pub ptr: usize,
+    /// Identifier of the directory of the thread which freed it.
+    pub freer: usize,
+    /// Its object was wiped.
+    pub wiped: bool
+}
+
+// Pointers freed by other threads and waiting to be deallocated by their
+// owner, errors met while deallocating the pointers freed by this owner,
+// along with the data shared by this owner.
+struct Queue {
+    ptrs: Vec<Pending>,
+    errors: Vec<MallocError>,
+    shared: Box<Shared>
+}
+
+struct Registry {
+    // Remote frees queues indexed by owner's identifier.
+    queues: HashMap<usize, Queue>,
+    // Identifiers and addresses of directories whose thread has exited
+    // while they still owned live objects.
+    orphans: Vec<(usize, usize)>,
+    // Cumulative counters of detached directories.
+    retired: Counters
+}
+
+// Owner's identifier and objects layout of each mapped object indexed by
+// the address of its first page. Owners register and unregister their
+// objects whenever a chunk, a span or a mapping is created, reused or
+// released. These maps are split in shards locked independently, so that
+// threads allocating concurrently seldom wait for each other there,
+// instead of sharing the registry's lock only taken on slower paths.
+type Owners = HashMap<usize, (usize, Layout)>;
+
+fn owners(page: usize) -> MutexGuard<'static, Owners> {
+    static ONCE: Once = ONCE_INIT;
+    static mut instance: *mut Vec<Mutex<Owners>> =
+        0 as *mut Vec<Mutex<Owners>>;
+
+    unsafe {
+        ONCE.call_once(|| {
+            let shards = (0..OWNERS_SHARDS).map(|_| {
+                Mutex::new(HashMap::new())
+            }).collect();
+            instance = Box::into_raw(Box::new(shards));
+        });
+
+        let shard = page / mmap::page_size() % OWNERS_SHARDS;
+        // Owners are never left in an inconsistent state by a panicking
+        // thread, ignore poisoning.
+        match (*instance)[shard].lock() {
+            Ok(guard) => guard,
+            Err(err) => err.into_inner()
+        }
+    }
+}
+
+fn registry() -> MutexGuard<'static, Registry> {
+    static ONCE: Once = ONCE_INIT;
+    static mut instance: *mut Mutex<Registry> = 0 as *mut Mutex<Registry>;
+
+    unsafe {
+        ONCE.call_once(|| {
+            instance = Box::into_raw(Box::new(Mutex::new(Registry {
+                queues: HashMap::new(),
+                orphans: Vec::new(),
+                retired: Counters::new(0)
+            })));
+        });
+
+        // The registry is never left in an inconsistent state by a
+        // panicking thread, ignore poisoning.
+        match (*instance).lock() {
+            Ok(guard) => guard,
+            Err(err) => err.into_inner()
+        }
+    }
+}
+
+
+/// Register a new directory using `classes` size-classes. Return
+/// its identifier and a pointer to its shared data, valid until `detach`
+/// is called.
+pub fn attach(classes: usize) -> (usize, *const Shared) {
+    let id = LAST_ID.fetch_add(1, Ordering::SeqCst) + 1;
+    let shared = Box::new(Shared {
+        pending: AtomicUsize::new(0),
+        counters: Counters::new(classes)
+    });
+    let shared_ptr = &*shared as *const Shared;
+
+    registry().queues.insert(id, Queue {
+        ptrs: Vec::new(),
+        errors: Vec::new(),
+        shared: shared
+    });
+
+    (id, shared_ptr)
+}
+
+/// Unregister directory `id`. It must not own any object anymore.
+pub fn detach(id: usize) {
+    let mut reg = registry();
+    if let Some(queue) = reg.queues.remove(&id) {
+        reg.retired.retire(&queue.shared.counters);
+    }
+}
+
+/// Record `id` as owner of the object mapped at `page` whose objects are
+/// laid out as `layout`. Once it returns, other threads are done wiping
+/// the objects of its previous layout.
+pub fn register(page: usize, id: usize, layout: Layout) {
+    owners(page).insert(page, (id, layout));
+}
+
+/// Forget the owner of the object mapped at `page`.
+pub fn unregister(page: usize) {
+    owners(page).remove(&page);
+}
+
+/// Queue `ptr` located in `page` for deallocation by its owner if it is
+/// not `id`. Its object is wiped first if it is laid out to be, it must
+/// not be used anymore. Return `false` if no other directory owns `page`.
+pub unsafe fn free_remote(id: usize, page: usize, ptr: usize) -> bool {
+    // Held until queued, its owner can't change its layout meanwhile.
+    let shard = owners(page);
+    let (owner, layout) = match shard.get(&page) {
+        Some(&(owner, layout)) if owner != id => (owner, layout),
+        _ => return false
+    };
+
+    let mut reg = registry();
+    match reg.queues.get_mut(&owner) {
+        Some(queue) => {
+            let size = layout.wipe_size(ptr);
+            utils::zero_memory(ptr as *mut u8, size);
+            queue.ptrs.push(Pending {
+                ptr: ptr,
+                freer: id,
+                wiped: size > 0
+            });
+            queue.shared.pending.fetch_add(1, Ordering::SeqCst);
+            true
+        },
+        None => false
+    }
+}
+
+/// Report `err` met while deallocating a pointer freed by directory `id`.
+/// Dropped if it is already detached.
+pub fn report(id: usize, err: MallocError) {
+    if let Some(queue) = registry().queues.get_mut(&id) {
+        queue.errors.push(err);
+    }
+}
+
+/// Take the errors reported to directory `id`.
+pub fn take_errors(id: usize) -> Vec<MallocError> {
+    match registry().queues.get_mut(&id) {
+        Some(queue) => mem::replace(&mut queue.errors, Vec::new()),
+        None => Vec::new()
+    }
+}
+
+/// Take the pointers queued for deallocation by directory `id`.
+pub fn take_pending(id: usize) -> Vec<Pending> {
+    match registry().queues.get_mut(&id) {
+        Some(queue) => {
+            queue.shared.pending.store(0, Ordering::SeqCst);
+            mem::replace(&mut queue.ptrs, Vec::new())
+        },
+        None => Vec::new()
+    }
+}
+
+/// Hand over directory `id` located at `dir`, its thread is exiting but
+/// some of its objects are still alive.
+pub fn orphan(id: usize, dir: usize) {
+    let mut reg = registry();
+    reg.orphans.push((id, dir));
+    ORPHANS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Take the oldest orphaned directory, it must be adopted, orphaned again
+/// or quarantined.
+pub fn take_orphan() -> Option<(usize, usize)> {
+    if ORPHANS.load(Ordering::SeqCst) == 0 {
+        return None;
+    }
+
+    let mut reg = registry();
+    if reg.orphans.is_empty() {
+        return None;
+    }
+    ORPHANS.fetch_sub(1, Ordering::SeqCst);
+    Some(reg.orphans.remove(0))
+}
+
+/// Give up on orphan `id`, its metadata are corrupted. It is detached,
+/// its objects are leaked and their remote frees rejected.
+pub fn quarantine(id: usize) {
+    CORRUPTED.fetch_add(1, Ordering::SeqCst);
+    detach(id);
+}
+
+/// Number of orphaned directories quarantined.
+pub fn corrupted() -> usize {
+    CORRUPTED.load(Ordering::SeqCst)
+}
+
+/// Transfer the pending remote frees of `orphan_id` to `id` and detach
+/// `orphan_id`. All its objects must have been registered to `id`.
+pub fn adopt(orphan_id: usize, id: usize) {
+    let mut reg = registry();
+
+    let ptrs = match reg.queues.remove(&orphan_id) {
+        Some(queue) => {
+            reg.retired.retire(&queue.shared.counters);
+            queue.ptrs
+        },
+        None => return
+    };
+
+    if let Some(queue) = reg.queues.get_mut(&id) {
+        queue.shared.pending.fetch_add(ptrs.len(), Ordering::SeqCst);
+        queue.ptrs.extend(ptrs);
+    }
+}
+
+/// Call `f` with the counters of every registered directory, orphans
+/// included, and with the cumulative counters of detached directories.
+pub fn fold_counters<F: FnMut(&Counters)>(mut f: F) {
+    let reg = registry();
+    for queue in reg.queues.values() {
+        f(&queue.shared.counters);
+    }
+    f(&reg.retired);
+}
@@ -0,0 +1,293 @@
+//! Buffered random source
+//!
+//! Random bytes are generated by a ChaCha stream cipher into a small pool
+//! from which they are handed out, much like the `getrnd` pool of
+//! OpenBSD's malloc. Each consumed byte is erased from the pool and the
+//! generator is reseeded from the OS after a fixed amount of output.
+//!
+//! When built with the `malloc_seed` feature, the generators of the
+//! allocator's directories may instead all be derived from a single seed
+//! in order to replay heap layouts, they are then never reseeded.
+#[cfg(feature = "malloc_seed")]
+use std::env;
+#[cfg(feature = "malloc_seed")]
+use std::io::{self, Write};
+use std::mem;
+use std::ptr;
+#[cfg(feature = "malloc_seed")]
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+#[cfg(feature = "malloc_seed")]
+use std::thread;
+
+use rand::{Rng, SeedableRng};
+use rand::chacha::ChaChaRng;
+
+use utils;
+
+
+// Number of bytes generated at once in the pool.
+const POOL_BYTES: usize = 256;
+
+// Number of pool refills before reseeding the generator from the OS.
+const RESEED_REFILLS: usize = 4096;
+
+/// Name of the environment variable read for the seed of the
+/// deterministic mode, a decimal integer.
+#[cfg(feature = "malloc_seed")]
+pub const SEED_ENV: &'static str = "TARS_MALLOC_SEED";
+
+// Seed states, see `seed_init`.
+#[cfg(feature = "malloc_seed")]
+const SEED_UNSET: usize = 0;
+#[cfg(feature = "malloc_seed")]
+const SEED_BUSY: usize = 1;
+#[cfg(feature = "malloc_seed")]
+const SEED_SET: usize = 2;
+
+#[cfg(feature = "malloc_seed")]
+static SEED_STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+#[cfg(feature = "malloc_seed")]
+static mut SEED: Option<u64> = None;
+
+// Number of generators derived from the seed.
+#[cfg(feature = "malloc_seed")]
+static SEEDED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+/// ChaCha based generator handing out random bytes from a pool.
+pub struct PoolRng {
+    chacha: ChaChaRng,
+    pool: [u8; POOL_BYTES],
+    // Number of bytes of the pool already consumed.
+    used: usize,
+    // Number of pool refills since the last reseed.
+    refills: usize,
+    // Derived from the seed of the deterministic mode, never reseeded.
+    seeded: bool
+}
+
+impl PoolRng {
+    /// New generator seeded from the OS, directories build theirs in place
+    /// with `init_for_dir`.
+    #[cfg(test)]
+    pub fn new() -> PoolRng {
+        let mut rng = PoolRng {
+            chacha: ChaChaRng::new_unseeded(),
+            pool: [0; POOL_BYTES],
+            used: POOL_BYTES,
+            refills: 0,
+            seeded: false
+        };
+        rng.reseed();
+        rng
+    }
+
+    /// New generator of `stream` derived from `seed`, the same seed and
+    /// stream always produce the same output.
+    #[cfg(all(test, feature = "malloc_seed"))]
+    pub fn from_seed(seed: u64, stream: u64) -> PoolRng {
+        PoolRng {
+            chacha: PoolRng::seeded_chacha(seed, stream),
+            pool: [0; POOL_BYTES],
+            used: POOL_BYTES,
+            refills: 0,
+            seeded: true
+        }
+    }
+
+    /// Initialize at `rng` the generator of a directory of the allocator.
+    /// It is built in place, no copy of its state is left on the stack.
+    /// Seeded from the OS unless the deterministic mode is enabled, it is
+    /// then derived from its seed and from the number of generators
+    /// already derived.
+    pub unsafe fn init_for_dir(rng: *mut PoolRng) {
+        let seed = next_seed();
+        ptr::write(&mut (*rng).chacha, match seed {
+            Some((seed, stream)) => PoolRng::seeded_chacha(seed, stream),
+            None => ChaChaRng::new_unseeded()
+        });
+        ptr::write_bytes((*rng).pool.as_mut_ptr(), 0, POOL_BYTES);
+        (*rng).used = POOL_BYTES;
+        (*rng).refills = 0;
+        (*rng).seeded = seed.is_some();
+        (*rng).reseed();
+    }
+
+    fn seeded_chacha(seed: u64, stream: u64) -> ChaChaRng {
+        let key = [seed as u32, (seed >> 32) as u32,
+                   stream as u32, (stream >> 32) as u32, 0, 0, 0, 0];
+        ChaChaRng::from_seed(&key[..])
+    }
+
+    fn reseed(&mut self) {
+        if !self.seeded {
+            let mut seed: [u32; 8] = utils::os_rng().gen();
+            self.chacha.reseed(&seed[..]);
+            unsafe {
+                utils::zero_memory(seed.as_mut_ptr() as *mut u8,
+                                   mem::size_of_val(&seed));
+            }
+        }
+        self.refills = 0;
+    }
+
+    fn refill(&mut self) {
+        if self.refills == RESEED_REFILLS {
+            self.reseed();
+        }
+        self.chacha.fill_bytes(&mut self.pool);
+        self.refills += 1;
+        self.used = 0;
+    }
+
+    // Take the next random byte out of the pool.
+    #[inline]
+    fn next_byte(&mut self) -> u8 {
+        if self.used == POOL_BYTES {
+            self.refill();
+        }
+        let byte = self.pool[self.used];
+        self.pool[self.used] = 0;
+        self.used += 1;
+        byte
+    }
+}
+
+impl Rng for PoolRng {
+    fn next_u32(&mut self) -> u32 {
+        (0_usize..4).fold(0, |word, _| word << 8 | self.next_byte() as u32)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for byte in dest.iter_mut() {
+            *byte = self.next_byte();
+        }
+    }
+}
+
+
+// Freeze the seed to `seed` or to the environment's seed. Return `false`
+// if the seed was already frozen or if it is refused, seeds are ignored
+// by release builds.
+#[cfg(feature = "malloc_seed")]
+fn seed_init(seed: Option<u64>) -> bool {
+    match SEED_STATE.compare_and_swap(SEED_UNSET, SEED_BUSY,
+                                      Ordering::SeqCst) {
+        SEED_UNSET => {
+            let mut seed = seed.or_else(|| {
+                env::var(SEED_ENV).ok().and_then(|var| match var.parse() {
+                    Ok(value) => Some(value),
+                    Err(_) => {
+                        let _ = writeln!(io::stderr(),
+                                         "tars malloc: WARNING: invalid {} \
+                                          '{}' ignored, expected a decimal \
+                                          integer", SEED_ENV, var);
+                        None
+                    }
+                })
+            });
+            let refused = seed.is_some() && !cfg!(debug_assertions);
+            if refused {
+                let _ = writeln!(io::stderr(),
+                                 "tars malloc: WARNING: deterministic mode \
+                                  refused by a release build, seed \
+                                  ignored");
+                seed = None;
+            }
+            if let Some(value) = seed {
+                let _ = writeln!(io::stderr(),
+                                 "tars malloc: WARNING: deterministic mode \
+                                  seeded with {}, the allocator's \
+                                  randomization is predictable, never use \
+                                  it in production", value);
+            }
+            unsafe {
+                SEED = seed;
+            }
+            SEED_STATE.store(SEED_SET, Ordering::SeqCst);
+            !refused
+        },
+        _ => {
+            while SEED_STATE.load(Ordering::SeqCst) != SEED_SET {
+                thread::yield_now();
+            }
+            false
+        }
+    }
+}
+
+/// Enable the deterministic mode with `seed`. Return `false` if the seed
+/// is already in use, it is then left unchanged, or if this is a release
+/// build, the deterministic mode is then never enabled.
+#[cfg(feature = "malloc_seed")]
+pub fn set_seed(seed: u64) -> bool {
+    seed_init(Some(seed))
+}
+
+// Seed and stream of the next generator of the deterministic mode.
+#[cfg(feature = "malloc_seed")]
+fn next_seed() -> Option<(u64, u64)> {
+    if SEED_STATE.load(Ordering::SeqCst) != SEED_SET {
+        seed_init(None);
+    }
+    let seed = unsafe { SEED };
+    seed.map(|seed| (seed, SEEDED.fetch_add(1, Ordering::SeqCst) as u64))
+}
+
+#[cfg(not(feature = "malloc_seed"))]
+fn next_seed() -> Option<(u64, u64)> {
+    None
+}
+
+
+#[cfg(test)]
+mod test {
+    use rand::Rng;
+
+    use super::{PoolRng, POOL_BYTES};
+
+
+    #[test]
+    fn test_pool_rng() {
+        let mut rng = PoolRng::new();
+        let mut other = PoolRng::new();
+
+        let a: Vec<u32> = (0_usize..POOL_BYTES).map(|_| rng.gen()).collect();
+        let b: Vec<u32> = (0_usize..POOL_BYTES).map(|_| other.gen())
+            .collect();
+        assert!(a != b);
+
+        // Consumed bytes are erased.
+        rng.gen::<u8>();
+        assert!(rng.pool[..rng.used].iter().all(|&byte| byte == 0));
+
+        let mut buf = [0_u8; POOL_BYTES * 3];
+        rng.fill_bytes(&mut buf);
+        assert!(buf.iter().any(|&byte| byte != 0));
+    }
+
+    #[cfg(feature = "malloc_seed")]
+    #[test]
+    fn test_seeded_rng() {
+        use super::RESEED_REFILLS;
+
+        let draw = |rng: &mut PoolRng| -> Vec<u32> {
+            (0_usize..POOL_BYTES).map(|_| rng.gen()).collect()
+        };
+        let mut rng = PoolRng::from_seed(42, 0);
+        let a = draw(&mut rng);
+        assert_eq!(a, draw(&mut PoolRng::from_seed(42, 0)));
+        assert!(a != draw(&mut PoolRng::from_seed(42, 1)));
+        assert!(a != draw(&mut PoolRng::from_seed(43, 0)));
+
+        // Still deterministic past the reseeding interval.
+        let mut other = PoolRng::from_seed(42, 0);
+        draw(&mut other);
+        let mut buf = [0_u8; POOL_BYTES];
+        for _ in 0_usize..RESEED_REFILLS {
+            rng.fill_bytes(&mut buf);
+            other.fill_bytes(&mut buf);
+        }
+        assert_eq!(draw(&mut rng), draw(&mut other));
+    }
+}
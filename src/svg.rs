@@ -0,0 +1,266 @@
+//! Heap map rendering
+//!
+//! Draw a `HeapSnapshot` as an SVG image: the regions table with the kind
+//! of region held by each entry, then one row per mapping in address
+//! order with its guard pages, the occupancy of each slot of chunks and
+//! spans, and the pages of large objects, keys, cached chunks and spares.
+use std::cmp;
+use std::fmt::Write;
+
+use malloc::{HeapSnapshot, Prot, RegionKind, RegionSnapshot};
+
+
+// Margin around the image and between its parts.
+const MARGIN: usize = 10;
+// Height of a line of text.
+const LINE: usize = 16;
+// Side of an entry of the regions table and number of entries per row.
+const ENTRY: usize = 10;
+const TABLE_COLUMNS: usize = 64;
+// Width of the labels preceding the pages of each mapping.
+const LABEL_WIDTH: usize = 300;
+// Size of a page and height of a row of pages.
+const PAGE_WIDTH: usize = 14;
+const ROW_HEIGHT: usize = 24;
+// Side of a slot of a chunk, distance between two slots and number of
+// slots per line. A page holds up to 256 slots i.e. 4 lines.
+const SLOT: usize = 4;
+const SLOT_PITCH: usize = 5;
+const SLOT_COLUMNS: usize = 64;
+// Number of pages drawn for a mapping, further pages are summarized.
+const MAX_PAGES: usize = 32;
+// Minimal width of the image.
+const MIN_WIDTH: usize = 960;
+
+const STYLE: &'static str = "\
+text { font-family: monospace; font-size: 11px; }
+rect { stroke: #ffffff; stroke-width: 1; }
+.free { fill: #f0f0f0; stroke: #c0c0c0; }
+.chunk { fill: #66a366; }
+.large { fill: #4f7fd1; }
+.key { fill: #d1a13a; }
+.cache { fill: #9fbf9f; }
+.tomb { fill: #a33a3a; }
+.spare { fill: #9fb3d1; }
+.span { fill: #8a63c7; }
+.guard { fill: #303030; }
+.none { fill: #b0b0b0; }
+.slot { fill: #2e6b2e; stroke: none; }
+.slot-free { fill: #d8ead8; stroke: none; }
+";
+
+
+// Append a rectangle of class `class`.
+fn rect(out: &mut String, x: usize, y: usize, width: usize, height: usize,
+        class: &str) {
+    let _ = write!(out, "<rect class=\"{}\" x=\"{}\" y=\"{}\" width=\"{}\" \
+                        height=\"{}\"/>\n", class, x, y, width, height);
+}
+
+// Append a line of text whose baseline starts at `x`, `y`.
+fn text(out: &mut String, x: usize, y: usize, content: &str) {
+    let _ = write!(out, "<text x=\"{}\" y=\"{}\">{}</text>\n", x, y,
+                   content);
+}
+
+// Class of the pages of the object of `region`.
+fn object_class(region: &RegionSnapshot) -> &'static str {
+    match region.prot {
+        Prot::None if region.kind != RegionKind::Cache &&
+                      region.kind != RegionKind::Tomb &&
+                      region.kind != RegionKind::Spare => "none",
+        _ => region.kind.name()
+    }
+}
+
+// Draw `count` pages of class `class` from `x`, summarizing the pages
+// beyond MAX_PAGES. Return the abscissa following them.
+fn pages(out: &mut String, x: usize, y: usize, count: usize,
+         class: &str) -> usize {
+    for i in 0_usize..cmp::min(count, MAX_PAGES) {
+        rect(out, x + i * PAGE_WIDTH, y, PAGE_WIDTH, ROW_HEIGHT - 4, class);
+    }
+    let mut end = x + cmp::min(count, MAX_PAGES) * PAGE_WIDTH;
+    if count > MAX_PAGES {
+        text(out, end + 4, y + LINE - 2,
+             &format!("+{} pages", count - MAX_PAGES));
+        end += LABEL_WIDTH / 3;
+    }
+    end
+}
+
+// Draw the mapping of `region` on the row starting at `y`. Return the
+// abscissa following its last page.
+fn mapping(out: &mut String, region: &RegionSnapshot, y: usize,
+           page_size: usize) -> usize {
+    text(out, MARGIN, y + LINE - 2,
+         &format!("{:#x} {} {}", region.address, region.kind.name(),
+                  region.size));
+
+    let mut x = pages(out, MARGIN + LABEL_WIDTH, y, 1, "guard");
+    match region.kind {
+        RegionKind::Chunk if region.slots > 0 => {
+            // Slots of the page, in lines of SLOT_COLUMNS.
+            for (i, &used) in region.occupancy.iter().enumerate() {
+                rect(out, x + 2 + (i % SLOT_COLUMNS) * SLOT_PITCH,
+                     y + (i / SLOT_COLUMNS) * SLOT_PITCH, SLOT, SLOT,
+                     if used { "slot" } else { "slot-free" });
+            }
+            x += SLOT_COLUMNS * SLOT_PITCH + 4;
+        },
+        RegionKind::Span => {
+            // Each slot is followed by a guard page, its pages are
+            // inaccessible while it is free.
+            let slot_pages = region.size / page_size;
+            for &used in region.occupancy.iter() {
+                let width = slot_pages * PAGE_WIDTH;
+                rect(out, x, y, width, ROW_HEIGHT - 4,
+                     if used { "slot" } else { "slot-free" });
+                x = pages(out, x + width, y, 1, "guard");
+            }
+            return x;
+        },
+        _ => {
+            x = pages(out, x, y, region.pages.saturating_sub(2),
+                      object_class(region));
+        }
+    }
+    pages(out, x, y, 1, "guard")
+}
+
+/// Render `snapshot` as an SVG image, `page_size` is the size of the
+/// pages of its mappings.
+pub fn render(snapshot: &HeapSnapshot, page_size: usize) -> String {
+    let table_rows = (snapshot.total + TABLE_COLUMNS - 1) / TABLE_COLUMNS;
+    let table_y = MARGIN + 2 * LINE;
+    let legend_y = table_y + table_rows * ENTRY + MARGIN;
+    let mappings_y = legend_y + 2 * LINE;
+
+    let mut kinds: Vec<Option<RegionKind>> = vec![None; snapshot.total];
+    // Regions outside of the table are only drawn with their mapping.
+    for region in snapshot.regions.iter() {
+        if let Some(kind) = kinds.get_mut(region.index) {
+            *kind = Some(region.kind);
+        }
+    }
+
+    let mut body = String::new();
+    text(&mut body, MARGIN, MARGIN + LINE - 2,
+         &format!("{} regions in a table of {} entries, {} pages mapped",
+                  snapshot.regions.len(), snapshot.total,
+                  snapshot.pages()));
+
+    // Regions table, the hash table locating regions from their object.
+    for (index, &kind) in kinds.iter().enumerate() {
+        rect(&mut body, MARGIN + (index % TABLE_COLUMNS) * ENTRY,
+             table_y + (index / TABLE_COLUMNS) * ENTRY, ENTRY, ENTRY,
+             kind.map(|kind| kind.name()).unwrap_or("free"));
+    }
+
+    let legend = [RegionKind::Chunk, RegionKind::Span, RegionKind::Large,
+                  RegionKind::Key, RegionKind::Cache, RegionKind::Tomb,
+                  RegionKind::Spare];
+    let mut x = MARGIN;
+    for kind in legend.iter() {
+        rect(&mut body, x, legend_y, ENTRY, ENTRY, kind.name());
+        text(&mut body, x + ENTRY + 4, legend_y + ENTRY, kind.name());
+        x += 80;
+    }
+    for &(class, name) in [("guard", "guard"), ("none", "no access"),
+                           ("slot", "used slot"),
+                           ("slot-free", "free slot")].iter() {
+        rect(&mut body, x, legend_y, ENTRY, ENTRY, class);
+        text(&mut body, x + ENTRY + 4, legend_y + ENTRY, name);
+        x += 90;
+    }
+
+    let mut width = cmp::max(MIN_WIDTH, x);
+    for (row, region) in snapshot.regions.iter().enumerate() {
+        let end = mapping(&mut body, region, mappings_y + row * ROW_HEIGHT,
+                          page_size);
+        width = cmp::max(width, end + MARGIN);
+    }
+    let height = mappings_y + snapshot.regions.len() * ROW_HEIGHT + MARGIN;
+
+    let mut out = String::new();
+    let _ = write!(out, "<svg xmlns=\"http://www.w3.org/2000/svg\" \
+                        width=\"{}\" height=\"{}\">\n", width, height);
+    let _ = write!(out, "<style>\n{}</style>\n", STYLE);
+    out.push_str(&body);
+    out.push_str("</svg>\n");
+    out
+}
+
+
+#[cfg(test)]
+mod test {
+    use malloc::{HeapSnapshot, Prot, RegionKind, RegionSnapshot};
+
+    use super::{render, MAX_PAGES};
+
+
+    fn region(index: usize, kind: RegionKind, address: usize, size: usize,
+              pages: usize, occupancy: Vec<bool>,
+              prot: Prot) -> RegionSnapshot {
+        RegionSnapshot {
+            index: index,
+            kind: kind,
+            address: address,
+            size: size,
+            pages: pages,
+            slots: occupancy.len(),
+            used_slots: occupancy.iter().filter(|&&used| used).count(),
+            occupancy: occupancy,
+            prot: prot
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let page = 4096;
+        let snapshot = HeapSnapshot {
+            total: 128,
+            regions: vec![
+                region(3, RegionKind::Chunk, 0x10000, 64, 3,
+                       (0_usize..64).map(|i| i % 3 == 0).collect(),
+                       Prot::ReadWrite),
+                region(70, RegionKind::Span, 0x20000, page << 1, 17,
+                       vec![true, false, false, true, false],
+                       Prot::ReadWrite),
+                region(5, RegionKind::Large, 0x40000, page * 40, 42,
+                       Vec::new(), Prot::ReadWrite),
+                region(127, RegionKind::Key, 0x80000, 42, 3, Vec::new(),
+                       Prot::None)]
+        };
+        let svg = render(&snapshot, page);
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+
+        // Table entries and legend.
+        assert_eq!(svg.matches("class=\"free\"").count(), 128 - 4);
+        assert_eq!(svg.matches("class=\"chunk\"").count(), 2);
+        // Chunk, span and legend.
+        assert_eq!(svg.matches("class=\"slot\"").count(), 22 + 2 + 1);
+        assert_eq!(svg.matches("class=\"slot-free\"").count(), 42 + 3 + 1);
+        // Two guard pages per mapping, one after each slot of spans.
+        assert_eq!(svg.matches("class=\"guard\"").count(), 2 * 3 + 6 + 1);
+        // Pages of the large object are summarized.
+        assert_eq!(svg.matches("class=\"large\"").count(), 2 + MAX_PAGES);
+        assert!(svg.contains(&format!("+{} pages", 40 - MAX_PAGES)[..]));
+        // Inaccessible key.
+        assert_eq!(svg.matches("class=\"none\"").count(), 1 + 1);
+        assert!(svg.contains("0x80000 key 42"));
+    }
+
+    #[test]
+    fn test_render_out_of_table() {
+        let snapshot = HeapSnapshot {
+            total: 4,
+            regions: vec![
+                region(4, RegionKind::Key, 0x80000, 42, 3, Vec::new(),
+                       Prot::ReadWrite)]
+        };
+        let svg = render(&snapshot, 4096);
+        assert_eq!(svg.matches("class=\"free\"").count(), 4);
+        assert!(svg.contains("0x80000 key 42"));
+    }
+}
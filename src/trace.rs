@@ -0,0 +1,139 @@
+//! Events tracing
+//!
+//! Keep the last events of each directory in a fixed-size ring buffer
+//! stored with its metadata, and hand every new event to the hook set by
+//! `set_hook` as well as to the `log` crate when built with the
+//! `malloc_log` feature. Events are only recorded while tracing is
+//! enabled, see `Options`.
+use std::io::{self, Write};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use malloc::{TraceEvent, TraceOp};
+
+
+// Number of events kept per directory.
+const RING_EVENTS: usize = 128;
+
+// Event filling unused entries.
+const NO_EVENT: TraceEvent = TraceEvent {
+    op: TraceOp::Alloc,
+    address: 0,
+    size: 0,
+    region: None,
+    prot: None
+};
+
+// Hook called on each event, stored as a function pointer or 0 if unset.
+static HOOK: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+/// Last events of a directory.
+#[derive(Copy, Clone)]
+pub struct Ring {
+    events: [TraceEvent; RING_EVENTS],
+    // Index of the entry receiving the next event.
+    next: usize,
+    // Number of events recorded, at most RING_EVENTS.
+    len: usize
+}
+
+impl Ring {
+    pub fn new() -> Ring {
+        Ring {
+            events: [NO_EVENT; RING_EVENTS],
+            next: 0,
+            len: 0
+        }
+    }
+
+    /// Record `event`, the oldest event is overwritten once full, then
+    /// pass it to the hook and to the logger.
+    pub fn record(&mut self, event: TraceEvent) {
+        self.events[self.next] = event;
+        self.next = (self.next + 1) % RING_EVENTS;
+        if self.len < RING_EVENTS {
+            self.len += 1;
+        }
+
+        let hook = HOOK.load(Ordering::SeqCst);
+        if hook != 0 {
+            let hook: fn(&TraceEvent) = unsafe { mem::transmute(hook) };
+            hook(&event);
+        }
+        log_event(&event);
+    }
+
+    /// Recorded events, oldest first.
+    pub fn events(&self) -> Vec<TraceEvent> {
+        let start = (self.next + RING_EVENTS - self.len) % RING_EVENTS;
+        (0_usize..self.len).map(|i| {
+            self.events[(start + i) % RING_EVENTS]
+        }).collect()
+    }
+}
+
+
+#[cfg(feature = "malloc_log")]
+fn log_event(event: &TraceEvent) {
+    trace!(target: "tars::malloc", "{}", event);
+}
+
+#[cfg(not(feature = "malloc_log"))]
+fn log_event(_: &TraceEvent) {
+}
+
+/// Set the hook called on each event, or remove it.
+pub fn set_hook(hook: Option<fn(&TraceEvent)>) {
+    HOOK.store(hook.map(|hook| hook as usize).unwrap_or(0),
+               Ordering::SeqCst);
+}
+
+/// Write `events` on the standard error, oldest first.
+pub fn dump(events: &[TraceEvent]) {
+    let stderr = io::stderr();
+    let mut out = stderr.lock();
+    let _ = writeln!(out, "tars malloc: last {} events:", events.len());
+    for event in events.iter() {
+        let _ = writeln!(out, "    {}", event);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use malloc::{Prot, TraceEvent, TraceOp};
+
+    use super::{Ring, RING_EVENTS};
+
+
+    fn event(address: usize) -> TraceEvent {
+        TraceEvent {
+            op: TraceOp::Protect,
+            address: address,
+            size: 64,
+            region: Some(address % 7),
+            prot: Some(Prot::Read)
+        }
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut ring = Ring::new();
+        assert!(ring.events().is_empty());
+
+        for address in 1_usize..4 {
+            ring.record(event(address));
+        }
+        assert_eq!(ring.events(), vec![event(1), event(2), event(3)]);
+
+        // Oldest events are overwritten.
+        for address in 4_usize..RING_EVENTS + 11 {
+            ring.record(event(address));
+        }
+        let events = ring.events();
+        assert_eq!(events.len(), RING_EVENTS);
+        assert_eq!(events[0], event(11));
+        assert_eq!(events[RING_EVENTS - 1], event(RING_EVENTS + 10));
+    }
+}
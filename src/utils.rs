@@ -3,8 +3,6 @@ use libc;
 use std::intrinsics;
 use std::mem;
 
-use rand::{self, Rng, ThreadRng};
-use rand::distributions::range::SampleRange;
 use rand::os::OsRng;
 
 
@@ -66,26 +64,11 @@ pub fn bytes_eq<T>(x: &[T], y: &[T]) -> bool {
 }
 
 
-// Instantiate a PRNG faster than `os_rng()`.
-pub fn rng() -> ThreadRng {
-    rand::thread_rng()
-}
-
 // Instantiate a PRNG based on `urandom`.
 pub fn os_rng() -> OsRng {
     OsRng::new().unwrap()
 }
 
-// Same as `Rng::gen_range` but also tolerates when `low == high`.
-pub fn gen_range<R: Rng, T: PartialOrd + SampleRange>(rng: &mut R,
-                                                      low: T, high: T) -> T {
-    if low == high {
-        return low;
-    }
-
-    rng.gen_range(low, high)
-}
-
 
 #[cfg(test)]
 mod tests {